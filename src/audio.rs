@@ -0,0 +1,264 @@
+// Abstraction over the audio system used for reading and changing the microphone state
+
+#[cfg(test)]
+pub mod mock;
+#[cfg(all(target_os = "linux", feature = "pipewire"))]
+pub mod pipewire;
+#[cfg(target_os = "linux")]
+pub mod pulseaudio;
+#[cfg(windows)]
+pub mod wasapi;
+
+use serde::Serialize;
+
+// An audio capture device as shown in the menu
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AudioDevice {
+    pub id: String,
+    pub name: String,
+}
+
+impl AudioDevice {
+    // Whether the id or the name matches the pattern, ignoring case, where *
+    // matches any run of characters and ? any single one
+    pub fn matches(&self, pattern: &str) -> bool {
+        glob_match(pattern, &self.id) || glob_match(pattern, &self.name)
+    }
+}
+
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last * and the text it was tried against, to come back
+    // to with one more character when the rest does not match
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// An application capturing audio from a device
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CaptureStream {
+    pub application: String,
+    pub process_id: Option<u32>,
+}
+
+// Defaults a capture device can be set as. Windows keeps one for call
+// applications, one for multimedia and one for everything else; other audio
+// systems have a single default for all of them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceRole {
+    Console,
+    Multimedia,
+    Communications,
+}
+
+impl DeviceRole {
+    pub const ALL: [DeviceRole; 3] = [
+        DeviceRole::Console,
+        DeviceRole::Multimedia,
+        DeviceRole::Communications,
+    ];
+}
+
+// Changes reported by an audio backend
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AudioEvent {
+    // Devices were added or removed, or the default device changed
+    Devices,
+    // The mute state or volume of the current device changed, or the mute
+    // state of another device
+    Volume,
+    // Applications started or stopped capturing audio, which not every backend
+    // reports
+    Streams,
+}
+
+// Function called by a backend when a change occurs, possibly from another thread
+pub type AudioEventListener = Box<dyn Fn(AudioEvent) + Send + Sync>;
+
+#[derive(Debug)]
+pub enum Error {
+    #[cfg(windows)]
+    Windows(windows::core::Error),
+    Io(std::io::Error),
+    Backend(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            #[cfg(windows)]
+            Error::Windows(error) => error.fmt(f),
+            Error::Io(error) => error.fmt(f),
+            Error::Backend(message) => message.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(windows)]
+impl From<windows::core::Error> for Error {
+    fn from(error: windows::core::Error) -> Self {
+        Error::Windows(error)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+#[cfg(windows)]
+impl From<Error> for windows::core::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Windows(error) => error,
+            error => {
+                windows::core::Error::new(windows::Win32::Foundation::E_FAIL, error.to_string())
+            }
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Create the backend for the audio system of this platform. On Windows COM must
+// already be initialized on the calling thread.
+pub fn default_backend() -> Result<Box<dyn AudioBackend>> {
+    #[cfg(windows)]
+    let audio_backend = wasapi::WasapiBackend::new()?;
+    // Use PipeWire directly when built with support for it and it is running,
+    // otherwise go through PulseAudio
+    #[cfg(target_os = "linux")]
+    let audio_backend = {
+        #[cfg(feature = "pipewire")]
+        if let Ok(audio_backend) = pipewire::PipeWireBackend::new() {
+            return Ok(Box::new(audio_backend));
+        }
+        pulseaudio::PulseAudioBackend::new()?
+    };
+    Ok(Box::new(audio_backend))
+}
+
+// Operations the indicator needs from the audio system. The "current" device is
+// the one whose state is shown, by default the default communications capture device.
+pub trait AudioBackend {
+    // List the active audio capture devices
+    fn capture_devices(&self) -> Result<Vec<AudioDevice>>;
+
+    // The current device, if there is one
+    fn default_device(&self) -> Result<Option<AudioDevice>>;
+
+    // Make the given device the default capture device for the given roles
+    fn set_default_device(&self, id: &str, roles: &[DeviceRole]) -> Result<()>;
+
+    // Id of the default capture device for a role, which is the current
+    // device for backends without separate defaults per role
+    fn role_default_device(&self, _role: DeviceRole) -> Result<Option<String>> {
+        Ok(self.default_device()?.map(|device| device.id))
+    }
+
+    // Mute state of the current device, or None if it cannot be determined
+    fn muted(&self) -> Result<Option<bool>>;
+
+    fn set_muted(&self, muted: bool) -> Result<()>;
+
+    // Mute state of any capture device by its id, or None if it cannot be
+    // determined
+    fn device_muted(&self, id: &str) -> Result<Option<bool>>;
+
+    fn set_device_muted(&self, id: &str, muted: bool) -> Result<()>;
+
+    // Volume of the current device as a scalar between 0.0 and 1.0, or None if
+    // it cannot be determined
+    fn volume(&self) -> Result<Option<f32>>;
+
+    fn set_volume(&self, volume: f32) -> Result<()>;
+
+    // Start or stop measuring the input level of the current device, which
+    // keeps following the current device while enabled
+    fn set_metering(&mut self, enabled: bool) -> Result<()>;
+
+    // Highest input level of the current device since the last call as a
+    // scalar between 0.0 and 1.0, or None if it is not being measured
+    fn peak(&self) -> Result<Option<f32>>;
+
+    // Applications with an active capture stream on the current device, one
+    // entry per application
+    fn capture_streams(&self) -> Result<Vec<CaptureStream>>;
+
+    // Register the listener to be called whenever a change occurs
+    fn subscribe(&mut self, listener: AudioEventListener) -> Result<()>;
+
+    // Make the current device the default one for the given role from the next
+    // refresh on, for backends with separate defaults per role
+    fn set_followed_role(&mut self, _role: DeviceRole) {}
+
+    // Make the first device matching the pattern the current device from the
+    // next refresh on, whether it is a default one or not. None goes back to
+    // the default device.
+    fn set_pinned_device(&mut self, pattern: Option<String>);
+
+    // Look up the current device again, used after receiving AudioEvent::Devices
+    fn refresh(&mut self) -> Result<()>;
+
+    // Toggle the mute state of the current device
+    fn toggle_mute(&self) -> Result<()> {
+        match self.muted()? {
+            Some(muted) => self.set_muted(!muted),
+            _ => Ok(()),
+        }
+    }
+
+    // Set the volume of the current device to 100% if it is lower
+    fn set_volume_to_max(&self) -> Result<()> {
+        if self.volume().is_ok_and(|v| v.is_some_and(|v| v < 1.0)) {
+            self.set_volume(1.0)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn devices_match_by_id_or_name_pattern() {
+        let device = AudioDevice {
+            id: "alsa_input.usb-Jabra_Link_380".to_owned(),
+            name: "Jabra Link 380 Mono".to_owned(),
+        };
+        assert!(device.matches("jabra link 380 mono"));
+        assert!(device.matches("alsa_input.usb-Jabra_Link_380"));
+        assert!(device.matches("*jabra*"));
+        assert!(device.matches("Jabra Link 3?0*"));
+        assert!(!device.matches("Jabra"));
+        assert!(!device.matches("*webcam*"));
+        assert!(!device.matches(""));
+    }
+}
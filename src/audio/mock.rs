@@ -0,0 +1,295 @@
+// Scriptable in-memory audio backend for tests
+
+use super::{
+    AudioBackend, AudioDevice, AudioEvent, AudioEventListener, CaptureStream, DeviceRole, Result,
+};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MockDevice {
+    pub device: AudioDevice,
+    pub muted: Option<bool>,
+    pub volume: Option<f32>,
+    pub streams: Vec<CaptureStream>,
+}
+
+#[derive(Default)]
+struct State {
+    devices: Vec<MockDevice>,
+    default_device: Option<String>,
+    // Defaults for the other roles, while default_device is the one for the
+    // communications role
+    console_device: Option<String>,
+    multimedia_device: Option<String>,
+    listener: Option<Arc<AudioEventListener>>,
+    volume_changes: usize,
+    metering: bool,
+    peak: f32,
+}
+
+// Backend keeping all state in memory. Clones share the same state, so a test
+// can keep one to script changes while the code under test owns another. Like
+// the real backends, changes made through the AudioBackend trait emit events,
+// while scripted changes only do so when emit is called.
+#[derive(Clone, Default)]
+pub struct MockBackend {
+    state: Arc<Mutex<State>>,
+    current: Option<String>,
+    // Role whose default device is the current one, communications if unset
+    followed_role: Option<DeviceRole>,
+    pinned_device: Option<String>,
+}
+
+impl State {
+    fn role_default(&mut self, role: DeviceRole) -> &mut Option<String> {
+        match role {
+            DeviceRole::Console => &mut self.console_device,
+            DeviceRole::Multimedia => &mut self.multimedia_device,
+            DeviceRole::Communications => &mut self.default_device,
+        }
+    }
+}
+
+impl MockBackend {
+    // Add an unmuted device at 100% volume
+    pub fn add_device(&self, id: &str, name: &str) {
+        self.state.lock().unwrap().devices.push(MockDevice {
+            device: AudioDevice {
+                id: id.to_owned(),
+                name: name.to_owned(),
+            },
+            muted: Some(false),
+            volume: Some(1.0),
+            streams: Vec::new(),
+        });
+    }
+
+    pub fn remove_device(&self, id: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .devices
+            .retain(|device| device.device.id != id);
+    }
+
+    pub fn set_device_state(&self, id: &str, muted: Option<bool>, volume: Option<f32>) {
+        if let Some(device) = self
+            .state
+            .lock()
+            .unwrap()
+            .devices
+            .iter_mut()
+            .find(|device| device.device.id == id)
+        {
+            device.muted = muted;
+            device.volume = volume;
+        }
+    }
+
+    // Set the applications capturing from a device
+    pub fn set_streams(&self, id: &str, streams: &[(&str, u32)]) {
+        if let Some(device) = self
+            .state
+            .lock()
+            .unwrap()
+            .devices
+            .iter_mut()
+            .find(|device| device.device.id == id)
+        {
+            device.streams = streams
+                .iter()
+                .map(|(application, process_id)| CaptureStream {
+                    application: (*application).to_owned(),
+                    process_id: Some(*process_id),
+                })
+                .collect();
+        }
+    }
+
+    // Set the default device for all roles
+    pub fn set_default(&self, id: Option<&str>) {
+        let mut state = self.state.lock().unwrap();
+        state.default_device = id.map(str::to_owned);
+        state.console_device = id.map(str::to_owned);
+        state.multimedia_device = id.map(str::to_owned);
+    }
+
+    pub fn device(&self, id: &str) -> Option<MockDevice> {
+        self.state
+            .lock()
+            .unwrap()
+            .devices
+            .iter()
+            .find(|device| device.device.id == id)
+            .cloned()
+    }
+
+    // Input level reported while metering is enabled
+    pub fn set_peak(&self, peak: f32) {
+        self.state.lock().unwrap().peak = peak;
+    }
+
+    // Number of times the volume was changed through the AudioBackend trait
+    pub fn volume_changes(&self) -> usize {
+        self.state.lock().unwrap().volume_changes
+    }
+
+    // Call the subscribed listener, if any
+    pub fn emit(&self, event: AudioEvent) {
+        let listener = self.state.lock().unwrap().listener.clone();
+        if let Some(listener) = listener {
+            listener(event);
+        }
+    }
+
+    // Change the current device, returning whether it exists
+    fn update_current(&self, update: impl FnOnce(&mut MockDevice)) -> bool {
+        match &self.current {
+            Some(id) => self.update_device(id, update),
+            _ => false,
+        }
+    }
+
+    // Change a device, returning whether it exists
+    fn update_device(&self, id: &str, update: impl FnOnce(&mut MockDevice)) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state
+            .devices
+            .iter_mut()
+            .find(|device| device.device.id == id)
+        {
+            Some(device) => {
+                update(device);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl AudioBackend for MockBackend {
+    fn capture_devices(&self) -> Result<Vec<AudioDevice>> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .devices
+            .iter()
+            .map(|device| device.device.clone())
+            .collect())
+    }
+
+    fn default_device(&self) -> Result<Option<AudioDevice>> {
+        Ok(self
+            .current
+            .as_ref()
+            .and_then(|id| self.device(id))
+            .map(|device| device.device))
+    }
+
+    fn set_default_device(&self, id: &str, roles: &[DeviceRole]) -> Result<()> {
+        {
+            let mut state = self.state.lock().unwrap();
+            for role in roles {
+                *state.role_default(*role) = Some(id.to_owned());
+            }
+        }
+        self.emit(AudioEvent::Devices);
+        Ok(())
+    }
+
+    fn role_default_device(&self, role: DeviceRole) -> Result<Option<String>> {
+        Ok(self.state.lock().unwrap().role_default(role).clone())
+    }
+
+    fn muted(&self) -> Result<Option<bool>> {
+        Ok(self
+            .current
+            .as_ref()
+            .and_then(|id| self.device(id))
+            .and_then(|device| device.muted))
+    }
+
+    fn set_muted(&self, muted: bool) -> Result<()> {
+        if self.update_current(|device| device.muted = Some(muted)) {
+            self.emit(AudioEvent::Volume);
+        }
+        Ok(())
+    }
+
+    fn device_muted(&self, id: &str) -> Result<Option<bool>> {
+        Ok(self.device(id).and_then(|device| device.muted))
+    }
+
+    fn set_device_muted(&self, id: &str, muted: bool) -> Result<()> {
+        if self.update_device(id, |device| device.muted = Some(muted)) {
+            self.emit(AudioEvent::Volume);
+        }
+        Ok(())
+    }
+
+    fn volume(&self) -> Result<Option<f32>> {
+        Ok(self
+            .current
+            .as_ref()
+            .and_then(|id| self.device(id))
+            .and_then(|device| device.volume))
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<()> {
+        if self.update_current(|device| device.volume = Some(volume)) {
+            self.state.lock().unwrap().volume_changes += 1;
+            self.emit(AudioEvent::Volume);
+        }
+        Ok(())
+    }
+
+    fn set_metering(&mut self, enabled: bool) -> Result<()> {
+        self.state.lock().unwrap().metering = enabled;
+        Ok(())
+    }
+
+    fn peak(&self) -> Result<Option<f32>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.metering.then_some(state.peak))
+    }
+
+    fn capture_streams(&self) -> Result<Vec<CaptureStream>> {
+        Ok(self
+            .current
+            .as_ref()
+            .and_then(|id| self.device(id))
+            .map(|device| device.streams)
+            .unwrap_or_default())
+    }
+
+    fn subscribe(&mut self, listener: AudioEventListener) -> Result<()> {
+        self.state.lock().unwrap().listener = Some(Arc::new(listener));
+        Ok(())
+    }
+
+    fn set_followed_role(&mut self, role: DeviceRole) {
+        self.followed_role = Some(role);
+    }
+
+    fn set_pinned_device(&mut self, pattern: Option<String>) {
+        self.pinned_device = pattern;
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        self.current = match &self.pinned_device {
+            Some(pattern) => self
+                .capture_devices()?
+                .into_iter()
+                .find(|device| device.matches(pattern))
+                .map(|device| device.id),
+            _ => self
+                .state
+                .lock()
+                .unwrap()
+                .role_default(self.followed_role.unwrap_or(DeviceRole::Communications))
+                .clone(),
+        };
+        Ok(())
+    }
+}
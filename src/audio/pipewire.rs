@@ -0,0 +1,611 @@
+// Audio backend talking to PipeWire directly
+//
+// PipeWire objects can only be used from the thread running their loop, so the
+// backend runs a loop on its own thread which keeps a copy of the state we need
+// and applies changes sent to it over a channel.
+
+use super::{
+    pulseaudio::PeakMeter, AudioBackend, AudioDevice, AudioEvent, AudioEventListener,
+    CaptureStream, DeviceRole, Error, Result,
+};
+use pipewire::{
+    self as pw,
+    metadata::{Metadata, MetadataListener},
+    node::{Node, NodeListener},
+    spa::{
+        self,
+        param::ParamType,
+        pod::{
+            deserialize::PodDeserializer, serialize::PodSerializer, Object, Pod, Property, Value,
+            ValueArray,
+        },
+        utils::result::AsyncSeq,
+    },
+    types::ObjectType,
+};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashMap},
+    io::Cursor,
+    rc::Rc,
+    sync::{mpsc, Arc, Mutex},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+// Media classes of nodes that are treated as capture devices
+const MEDIA_CLASSES: [&str; 2] = ["Audio/Source", "Audio/Source/Virtual"];
+
+// Media class of nodes of applications capturing audio
+const STREAM_MEDIA_CLASS: &str = "Audio/Stream/Input";
+
+// Metadata object and keys holding the default source
+const METADATA_NAME: &str = "default";
+const DEFAULT_SOURCE_KEY: &str = "default.audio.source";
+const CONFIGURED_DEFAULT_SOURCE_KEY: &str = "default.configured.audio.source";
+
+// Time to wait for the initial state to be received from PipeWire
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct NodeState {
+    name: String,
+    description: String,
+    muted: Option<bool>,
+    channel_volumes: Vec<f32>,
+}
+
+#[derive(Default)]
+struct State {
+    nodes: BTreeMap<u32, NodeState>,
+    default_source: Option<String>,
+    // Capture streams by node id, and links as output and input node ids
+    streams: BTreeMap<u32, CaptureStream>,
+    links: BTreeMap<u32, (u32, u32)>,
+}
+
+impl State {
+    fn node(&self, name: &str) -> Option<(u32, &NodeState)> {
+        self.nodes
+            .iter()
+            .find(|(_, node)| node.name == name)
+            .map(|(id, node)| (*id, node))
+    }
+}
+
+// Changes applied by the loop thread
+enum Command {
+    SetMute(u32, bool),
+    SetChannelVolumes(u32, Vec<f32>),
+    SetDefaultSource(String),
+    Quit,
+}
+
+// Progress of the round trips used to know when the loop thread has caught up
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    Binding,
+    Receiving,
+    Running,
+    Quitting,
+}
+
+type SharedListener = Arc<Mutex<Option<AudioEventListener>>>;
+type Nodes = Rc<RefCell<HashMap<u32, (Node, NodeListener)>>>;
+
+fn notify(listener: &SharedListener, event: AudioEvent) {
+    if let Some(listener) = &*listener.lock().unwrap() {
+        listener(event);
+    }
+}
+
+// Extract the node name from a metadata value such as `{ "name": "alsa_input.usb-..." }`
+fn parse_metadata_name(value: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_str(value).ok()?;
+    Some(value.get("name")?.as_str()?.to_owned())
+}
+
+// Store the mute state and volumes from a Props param of a node
+fn update_node_props(state: &Mutex<State>, id: u32, param: &Pod) {
+    let Ok((_, Value::Object(object))) = PodDeserializer::deserialize_any_from(param.as_bytes())
+    else {
+        return;
+    };
+    let mut state = state.lock().unwrap();
+    let Some(node) = state.nodes.get_mut(&id) else {
+        return;
+    };
+    for property in object.properties {
+        match (property.key, property.value) {
+            (spa::sys::SPA_PROP_mute, Value::Bool(muted)) => node.muted = Some(muted),
+            (spa::sys::SPA_PROP_channelVolumes, Value::ValueArray(ValueArray::Float(volumes))) => {
+                node.channel_volumes = volumes
+            }
+            _ => {}
+        }
+    }
+}
+
+// Change a single property in the Props param of a node
+fn set_node_props(nodes: &Nodes, id: u32, property: Property) {
+    if let Some((node, _)) = nodes.borrow().get(&id) {
+        let Ok((pod, _)) = PodSerializer::serialize(
+            Cursor::new(Vec::new()),
+            &Value::Object(Object {
+                type_: spa::sys::SPA_TYPE_OBJECT_Props,
+                id: spa::sys::SPA_PARAM_Props,
+                properties: vec![property],
+            }),
+        ) else {
+            return;
+        };
+        if let Some(pod) = Pod::from_bytes(&pod.into_inner()) {
+            node.set_param(ParamType::Props, 0, pod);
+        }
+    }
+}
+
+// Run the PipeWire loop until told to quit, sending on ready once the initial state is known
+fn run(
+    state: Arc<Mutex<State>>,
+    listener: SharedListener,
+    commands: pw::channel::Receiver<Command>,
+    ready: mpsc::Sender<std::result::Result<(), String>>,
+) -> std::result::Result<(), pw::Error> {
+    let main_loop = pw::main_loop::MainLoop::new(None)?;
+    let context = pw::context::Context::new(&main_loop)?;
+    let core = context.connect(None)?;
+    let registry = Rc::new(core.get_registry()?);
+
+    // Proxies need to be kept alive to receive their events and change them
+    let nodes: Nodes = Default::default();
+    let metadata: Rc<RefCell<Option<(Metadata, MetadataListener)>>> = Default::default();
+
+    // Every phase ends with a round trip to the server
+    let phase = Rc::new(Cell::new(Phase::Binding));
+    let pending: Rc<Cell<Option<AsyncSeq>>> = Default::default();
+    let _core_listener = core
+        .add_listener_local()
+        .done({
+            let core = core.clone();
+            let main_loop = main_loop.clone();
+            let phase = phase.clone();
+            let pending = pending.clone();
+            move |id, seq| {
+                if id != pw::core::PW_ID_CORE || pending.get() != Some(seq) {
+                    return;
+                }
+                match phase.get() {
+                    // Objects are bound, wait for their params and properties
+                    Phase::Binding => {
+                        phase.set(Phase::Receiving);
+                        pending.set(core.sync(0).ok());
+                    }
+                    Phase::Receiving => {
+                        phase.set(Phase::Running);
+                        ready.send(Ok(())).ok();
+                    }
+                    Phase::Running => {}
+                    Phase::Quitting => main_loop.quit(),
+                }
+            }
+        })
+        .error({
+            let main_loop = main_loop.clone();
+            move |id, _seq, _res, _message| {
+                if id == pw::core::PW_ID_CORE {
+                    main_loop.quit();
+                }
+            }
+        })
+        .register();
+
+    let _registry_listener = registry
+        .add_listener_local()
+        .global({
+            let registry = Rc::downgrade(&registry);
+            let nodes = nodes.clone();
+            let metadata = metadata.clone();
+            let state = state.clone();
+            let listener = listener.clone();
+            move |global| {
+                let (Some(registry), Some(props)) = (registry.upgrade(), global.props) else {
+                    return;
+                };
+                match global.type_ {
+                    ObjectType::Node
+                        if props
+                            .get("media.class")
+                            .is_some_and(|class| MEDIA_CLASSES.contains(&class)) =>
+                    {
+                        let Ok(node) = registry.bind::<Node, _>(global) else {
+                            return;
+                        };
+                        let id = global.id;
+                        let node_listener = node
+                            .add_listener_local()
+                            .param({
+                                let state = state.clone();
+                                let listener = listener.clone();
+                                move |_seq, _id, _index, _next, param| {
+                                    if let Some(param) = param {
+                                        update_node_props(&state, id, param);
+                                        notify(&listener, AudioEvent::Volume);
+                                    }
+                                }
+                            })
+                            .register();
+                        node.subscribe_params(&[ParamType::Props]);
+                        let name = props.get("node.name").unwrap_or_default().to_owned();
+                        let description = props
+                            .get("node.description")
+                            .or_else(|| props.get("node.nick"))
+                            .unwrap_or(&name)
+                            .to_owned();
+                        state.lock().unwrap().nodes.insert(
+                            id,
+                            NodeState {
+                                name,
+                                description,
+                                ..Default::default()
+                            },
+                        );
+                        nodes.borrow_mut().insert(id, (node, node_listener));
+                        notify(&listener, AudioEvent::Devices);
+                    }
+                    ObjectType::Node if props.get("media.class") == Some(STREAM_MEDIA_CLASS) => {
+                        let application = props
+                            .get("application.name")
+                            .or_else(|| props.get("application.process.binary"))
+                            .or_else(|| props.get("node.name"))
+                            .unwrap_or_default()
+                            .to_owned();
+                        let process_id = props
+                            .get("application.process.id")
+                            .and_then(|id| id.parse().ok());
+                        state.lock().unwrap().streams.insert(
+                            global.id,
+                            CaptureStream {
+                                application,
+                                process_id,
+                            },
+                        );
+                        notify(&listener, AudioEvent::Streams);
+                    }
+                    ObjectType::Link => {
+                        let node = |key| props.get(key).and_then(|id| id.parse::<u32>().ok());
+                        let (Some(output), Some(input)) =
+                            (node("link.output.node"), node("link.input.node"))
+                        else {
+                            return;
+                        };
+                        state
+                            .lock()
+                            .unwrap()
+                            .links
+                            .insert(global.id, (output, input));
+                        notify(&listener, AudioEvent::Streams);
+                    }
+                    ObjectType::Metadata if props.get("metadata.name") == Some(METADATA_NAME) => {
+                        let Ok(default_metadata) = registry.bind::<Metadata, _>(global) else {
+                            return;
+                        };
+                        let metadata_listener = default_metadata
+                            .add_listener_local()
+                            .property({
+                                let state = state.clone();
+                                let listener = listener.clone();
+                                move |_subject, key, _type, value| {
+                                    // A key of None means all properties were removed
+                                    if key.is_none_or(|key| key == DEFAULT_SOURCE_KEY) {
+                                        state.lock().unwrap().default_source =
+                                            value.and_then(parse_metadata_name);
+                                        notify(&listener, AudioEvent::Devices);
+                                    }
+                                    0
+                                }
+                            })
+                            .register();
+                        metadata.replace(Some((default_metadata, metadata_listener)));
+                    }
+                    _ => {}
+                }
+            }
+        })
+        .global_remove({
+            let nodes = nodes.clone();
+            let state = state.clone();
+            let listener = listener.clone();
+            move |id| {
+                if nodes.borrow_mut().remove(&id).is_some() {
+                    state.lock().unwrap().nodes.remove(&id);
+                    notify(&listener, AudioEvent::Devices);
+                } else if {
+                    let mut state = state.lock().unwrap();
+                    state.streams.remove(&id).is_some() | state.links.remove(&id).is_some()
+                } {
+                    notify(&listener, AudioEvent::Streams);
+                }
+            }
+        })
+        .register();
+
+    let _commands = commands.attach(main_loop.loop_(), {
+        let core = core.clone();
+        let phase = phase.clone();
+        let pending = pending.clone();
+        move |command| match command {
+            Command::SetMute(id, muted) => set_node_props(
+                &nodes,
+                id,
+                Property::new(spa::sys::SPA_PROP_mute, Value::Bool(muted)),
+            ),
+            Command::SetChannelVolumes(id, volumes) => set_node_props(
+                &nodes,
+                id,
+                Property::new(
+                    spa::sys::SPA_PROP_channelVolumes,
+                    Value::ValueArray(ValueArray::Float(volumes)),
+                ),
+            ),
+            Command::SetDefaultSource(name) => {
+                if let Some((metadata, _)) = &*metadata.borrow() {
+                    metadata.set_property(
+                        0,
+                        CONFIGURED_DEFAULT_SOURCE_KEY,
+                        Some("Spa:String:JSON"),
+                        Some(&serde_json::json!({ "name": name }).to_string()),
+                    );
+                }
+            }
+            // Quit after a round trip so earlier changes reach the server
+            Command::Quit => {
+                phase.set(Phase::Quitting);
+                pending.set(core.sync(0).ok());
+            }
+        }
+    });
+
+    pending.set(Some(core.sync(0)?));
+    main_loop.run();
+    Ok(())
+}
+
+// Backend using the default source. Volumes are converted between the cubic
+// channel volumes PipeWire uses and the scalar shown by other tools. The input
+// level is measured through PipeWire's PulseAudio server, which names sources
+// after their nodes.
+pub struct PipeWireBackend {
+    state: Arc<Mutex<State>>,
+    listener: SharedListener,
+    commands: pw::channel::Sender<Command>,
+    thread: Option<JoinHandle<()>>,
+    source: Option<String>,
+    pinned_source: Option<String>,
+    metering: bool,
+    meter: Option<PeakMeter>,
+}
+
+impl PipeWireBackend {
+    pub fn new() -> Result<Self> {
+        pw::init();
+        let state: Arc<Mutex<State>> = Default::default();
+        let listener: SharedListener = Default::default();
+        let (commands, commands_receiver) = pw::channel::channel();
+        let (ready, ready_receiver) = mpsc::channel();
+        let thread = std::thread::spawn({
+            let state = state.clone();
+            let listener = listener.clone();
+            move || {
+                if let Err(error) = run(state, listener, commands_receiver, ready.clone()) {
+                    ready.send(Err(error.to_string())).ok();
+                }
+            }
+        });
+        let mut backend = Self {
+            state,
+            listener,
+            commands,
+            thread: Some(thread),
+            source: None,
+            pinned_source: None,
+            metering: false,
+            meter: None,
+        };
+        match ready_receiver.recv_timeout(CONNECT_TIMEOUT) {
+            Ok(Ok(())) => {}
+            Ok(Err(message)) => return Err(Error::Backend(message)),
+            Err(_) => return Err(Error::Backend("PipeWire did not respond".to_owned())),
+        }
+        backend.refresh()?;
+        Ok(backend)
+    }
+
+    // Measure the current source while metering is enabled
+    fn update_meter(&mut self) -> Result<()> {
+        let source = self.source.as_deref().filter(|_| self.metering);
+        if self.meter.as_ref().map(PeakMeter::source) != source {
+            self.meter = None;
+            if let Some(source) = source {
+                self.meter = Some(PeakMeter::new(source)?);
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&self, command: Command) -> Result<()> {
+        self.commands
+            .send(command)
+            .map_err(|_| Error::Backend("PipeWire connection was closed".to_owned()))
+    }
+}
+
+impl Drop for PipeWireBackend {
+    fn drop(&mut self) {
+        if self.send(Command::Quit).is_ok() {
+            if let Some(thread) = self.thread.take() {
+                thread.join().ok();
+            }
+        }
+    }
+}
+
+impl AudioBackend for PipeWireBackend {
+    fn capture_devices(&self) -> Result<Vec<AudioDevice>> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .nodes
+            .values()
+            .map(|node| AudioDevice {
+                id: node.name.clone(),
+                name: node.description.clone(),
+            })
+            .collect())
+    }
+
+    fn default_device(&self) -> Result<Option<AudioDevice>> {
+        let state = self.state.lock().unwrap();
+        Ok(self
+            .source
+            .as_ref()
+            .and_then(|source| state.node(source))
+            .map(|(_, node)| AudioDevice {
+                id: node.name.clone(),
+                name: node.description.clone(),
+            }))
+    }
+
+    // PipeWire has a single default source for all roles
+    fn set_default_device(&self, id: &str, _roles: &[DeviceRole]) -> Result<()> {
+        self.send(Command::SetDefaultSource(id.to_owned()))
+    }
+
+    fn muted(&self) -> Result<Option<bool>> {
+        let state = self.state.lock().unwrap();
+        Ok(self
+            .source
+            .as_ref()
+            .and_then(|source| state.node(source))
+            .and_then(|(_, node)| node.muted))
+    }
+
+    fn set_muted(&self, muted: bool) -> Result<()> {
+        let id = {
+            let state = self.state.lock().unwrap();
+            self.source
+                .as_ref()
+                .and_then(|source| state.node(source))
+                .map(|(id, _)| id)
+        };
+        match id {
+            Some(id) => self.send(Command::SetMute(id, muted)),
+            _ => Ok(()),
+        }
+    }
+
+    fn device_muted(&self, id: &str) -> Result<Option<bool>> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .node(id)
+            .and_then(|(_, node)| node.muted))
+    }
+
+    fn set_device_muted(&self, id: &str, muted: bool) -> Result<()> {
+        let node = self.state.lock().unwrap().node(id).map(|(node, _)| node);
+        match node {
+            Some(node) => self.send(Command::SetMute(node, muted)),
+            _ => Ok(()),
+        }
+    }
+
+    fn volume(&self) -> Result<Option<f32>> {
+        let state = self.state.lock().unwrap();
+        Ok(self
+            .source
+            .as_ref()
+            .and_then(|source| state.node(source))
+            .filter(|(_, node)| !node.channel_volumes.is_empty())
+            .map(|(_, node)| {
+                (node.channel_volumes.iter().sum::<f32>() / node.channel_volumes.len() as f32)
+                    .cbrt()
+            }))
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<()> {
+        let command = {
+            let state = self.state.lock().unwrap();
+            self.source
+                .as_ref()
+                .and_then(|source| state.node(source))
+                .filter(|(_, node)| !node.channel_volumes.is_empty())
+                .map(|(id, node)| {
+                    Command::SetChannelVolumes(
+                        id,
+                        vec![volume.max(0.0).powi(3); node.channel_volumes.len()],
+                    )
+                })
+        };
+        match command {
+            Some(command) => self.send(command),
+            _ => Ok(()),
+        }
+    }
+
+    // Metering stays off when parec cannot be started
+    fn set_metering(&mut self, enabled: bool) -> Result<()> {
+        self.metering = enabled;
+        let result = self.update_meter();
+        if result.is_err() {
+            self.metering = false;
+        }
+        result
+    }
+
+    fn peak(&self) -> Result<Option<f32>> {
+        Ok(self.meter.as_ref().map(PeakMeter::take_peak))
+    }
+
+    // Streams count as capturing while they are linked to the source
+    fn capture_streams(&self) -> Result<Vec<CaptureStream>> {
+        let state = self.state.lock().unwrap();
+        let Some((source_id, _)) = self.source.as_ref().and_then(|source| state.node(source))
+        else {
+            return Ok(Vec::new());
+        };
+        let meter = self.meter.as_ref().map(PeakMeter::process_id);
+        let mut streams = Vec::new();
+        for (output, input) in state.links.values() {
+            if let Some(stream) = state.streams.get(input).filter(|_| *output == source_id) {
+                if (meter.is_none() || stream.process_id != meter) && !streams.contains(stream) {
+                    streams.push(stream.clone());
+                }
+            }
+        }
+        Ok(streams)
+    }
+
+    fn subscribe(&mut self, listener: AudioEventListener) -> Result<()> {
+        self.listener.lock().unwrap().replace(listener);
+        Ok(())
+    }
+
+    fn set_pinned_device(&mut self, pattern: Option<String>) {
+        self.pinned_source = pattern;
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        self.source = match &self.pinned_source {
+            Some(pattern) => self
+                .capture_devices()?
+                .into_iter()
+                .find(|device| device.matches(pattern))
+                .map(|device| device.id),
+            _ => self.state.lock().unwrap().default_source.clone(),
+        };
+        self.update_meter()
+    }
+}
@@ -0,0 +1,677 @@
+// Audio backend using PulseAudio (or PipeWire's PulseAudio server) through pactl
+
+use super::{
+    AudioBackend, AudioDevice, AudioEvent, AudioEventListener, CaptureStream, DeviceRole, Error,
+    Result,
+};
+use std::{
+    io::{BufRead, BufReader, Read},
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+// Volume value PulseAudio uses for 100%
+const PA_VOLUME_NORM: f32 = 65536.0;
+
+// Format the input level is measured in, a low rate is enough to catch the
+// peaks of speech. Samples are read in blocks of 20 ms.
+const METER_RATE: u32 = 8000;
+const METER_BLOCK_BYTES: usize = 2 * METER_RATE as usize / 50;
+
+// Source as described by `pactl list sources`
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Source {
+    index: u32,
+    name: String,
+    description: String,
+    monitor: bool,
+    muted: bool,
+    volume: f32,
+}
+
+// Source output as described by `pactl list source-outputs`
+#[derive(Clone, Debug, Default, PartialEq)]
+struct SourceOutput {
+    source: u32,
+    corked: bool,
+    application: String,
+    process_id: Option<u32>,
+}
+
+// Run pactl with the given arguments and return its output
+fn pactl(args: &[&str]) -> Result<String> {
+    let output = Command::new("pactl")
+        .env("LC_ALL", "C")
+        .args(args)
+        .stdin(Stdio::null())
+        .output()?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(Error::Backend(format!(
+            "pactl {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+// Parse the output of `pactl info` for the name of the default source
+fn parse_default_source(info: &str) -> Option<String> {
+    info.lines()
+        .find_map(|line| line.strip_prefix("Default Source: "))
+        .map(|name| name.trim().to_owned())
+        .filter(|name| !name.is_empty() && name != "@DEFAULT_SOURCE@")
+}
+
+// Parse the average channel volume from a `Volume:` line such as
+// `front-left: 65536 / 100% / 0.00 dB,   front-right: 65536 / 100% / 0.00 dB`
+fn parse_volume(volume: &str) -> f32 {
+    let channels = volume
+        .split(',')
+        .filter_map(|channel| {
+            channel
+                .split_once(':')
+                .and_then(|(_, value)| value.split('/').next())
+                .and_then(|value| value.trim().parse::<u32>().ok())
+        })
+        .collect::<Vec<_>>();
+    if channels.is_empty() {
+        0.0
+    } else {
+        channels.iter().map(|v| *v as f32).sum::<f32>() / channels.len() as f32 / PA_VOLUME_NORM
+    }
+}
+
+// Parse the output of `pactl list sources`
+fn parse_sources(list: &str) -> Vec<Source> {
+    let mut sources = Vec::new();
+    let mut current: Option<Source> = None;
+    for line in list.lines() {
+        if let Some(index) = line.strip_prefix("Source #") {
+            sources.extend(current.replace(Source {
+                index: index.trim().parse().unwrap_or_default(),
+                ..Default::default()
+            }));
+            continue;
+        }
+        let Some(source) = current.as_mut() else {
+            continue;
+        };
+        // Properties are indented with a single tab, deeper levels are ignored
+        let Some(line) = line.strip_prefix('\t') else {
+            continue;
+        };
+        if line.starts_with('\t') {
+            continue;
+        }
+        match line.split_once(':') {
+            Some(("Name", value)) => source.name = value.trim().to_owned(),
+            Some(("Description", value)) => source.description = value.trim().to_owned(),
+            Some(("Monitor of Sink", value)) => source.monitor = value.trim() != "n/a",
+            Some(("Mute", value)) => source.muted = value.trim() == "yes",
+            Some(("Volume", value)) => source.volume = parse_volume(value),
+            _ => {}
+        }
+    }
+    sources.extend(current);
+    sources
+}
+
+// Parse the output of `pactl list source-outputs`
+fn parse_source_outputs(list: &str) -> Vec<SourceOutput> {
+    let mut outputs = Vec::new();
+    let mut current: Option<SourceOutput> = None;
+    for line in list.lines() {
+        if line.starts_with("Source Output #") {
+            outputs.extend(current.replace(SourceOutput::default()));
+            continue;
+        }
+        let Some(output) = current.as_mut() else {
+            continue;
+        };
+        // Only the application properties are needed from the property list,
+        // which is indented with two tabs and has quoted values
+        if let Some((key, value)) = line
+            .strip_prefix("\t\t")
+            .and_then(|line| line.split_once(" = "))
+        {
+            let value = value.trim().trim_matches('"');
+            match key {
+                "application.name" => output.application = value.to_owned(),
+                "application.process.id" => output.process_id = value.parse().ok(),
+                "application.process.binary" if output.application.is_empty() => {
+                    output.application = value.to_owned()
+                }
+                _ => {}
+            }
+            continue;
+        }
+        match line
+            .strip_prefix('\t')
+            .and_then(|line| line.split_once(':'))
+        {
+            Some(("Source", value)) => output.source = value.trim().parse().unwrap_or_default(),
+            Some(("Corked", value)) => output.corked = value.trim() == "yes",
+            _ => {}
+        }
+    }
+    outputs.extend(current);
+    outputs
+}
+
+// Map a line from `pactl subscribe` to the event it represents
+fn parse_event(line: &str) -> Option<AudioEvent> {
+    let (kind, facility) = line
+        .strip_prefix("Event '")?
+        .split_once("' on ")
+        .map(|(kind, rest)| (kind, rest.split(' ').next().unwrap_or(rest)))?;
+    match (kind, facility) {
+        ("new" | "remove", "source") => Some(AudioEvent::Devices),
+        ("change", "source") => Some(AudioEvent::Volume),
+        ("change", "server") => Some(AudioEvent::Devices),
+        ("new" | "remove" | "change", "source-output") => Some(AudioEvent::Streams),
+        _ => None,
+    }
+}
+
+// Measures the input level of a source by recording from it with parec
+pub(super) struct PeakMeter {
+    source: String,
+    child: Child,
+    // Highest peak since it was last taken. Stored as the bits of the f32,
+    // which compare the same as the values themselves for positive numbers.
+    peak: Arc<AtomicU32>,
+}
+
+impl PeakMeter {
+    pub(super) fn new(source: &str) -> Result<Self> {
+        let mut child = Command::new("parec")
+            .env("LC_ALL", "C")
+            .arg(format!("--device={}", source))
+            .arg(format!("--rate={}", METER_RATE))
+            .args([
+                "--raw",
+                "--format=s16le",
+                "--channels=1",
+                "--latency-msec=20",
+                "--client-name=Microphone Mute Indicator",
+                "--stream-name=Level meter",
+            ])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let mut stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| Error::Backend("parec has no output".to_owned()))?;
+        let peak = Arc::new(AtomicU32::new(0));
+        std::thread::spawn({
+            let peak = peak.clone();
+            move || {
+                let mut block = [0u8; METER_BLOCK_BYTES];
+                while stdout.read_exact(&mut block).is_ok() {
+                    let block_peak = block
+                        .chunks_exact(2)
+                        .map(|sample| i16::from_le_bytes([sample[0], sample[1]]).unsigned_abs())
+                        .max()
+                        .unwrap_or(0);
+                    peak.fetch_max((block_peak as f32 / 32768.0).to_bits(), Ordering::Relaxed);
+                }
+            }
+        });
+        Ok(Self {
+            source: source.to_owned(),
+            child,
+            peak,
+        })
+    }
+
+    pub(super) fn source(&self) -> &str {
+        &self.source
+    }
+
+    // Process of the recording stream, which is not one of the applications
+    // using the microphone
+    pub(super) fn process_id(&self) -> u32 {
+        self.child.id()
+    }
+
+    pub(super) fn take_peak(&self) -> f32 {
+        f32::from_bits(self.peak.swap(0, Ordering::Relaxed))
+    }
+}
+
+impl Drop for PeakMeter {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+}
+
+// Backend using the default source, or the pinned one. Monitor sources of sinks
+// are not treated as capture devices.
+pub struct PulseAudioBackend {
+    source: Option<String>,
+    pinned_source: Option<String>,
+    subscription: Option<Child>,
+    metering: bool,
+    meter: Option<PeakMeter>,
+}
+
+impl PulseAudioBackend {
+    pub fn new() -> Result<Self> {
+        let mut backend = Self {
+            source: None,
+            pinned_source: None,
+            subscription: None,
+            metering: false,
+            meter: None,
+        };
+        backend.refresh()?;
+        Ok(backend)
+    }
+
+    // Look up the current source in the list of all sources
+    fn source(&self) -> Result<Option<Source>> {
+        match &self.source {
+            Some(name) => Ok(parse_sources(&pactl(&["list", "sources"])?)
+                .into_iter()
+                .find(|source| &source.name == name)),
+            _ => Ok(None),
+        }
+    }
+
+    // Measure the current source while metering is enabled
+    fn update_meter(&mut self) -> Result<()> {
+        let source = self.source.as_deref().filter(|_| self.metering);
+        if self.meter.as_ref().map(PeakMeter::source) != source {
+            self.meter = None;
+            if let Some(source) = source {
+                self.meter = Some(PeakMeter::new(source)?);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PulseAudioBackend {
+    fn drop(&mut self) {
+        if let Some(mut subscription) = self.subscription.take() {
+            subscription.kill().ok();
+            subscription.wait().ok();
+        }
+    }
+}
+
+impl AudioBackend for PulseAudioBackend {
+    fn capture_devices(&self) -> Result<Vec<AudioDevice>> {
+        Ok(parse_sources(&pactl(&["list", "sources"])?)
+            .into_iter()
+            .filter(|source| !source.monitor)
+            .map(|source| AudioDevice {
+                id: source.name,
+                name: source.description,
+            })
+            .collect())
+    }
+
+    fn default_device(&self) -> Result<Option<AudioDevice>> {
+        Ok(self.source()?.map(|source| AudioDevice {
+            id: source.name,
+            name: source.description,
+        }))
+    }
+
+    // PulseAudio has a single default source for all roles
+    fn set_default_device(&self, id: &str, _roles: &[DeviceRole]) -> Result<()> {
+        pactl(&["set-default-source", id]).map(|_| ())
+    }
+
+    fn muted(&self) -> Result<Option<bool>> {
+        Ok(self.source()?.map(|source| source.muted))
+    }
+
+    fn set_muted(&self, muted: bool) -> Result<()> {
+        match &self.source {
+            Some(name) => {
+                pactl(&["set-source-mute", name, if muted { "1" } else { "0" }]).map(|_| ())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn device_muted(&self, id: &str) -> Result<Option<bool>> {
+        Ok(parse_sources(&pactl(&["list", "sources"])?)
+            .into_iter()
+            .find(|source| source.name == id)
+            .map(|source| source.muted))
+    }
+
+    fn set_device_muted(&self, id: &str, muted: bool) -> Result<()> {
+        pactl(&["set-source-mute", id, if muted { "1" } else { "0" }]).map(|_| ())
+    }
+
+    fn volume(&self) -> Result<Option<f32>> {
+        Ok(self.source()?.map(|source| source.volume))
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<()> {
+        match &self.source {
+            Some(name) => {
+                let volume = ((volume.max(0.0) * PA_VOLUME_NORM).round() as u32).to_string();
+                pactl(&["set-source-volume", name, &volume]).map(|_| ())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Metering stays off when parec cannot be started
+    fn set_metering(&mut self, enabled: bool) -> Result<()> {
+        self.metering = enabled;
+        let result = self.update_meter();
+        if result.is_err() {
+            self.metering = false;
+        }
+        result
+    }
+
+    fn peak(&self) -> Result<Option<f32>> {
+        Ok(self.meter.as_ref().map(PeakMeter::take_peak))
+    }
+
+    // Streams which are corked (paused) are not actually capturing anything
+    fn capture_streams(&self) -> Result<Vec<CaptureStream>> {
+        let Some(source) = self.source()? else {
+            return Ok(Vec::new());
+        };
+        let meter = self.meter.as_ref().map(PeakMeter::process_id);
+        let mut streams = Vec::new();
+        for output in parse_source_outputs(&pactl(&["list", "source-outputs"])?) {
+            let stream = CaptureStream {
+                application: output.application,
+                process_id: output.process_id,
+            };
+            if output.source == source.index
+                && !output.corked
+                && (meter.is_none() || output.process_id != meter)
+                && !streams.contains(&stream)
+            {
+                streams.push(stream);
+            }
+        }
+        Ok(streams)
+    }
+
+    fn subscribe(&mut self, listener: AudioEventListener) -> Result<()> {
+        if let Some(mut subscription) = self.subscription.take() {
+            subscription.kill().ok();
+            subscription.wait().ok();
+        }
+        let mut subscription = Command::new("pactl")
+            .env("LC_ALL", "C")
+            .arg("subscribe")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let stdout = subscription
+            .stdout
+            .take()
+            .ok_or_else(|| Error::Backend("pactl subscribe has no output".to_owned()))?;
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                match line {
+                    Ok(line) => {
+                        if let Some(event) = parse_event(&line) {
+                            listener(event);
+                        }
+                    }
+                    _ => break,
+                }
+            }
+        });
+        self.subscription = Some(subscription);
+        Ok(())
+    }
+
+    fn set_pinned_device(&mut self, pattern: Option<String>) {
+        self.pinned_source = pattern;
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        self.source = match &self.pinned_source {
+            Some(pattern) => self
+                .capture_devices()?
+                .into_iter()
+                .find(|device| device.matches(pattern))
+                .map(|device| device.id),
+            _ => parse_default_source(&pactl(&["info"])?),
+        };
+        self.update_meter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Output of `pactl list sources` from PipeWire's PulseAudio server, with a
+    // monitor source, a muted stereo microphone and a microphone array
+    const SOURCES: &str = "Source #55
+\tState: SUSPENDED
+\tName: alsa_output.pci-0000_00_1f.3.analog-stereo.monitor
+\tDescription: Monitor of Built-in Audio Analog Stereo
+\tDriver: PipeWire
+\tSample Specification: s32le 2ch 48000Hz
+\tChannel Map: front-left,front-right
+\tOwner Module: 4294967295
+\tMute: no
+\tVolume: front-left: 65536 / 100% / 0.00 dB,   front-right: 65536 / 100% / 0.00 dB
+\t        balance 0.00
+\tBase Volume: 65536 / 100% / 0.00 dB
+\tMonitor of Sink: alsa_output.pci-0000_00_1f.3.analog-stereo
+\tLatency: 0 usec, configured 0 usec
+\tFlags: HARDWARE DECIBEL_VOLUME LATENCY 
+\tProperties:
+\t\tdevice.description = \"Built-in Audio Analog Stereo\"
+\t\tnode.name = \"alsa_output.pci-0000_00_1f.3.analog-stereo\"
+\tFormats:
+\t\tpcm
+Source #56
+\tState: RUNNING
+\tName: alsa_input.pci-0000_00_1f.3.analog-stereo
+\tDescription: Built-in Audio Analog Stereo
+\tDriver: PipeWire
+\tSample Specification: s32le 2ch 48000Hz
+\tChannel Map: front-left,front-right
+\tOwner Module: 4294967295
+\tMute: yes
+\tVolume: front-left: 26214 /  40% / -23.88 dB,   front-right: 39322 /  60% / -13.31 dB
+\t        balance 0.20
+\tBase Volume: 65536 / 100% / 0.00 dB
+\tMonitor of Sink: n/a
+\tLatency: 0 usec, configured 0 usec
+\tFlags: HARDWARE HW_MUTE_CTRL HW_VOLUME_CTRL DECIBEL_VOLUME LATENCY 
+\tProperties:
+\t\talsa.resolution_bits = \"24\"
+\t\tdevice.description = \"Something else\"
+\tPorts:
+\t\tanalog-input-mic: Microphone (type: Mic, priority: 8700, availability unknown)
+\tActive Port: analog-input-mic
+\tFormats:
+\t\tpcm
+Source #57
+\tState: IDLE
+\tName: alsa_input.usb-Mikrofon_Array-00.multichannel-input
+\tDescription: Mikrofon-Array – Eingebaut
+\tMute: no
+\tVolume: front-left: 65536 / 100% / 0,00 dB,   front-right: 65536 / 100% / 0,00 dB,   rear-left: 32768 /  50% / -18,06 dB,   rear-right: 32768 /  50% / -18,06 dB
+\t        balance 0,00
+\tMonitor of Sink: n/a
+";
+
+    // Output of `pactl list source-outputs` with a recording application and
+    // a corked stream which only names its binary
+    const SOURCE_OUTPUTS: &str = "Source Output #71
+\tDriver: PipeWire
+\tOwner Module: n/a
+\tClient: 70
+\tSource: 56
+\tSample Specification: float32le 1ch 48000Hz
+\tChannel Map: mono
+\tFormat: pcm, format.sample_format = \"\\\"float32le\\\"\"  format.rate = \"48000\"  format.channels = \"1\"
+\tCorked: no
+\tMute: no
+\tVolume: mono: 65536 / 100% / 0.00 dB
+\t        balance 0.00
+\tBuffer Latency: 0 usec
+\tSource Latency: 0 usec
+\tResample method: PipeWire
+\tProperties:
+\t\tmedia.name = \"AudioStream\"
+\t\tapplication.name = \"Firefox\"
+\t\tapplication.process.id = \"4242\"
+\t\tapplication.process.binary = \"firefox\"
+Source Output #72
+\tDriver: PipeWire
+\tSource: 57
+\tCorked: yes
+\tProperties:
+\t\tapplication.process.binary = \"arecord\"
+";
+
+    #[test]
+    fn default_source_is_read_from_info() {
+        let info = "Server String: /run/user/1000/pulse/native\n\
+                    Default Sink: alsa_output.pci-0000_00_1f.3.analog-stereo\n\
+                    Default Source: alsa_input.pci-0000_00_1f.3.analog-stereo\n";
+        assert_eq!(
+            parse_default_source(info).as_deref(),
+            Some("alsa_input.pci-0000_00_1f.3.analog-stereo")
+        );
+        assert_eq!(
+            parse_default_source("Default Source: @DEFAULT_SOURCE@\n"),
+            None
+        );
+        assert_eq!(parse_default_source("Default Source: \n"), None);
+        // Localized output, which we avoid with LC_ALL=C, has no default
+        assert_eq!(
+            parse_default_source("Standard-Quelle: alsa_input.usb\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn volume_is_averaged_over_the_channels() {
+        // Output of `pactl get-source-volume`, which starts with the same line
+        let output = "Volume: front-left: 32768 /  50% / -18.06 dB,   \
+                      front-right: 32768 /  50% / -18.06 dB\n        balance 0.00\n";
+        let line = output.lines().next().unwrap();
+        assert_eq!(parse_volume(line.strip_prefix("Volume:").unwrap()), 0.5);
+        assert_eq!(parse_volume(" mono: 65536 / 100% / 0.00 dB"), 1.0);
+        assert_eq!(
+            parse_volume(
+                " front-left: 65536 / 100% / 0,00 dB,   front-right: 0 /   0% / -inf dB,   \
+                 rear-left: 65536 / 100% / 0,00 dB,   rear-right: 0 /   0% / -inf dB"
+            ),
+            0.5
+        );
+        assert_eq!(parse_volume(" 150%"), 0.0);
+        assert_eq!(parse_volume(""), 0.0);
+    }
+
+    #[test]
+    fn sources_are_parsed() {
+        let sources = parse_sources(SOURCES);
+        assert_eq!(sources.len(), 3);
+        assert!(sources[0].monitor);
+        assert_eq!(
+            sources[1],
+            Source {
+                index: 56,
+                name: "alsa_input.pci-0000_00_1f.3.analog-stereo".to_owned(),
+                description: "Built-in Audio Analog Stereo".to_owned(),
+                monitor: false,
+                muted: true,
+                volume: 0.5,
+            }
+        );
+        assert_eq!(sources[2].description, "Mikrofon-Array – Eingebaut");
+        assert!(!sources[2].muted);
+        assert_eq!(sources[2].volume, 0.75);
+    }
+
+    #[test]
+    fn missing_fields_of_sources_are_left_empty() {
+        let sources = parse_sources("Source #3\n\tName: null-source\nSource #x\n");
+        assert_eq!(
+            sources,
+            [
+                Source {
+                    index: 3,
+                    name: "null-source".to_owned(),
+                    ..Default::default()
+                },
+                Source::default(),
+            ]
+        );
+        // Localized output, which we avoid with LC_ALL=C, has no sources
+        assert_eq!(parse_sources("Quelle #56\n\tName: mic\n"), []);
+        assert_eq!(parse_sources(""), []);
+    }
+
+    #[test]
+    fn source_outputs_are_parsed() {
+        assert_eq!(
+            parse_source_outputs(SOURCE_OUTPUTS),
+            [
+                SourceOutput {
+                    source: 56,
+                    corked: false,
+                    application: "Firefox".to_owned(),
+                    process_id: Some(4242),
+                },
+                SourceOutput {
+                    source: 57,
+                    corked: true,
+                    application: "arecord".to_owned(),
+                    process_id: None,
+                },
+            ]
+        );
+        assert_eq!(parse_source_outputs(""), []);
+    }
+
+    #[test]
+    fn subscribe_events_are_mapped() {
+        let events = [
+            ("Event 'new' on source #58", Some(AudioEvent::Devices)),
+            ("Event 'remove' on source #58", Some(AudioEvent::Devices)),
+            ("Event 'change' on source #56", Some(AudioEvent::Volume)),
+            (
+                "Event 'change' on server #4294967295",
+                Some(AudioEvent::Devices),
+            ),
+            (
+                "Event 'new' on source-output #72",
+                Some(AudioEvent::Streams),
+            ),
+            (
+                "Event 'remove' on source-output #72",
+                Some(AudioEvent::Streams),
+            ),
+            ("Event 'change' on sink #48", None),
+            ("Event 'new' on client #80", None),
+            ("Ereignis »new« auf source #58", None),
+            ("", None),
+        ];
+        for (line, event) in events {
+            assert_eq!(parse_event(line), event, "{}", line);
+        }
+    }
+}
@@ -0,0 +1,664 @@
+// Audio backend using the Windows Audio Session API
+
+use super::{
+    AudioBackend, AudioDevice, AudioEvent, AudioEventListener, CaptureStream, DeviceRole, Result,
+};
+use std::{ffi::c_void, path::Path, sync::Arc};
+use windows::{
+    core::*,
+    Win32::{
+        Devices::FunctionDiscovery::PKEY_Device_FriendlyName,
+        Foundation::*,
+        Media::Audio::{Endpoints::*, *},
+        System::{Com::*, Threading::*},
+        UI::Shell::PropertiesSystem::PROPERTYKEY,
+    },
+};
+
+#[implement(IMMNotificationClient)]
+struct AudioEndpointCallback {
+    listener: Arc<AudioEventListener>,
+}
+
+#[allow(non_snake_case)]
+impl IMMNotificationClient_Impl for AudioEndpointCallback_Impl {
+    fn OnDeviceStateChanged(
+        &self,
+        _pwstrdeviceid: &PCWSTR,
+        _dwnewstate: DEVICE_STATE,
+    ) -> windows::core::Result<()> {
+        (self.listener)(AudioEvent::Devices);
+        Ok(())
+    }
+    fn OnDeviceAdded(&self, _pwstrdeviceid: &PCWSTR) -> windows::core::Result<()> {
+        (self.listener)(AudioEvent::Devices);
+        Ok(())
+    }
+    fn OnDeviceRemoved(&self, _pwstrdeviceid: &PCWSTR) -> windows::core::Result<()> {
+        (self.listener)(AudioEvent::Devices);
+        Ok(())
+    }
+    fn OnDefaultDeviceChanged(
+        &self,
+        _flow: EDataFlow,
+        _role: ERole,
+        _pwstrdefaultdeviceid: &PCWSTR,
+    ) -> windows::core::Result<()> {
+        (self.listener)(AudioEvent::Devices);
+        Ok(())
+    }
+    fn OnPropertyValueChanged(
+        &self,
+        _pwstrdeviceid: &PCWSTR,
+        _key: &PROPERTYKEY,
+    ) -> windows::core::Result<()> {
+        (self.listener)(AudioEvent::Devices);
+        Ok(())
+    }
+}
+
+#[implement(IAudioEndpointVolumeCallback)]
+struct AudioEndpointVolumeCallback {
+    listener: Arc<AudioEventListener>,
+}
+
+#[allow(non_snake_case)]
+impl IAudioEndpointVolumeCallback_Impl for AudioEndpointVolumeCallback_Impl {
+    fn OnNotify(&self, _pnotify: *mut AUDIO_VOLUME_NOTIFICATION_DATA) -> windows::core::Result<()> {
+        (self.listener)(AudioEvent::Volume);
+        Ok(())
+    }
+}
+
+// Implementation of reversed engineered COM object for changing default audio endpoint
+#[allow(non_upper_case_globals)]
+pub const PolicyConfig: GUID = GUID::from_u128(0x870af99c_171d_4f9e_af0d_e63df40c2bc9);
+
+define_interface!(
+    IPolicyConfig,
+    IPolicyConfig_Vtbl,
+    0xf8679f50_850a_41cf_9c72_430f290290c8
+);
+impl std::ops::Deref for IPolicyConfig {
+    type Target = IUnknown;
+    fn deref(&self) -> &Self::Target {
+        unsafe { std::mem::transmute(self) }
+    }
+}
+interface_hierarchy!(IPolicyConfig, IUnknown);
+impl IPolicyConfig {
+    #[allow(non_snake_case, clippy::missing_safety_doc)]
+    pub unsafe fn SetDefaultEndpoint<P0>(
+        &self,
+        wszDeviceId: P0,
+        role: ERole,
+    ) -> windows::core::Result<()>
+    where
+        P0: Param<PWSTR>,
+    {
+        (Interface::vtable(self).SetDefaultEndpoint)(
+            Interface::as_raw(self),
+            wszDeviceId.param().abi(),
+            role,
+        )
+        .ok()
+    }
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct IPolicyConfig_Vtbl {
+    pub base__: IUnknown_Vtbl,
+    pub GetMixFormat: unsafe extern "system" fn(
+        this: *mut c_void,
+        pwstrid: PWSTR,
+        waveformatex: *mut c_void,
+    ) -> HRESULT,
+    pub GetDeviceFormat: unsafe extern "system" fn(
+        this: *mut c_void,
+        pwstrid: PWSTR,
+        param0: i32,
+        waveformatex: *mut c_void,
+    ) -> HRESULT,
+    pub ResetDeviceFormat: unsafe extern "system" fn(this: c_void, pwstrid: PWSTR) -> HRESULT,
+    pub SetDeviceFormat: unsafe extern "system" fn(
+        this: *mut c_void,
+        pwstrid: PWSTR,
+        waveformatex0: c_void,
+        waveformatex1: *mut c_void,
+    ) -> HRESULT,
+    pub GetProcessingPeriod: unsafe extern "system" fn(
+        this: *mut c_void,
+        pwstrid: PWSTR,
+        param0: i32,
+        param1: c_void,
+        param1: *mut c_void,
+    ) -> HRESULT,
+    pub SetProcessingPeriod:
+        unsafe extern "system" fn(this: c_void, pwstrid: PWSTR, param0: c_void) -> HRESULT,
+    pub GetShareMode: unsafe extern "system" fn(
+        this: *mut c_void,
+        pwstrid: PWSTR,
+        devicesharemode: *mut c_void,
+    ) -> HRESULT,
+    pub SetShareMode: unsafe extern "system" fn(
+        this: *mut c_void,
+        pwstrid: PWSTR,
+        devicesharemode: *mut c_void,
+    ) -> HRESULT,
+    pub GetPropertyValue: unsafe extern "system" fn(
+        this: *mut c_void,
+        pwstrid: PWSTR,
+        key: c_void,
+        propvariant: *mut c_void,
+    ) -> HRESULT,
+    pub SetPropertyValue: unsafe extern "system" fn(
+        this: *mut c_void,
+        pwstrid: PWSTR,
+        key: c_void,
+        propvariant: *mut c_void,
+    ) -> HRESULT,
+    pub SetDefaultEndpoint:
+        unsafe extern "system" fn(this: *mut c_void, pwstrid: PWSTR, role: ERole) -> HRESULT,
+    pub SetEndpointVisibility:
+        unsafe extern "system" fn(this: *mut c_void, pwstrid: PWSTR, param0: i32) -> HRESULT,
+}
+
+// Read the ID of a device, freeing the string allocated by COM
+fn device_id(device: &IMMDevice) -> windows::core::Result<String> {
+    unsafe {
+        let id = device.GetId()?;
+        let result = id.to_string();
+        CoTaskMemFree(Some(id.0 as *const c_void));
+        result.map_err(|_| E_UNEXPECTED.into())
+    }
+}
+
+fn device_name(device: &IMMDevice) -> windows::core::Result<String> {
+    Ok(unsafe {
+        device
+            .OpenPropertyStore(STGM_READ)?
+            .GetValue(&PKEY_Device_FriendlyName)?
+            .to_string()
+    })
+}
+
+// Name of the executable of a process without its extension
+fn process_name(process_id: u32) -> Option<String> {
+    unsafe {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, process_id).ok()?;
+        let mut buffer = [0u16; MAX_PATH as usize];
+        let mut size = buffer.len() as u32;
+        let result = QueryFullProcessImageNameW(
+            process,
+            PROCESS_NAME_WIN32,
+            PWSTR(buffer.as_mut_ptr()),
+            &mut size,
+        );
+        CloseHandle(process).ok();
+        result.ok()?;
+        let path = String::from_utf16_lossy(&buffer[..size as usize]);
+        Path::new(&path)
+            .file_stem()
+            .map(|name| name.to_string_lossy().into_owned())
+    }
+}
+
+// Display name a session sets for itself, which most applications leave empty
+fn session_display_name(session: &IAudioSessionControl2) -> Option<String> {
+    unsafe {
+        let name = session.GetDisplayName().ok()?;
+        let result = name.to_string();
+        CoTaskMemFree(Some(name.0 as *const c_void));
+        result.ok().filter(|name| !name.is_empty())
+    }
+}
+
+// The Windows role for a device role
+fn endpoint_role(role: DeviceRole) -> ERole {
+    match role {
+        DeviceRole::Console => eConsole,
+        DeviceRole::Multimedia => eMultimedia,
+        DeviceRole::Communications => eCommunications,
+    }
+}
+
+// Backend using the default capture device for a role, the communications one
+// unless set otherwise, or the pinned device. Requires COM to be initialized on
+// the calling thread.
+pub struct WasapiBackend {
+    policy_config: IPolicyConfig,
+    endpoint_enumerator: IMMDeviceEnumerator,
+    role: ERole,
+    pinned_device: Option<String>,
+    endpoint: Option<IMMDevice>,
+    endpoint_volume: Option<IAudioEndpointVolume>,
+    endpoint_meter: Option<EndpointMeter>,
+    metering: bool,
+    endpoint_callback: Option<IMMNotificationClient>,
+    endpoint_volume_callback: Option<IAudioEndpointVolumeCallback>,
+    // Volume controls of the other active capture devices, registered with the
+    // volume callback so changes to their mute state are reported too
+    device_volumes: Vec<IAudioEndpointVolume>,
+}
+
+impl WasapiBackend {
+    pub fn new() -> Result<Self> {
+        let mut backend = Self {
+            policy_config: unsafe { CoCreateInstance(&PolicyConfig, None, CLSCTX_ALL) }?,
+            endpoint_enumerator: unsafe {
+                CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
+            }?,
+            role: eCommunications,
+            pinned_device: None,
+            endpoint: None,
+            endpoint_volume: None,
+            endpoint_meter: None,
+            metering: false,
+            endpoint_callback: None,
+            endpoint_volume_callback: None,
+            device_volumes: Vec::new(),
+        };
+        backend.refresh()?;
+        Ok(backend)
+    }
+
+    fn init_endpoint(&mut self) -> windows::core::Result<()> {
+        self.deinit_endpoint();
+        if let Some(pattern) = &self.pinned_device {
+            let id = self
+                .capture_devices()?
+                .into_iter()
+                .find(|device| device.matches(pattern))
+                .map(|device| device.id);
+            self.endpoint = match id {
+                Some(id) => {
+                    Some(unsafe { self.endpoint_enumerator.GetDevice(&HSTRING::from(id)) }?)
+                }
+                _ => None,
+            };
+            return Ok(());
+        }
+        self.endpoint = unsafe {
+            self.endpoint_enumerator
+                .GetDefaultAudioEndpoint(eCapture, self.role)
+        }
+        .map_or_else(
+            |error| {
+                if error.code() == ERROR_NOT_FOUND.to_hresult() {
+                    Ok(None)
+                } else {
+                    Err(error)
+                }
+            },
+            |endpoint| Ok(Some(endpoint)),
+        )?;
+        Ok(())
+    }
+
+    fn init_endpoint_volume(&mut self) -> windows::core::Result<()> {
+        self.deinit_endpoint_volume();
+        self.endpoint_volume = match &self.endpoint {
+            Some(endpoint) => {
+                unsafe { endpoint.Activate::<IAudioEndpointVolume>(CLSCTX_ALL, None) }.map_or_else(
+                    |error| {
+                        if error.code() == E_NOINTERFACE {
+                            Ok(None)
+                        } else {
+                            Err(error)
+                        }
+                    },
+                    |endpoint_volume| {
+                        if let Some(callback) = &self.endpoint_volume_callback {
+                            unsafe { endpoint_volume.RegisterControlChangeNotify(callback) }?;
+                        }
+                        Ok(Some(endpoint_volume))
+                    },
+                )?
+            }
+            _ => None,
+        };
+        Ok(())
+    }
+
+    fn deinit_endpoint_volume(&mut self) {
+        if let Some(endpoint_volume) = self.endpoint_volume.take() {
+            if let Some(callback) = &self.endpoint_volume_callback {
+                unsafe { endpoint_volume.UnregisterControlChangeNotify(callback) }.ok();
+            }
+        }
+    }
+
+    // Volume control of any capture device by its id, None when it has none
+    fn device_volume(&self, id: &str) -> windows::core::Result<Option<IAudioEndpointVolume>> {
+        let device = unsafe { self.endpoint_enumerator.GetDevice(&HSTRING::from(id)) }?;
+        unsafe { device.Activate::<IAudioEndpointVolume>(CLSCTX_ALL, None) }.map_or_else(
+            |error| {
+                if error.code() == E_NOINTERFACE {
+                    Ok(None)
+                } else {
+                    Err(error)
+                }
+            },
+            |device_volume| Ok(Some(device_volume)),
+        )
+    }
+
+    fn init_device_volumes(&mut self) -> windows::core::Result<()> {
+        self.deinit_device_volumes();
+        let Some(callback) = &self.endpoint_volume_callback else {
+            return Ok(());
+        };
+        let current = self.endpoint.as_ref().map(device_id).transpose()?;
+        let devices = unsafe {
+            self.endpoint_enumerator
+                .EnumAudioEndpoints(eCapture, DEVICE_STATE_ACTIVE)
+        }?;
+        for i in 0..unsafe { devices.GetCount() }? {
+            let id = device_id(&unsafe { devices.Item(i) }?)?;
+            if Some(&id) == current.as_ref() {
+                continue;
+            }
+            if let Some(device_volume) = self.device_volume(&id)? {
+                unsafe { device_volume.RegisterControlChangeNotify(callback) }?;
+                self.device_volumes.push(device_volume);
+            }
+        }
+        Ok(())
+    }
+
+    fn deinit_device_volumes(&mut self) {
+        for device_volume in self.device_volumes.drain(..) {
+            if let Some(callback) = &self.endpoint_volume_callback {
+                unsafe { device_volume.UnregisterControlChangeNotify(callback) }.ok();
+            }
+        }
+    }
+
+    // Keep metering the same endpoint without restarting its stream, so other
+    // device changes do not interrupt it
+    fn init_endpoint_meter(&mut self) -> windows::core::Result<()> {
+        let endpoint = self.endpoint.as_ref().filter(|_| self.metering);
+        let id = endpoint.map(device_id).transpose()?;
+        if self.endpoint_meter.as_ref().map(|meter| &meter.id) == id.as_ref() {
+            return Ok(());
+        }
+        self.endpoint_meter = None;
+        if let (Some(endpoint), Some(id)) = (endpoint, id) {
+            self.endpoint_meter = Some(EndpointMeter {
+                meter: unsafe { endpoint.Activate::<IAudioMeterInformation>(CLSCTX_ALL, None) }?,
+                capture: start_capture(endpoint)?,
+                id,
+            });
+        }
+        Ok(())
+    }
+
+    // Stop metering when the meter cannot be opened, so the failure is only
+    // reported once instead of on every later refresh
+    fn apply_metering(&mut self) -> Result<()> {
+        let result = self.init_endpoint_meter();
+        if result.is_err() {
+            self.metering = false;
+            self.endpoint_meter = None;
+        }
+        Ok(result?)
+    }
+
+    fn deinit_endpoint(&mut self) {
+        self.deinit_endpoint_volume();
+        self.endpoint = None;
+    }
+}
+
+// Meter of an endpoint with a stream capturing from it, without which the meter
+// reads silence. Windows shows the microphone as in use while it exists.
+struct EndpointMeter {
+    id: String,
+    meter: IAudioMeterInformation,
+    capture: IAudioClient,
+}
+
+impl Drop for EndpointMeter {
+    fn drop(&mut self) {
+        unsafe { self.capture.Stop() }.ok();
+    }
+}
+
+// Start a shared mode stream capturing from the endpoint
+fn start_capture(endpoint: &IMMDevice) -> windows::core::Result<IAudioClient> {
+    // Buffer duration in units of 100 ns
+    const BUFFER_DURATION: i64 = 10_000_000;
+    let capture = unsafe { endpoint.Activate::<IAudioClient>(CLSCTX_ALL, None) }?;
+    let format = unsafe { capture.GetMixFormat() }?;
+    let initialized = unsafe {
+        capture.Initialize(
+            AUDCLNT_SHAREMODE_SHARED,
+            0,
+            BUFFER_DURATION,
+            0,
+            format,
+            None,
+        )
+    };
+    unsafe { CoTaskMemFree(Some(format as *const c_void)) };
+    initialized?;
+    unsafe { capture.Start() }?;
+    Ok(capture)
+}
+
+impl Drop for WasapiBackend {
+    fn drop(&mut self) {
+        self.deinit_endpoint();
+        self.deinit_device_volumes();
+        if let Some(callback) = self.endpoint_callback.take() {
+            unsafe {
+                self.endpoint_enumerator
+                    .UnregisterEndpointNotificationCallback(&callback)
+            }
+            .ok();
+        }
+    }
+}
+
+impl AudioBackend for WasapiBackend {
+    fn capture_devices(&self) -> Result<Vec<AudioDevice>> {
+        let devices = unsafe {
+            self.endpoint_enumerator
+                .EnumAudioEndpoints(eCapture, DEVICE_STATE_ACTIVE)
+        }?;
+        let devices_count = unsafe { devices.GetCount() }?;
+        (0..devices_count)
+            .map(|i| {
+                let device = unsafe { devices.Item(i) }?;
+                Ok(AudioDevice {
+                    id: device_id(&device)?,
+                    name: device_name(&device)?,
+                })
+            })
+            .collect()
+    }
+
+    fn default_device(&self) -> Result<Option<AudioDevice>> {
+        match &self.endpoint {
+            Some(endpoint) => Ok(Some(AudioDevice {
+                id: device_id(endpoint)?,
+                name: device_name(endpoint)?,
+            })),
+            _ => Ok(None),
+        }
+    }
+
+    fn set_default_device(&self, id: &str, roles: &[DeviceRole]) -> Result<()> {
+        let mut device_id = id
+            .encode_utf16()
+            .chain(std::iter::once(0))
+            .collect::<Vec<u16>>();
+        let device_id = PWSTR(device_id.as_mut_ptr());
+        for role in roles {
+            match unsafe {
+                self.policy_config
+                    .SetDefaultEndpoint(device_id, endpoint_role(*role))
+            } {
+                Err(error) if error.code() == NTE_NOT_FOUND => return Ok(()),
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    fn role_default_device(&self, role: DeviceRole) -> Result<Option<String>> {
+        match unsafe {
+            self.endpoint_enumerator
+                .GetDefaultAudioEndpoint(eCapture, endpoint_role(role))
+        } {
+            Err(error) if error.code() == ERROR_NOT_FOUND.to_hresult() => Ok(None),
+            result => Ok(Some(device_id(&result?)?)),
+        }
+    }
+
+    fn muted(&self) -> Result<Option<bool>> {
+        match &self.endpoint_volume {
+            Some(endpoint_volume) => Ok(Some(unsafe { endpoint_volume.GetMute() }?.as_bool())),
+            _ => Ok(None),
+        }
+    }
+
+    fn set_muted(&self, muted: bool) -> Result<()> {
+        match &self.endpoint_volume {
+            Some(endpoint_volume) => {
+                Ok(unsafe { endpoint_volume.SetMute(muted, std::ptr::null()) }?)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn device_muted(&self, id: &str) -> Result<Option<bool>> {
+        match self.device_volume(id)? {
+            Some(device_volume) => Ok(Some(unsafe { device_volume.GetMute() }?.as_bool())),
+            _ => Ok(None),
+        }
+    }
+
+    fn set_device_muted(&self, id: &str, muted: bool) -> Result<()> {
+        match self.device_volume(id)? {
+            Some(device_volume) => Ok(unsafe { device_volume.SetMute(muted, std::ptr::null()) }?),
+            _ => Ok(()),
+        }
+    }
+
+    fn volume(&self) -> Result<Option<f32>> {
+        match &self.endpoint_volume {
+            Some(endpoint_volume) => Ok(Some(unsafe {
+                endpoint_volume.GetMasterVolumeLevelScalar()
+            }?)),
+            _ => Ok(None),
+        }
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<()> {
+        match &self.endpoint_volume {
+            Some(endpoint_volume) => {
+                Ok(
+                    unsafe {
+                        endpoint_volume.SetMasterVolumeLevelScalar(volume, std::ptr::null())
+                    }?,
+                )
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // The meter of the endpoint only measures while a stream captures from it,
+    // so one is kept running whose data is never read
+    fn set_metering(&mut self, enabled: bool) -> Result<()> {
+        self.metering = enabled;
+        self.apply_metering()
+    }
+
+    fn peak(&self) -> Result<Option<f32>> {
+        match &self.endpoint_meter {
+            Some(endpoint_meter) => Ok(Some(unsafe { endpoint_meter.meter.GetPeakValue() }?)),
+            _ => Ok(None),
+        }
+    }
+
+    // Sessions only report becoming active to callbacks registered on each of
+    // them, so Streams events are not sent and front ends poll this instead
+    fn capture_streams(&self) -> Result<Vec<CaptureStream>> {
+        let Some(endpoint) = &self.endpoint else {
+            return Ok(Vec::new());
+        };
+        let sessions = unsafe {
+            endpoint
+                .Activate::<IAudioSessionManager2>(CLSCTX_ALL, None)?
+                .GetSessionEnumerator()
+        }?;
+        let mut streams = Vec::new();
+        for i in 0..unsafe { sessions.GetCount() }? {
+            let session = unsafe { sessions.GetSession(i) }?;
+            if unsafe { session.GetState() }? != AudioSessionStateActive {
+                continue;
+            }
+            let session: IAudioSessionControl2 = session.cast()?;
+            let process_id = unsafe { session.GetProcessId() }
+                .ok()
+                .filter(|process_id| *process_id != 0);
+            // Our own stream for metering is not an application using the device
+            if process_id == Some(std::process::id()) {
+                continue;
+            }
+            let stream = CaptureStream {
+                application: process_id
+                    .and_then(process_name)
+                    .or_else(|| session_display_name(&session))
+                    .unwrap_or_default(),
+                process_id,
+            };
+            if !streams.contains(&stream) {
+                streams.push(stream);
+            }
+        }
+        Ok(streams)
+    }
+
+    fn subscribe(&mut self, listener: AudioEventListener) -> Result<()> {
+        self.deinit_endpoint_volume();
+        self.deinit_device_volumes();
+        if let Some(callback) = self.endpoint_callback.take() {
+            unsafe {
+                self.endpoint_enumerator
+                    .UnregisterEndpointNotificationCallback(&callback)
+            }?;
+        }
+        let listener = Arc::new(listener);
+        let endpoint_callback: IMMNotificationClient = AudioEndpointCallback {
+            listener: listener.clone(),
+        }
+        .into();
+        unsafe {
+            self.endpoint_enumerator
+                .RegisterEndpointNotificationCallback(&endpoint_callback)
+        }?;
+        self.endpoint_callback = Some(endpoint_callback);
+        self.endpoint_volume_callback = Some(AudioEndpointVolumeCallback { listener }.into());
+        self.init_endpoint_volume()?;
+        self.init_device_volumes()?;
+        Ok(())
+    }
+
+    fn set_followed_role(&mut self, role: DeviceRole) {
+        self.role = endpoint_role(role);
+    }
+
+    fn set_pinned_device(&mut self, pattern: Option<String>) {
+        self.pinned_device = pattern;
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        self.init_endpoint()?;
+        self.init_endpoint_volume()?;
+        self.init_device_volumes()?;
+        self.apply_metering()
+    }
+}
@@ -0,0 +1,602 @@
+// Configuration from the config file, with the command line options applied on top
+
+use crate::{
+    hotkey::{Hotkey, HotkeyAction},
+    CliArgs, DefaultDeviceRoles, FollowRole, IconTheme,
+};
+use microphone_mute_indicator::{
+    audio::DeviceRole, indicator::TalkingWhileMuted, notification::NotificationKinds, sound::Cue,
+};
+use serde::Deserialize;
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+const CONFIG_DIRECTORY: &str = "microphone-mute-indicator";
+const CONFIG_FILE: &str = "config.toml";
+
+// Sound played for a cue
+#[derive(Clone, Debug, PartialEq)]
+pub enum Sound {
+    BuiltIn(Cue),
+    File(PathBuf),
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(path, error) => write!(f, "Cannot read {}: {}", path.display(), error),
+            Error::Parse(path, error) => {
+                write!(f, "Invalid config in {}: {}", path.display(), error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(windows)]
+impl From<Error> for windows::core::Error {
+    fn from(error: Error) -> Self {
+        windows::core::Error::new(windows::Win32::Foundation::E_FAIL, error.to_string())
+    }
+}
+
+// Options use the names of the command line options without the config- prefix
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Config {
+    pub force_keep_volume_at_max: bool,
+    pub force_keep_volume_at: Option<u32>,
+    pub icon_theme: Option<IconTheme>,
+    pub icon_active: Option<PathBuf>,
+    pub icon_muted: Option<PathBuf>,
+    pub hotkey_toggle_mute: Option<Hotkey>,
+    pub hotkey_mute: Option<Hotkey>,
+    pub hotkey_unmute: Option<Hotkey>,
+    pub hotkey_push_to_talk: Option<Hotkey>,
+    pub hotkey_push_to_mute: Option<Hotkey>,
+    pub push_to_talk_release_delay: Option<u64>,
+    pub notify_mute: bool,
+    pub notify_device: bool,
+    pub notify_volume: bool,
+    pub sound_cues: bool,
+    pub sound_muted: Option<PathBuf>,
+    pub sound_unmuted: Option<PathBuf>,
+    pub level_meter: bool,
+    pub mute_all_devices: bool,
+    pub mute_group: Vec<String>,
+    pub pinned_device: Option<String>,
+    pub follow_role: Option<FollowRole>,
+    pub default_device_roles: Option<DefaultDeviceRoles>,
+    pub preferred_devices: Vec<String>,
+    pub notify_talking_while_muted: bool,
+    pub flash_talking_while_muted: bool,
+    pub talking_while_muted_threshold: Option<i32>,
+    pub talking_while_muted_hold_time: Option<u64>,
+}
+
+// Default location of the config file: %APPDATA% on Windows and
+// $XDG_CONFIG_HOME (defaulting to ~/.config) elsewhere
+pub fn default_path() -> Option<PathBuf> {
+    #[cfg(windows)]
+    let directory = std::env::var_os("APPDATA").map(PathBuf::from);
+    #[cfg(not(windows))]
+    let directory = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|directory| directory.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+    directory.map(|directory| directory.join(CONFIG_DIRECTORY).join(CONFIG_FILE))
+}
+
+impl Config {
+    // Read a config file. Relative icon and sound paths are relative to the file.
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        let text =
+            std::fs::read_to_string(path).map_err(|error| Error::Io(path.to_owned(), error))?;
+        let mut config: Config =
+            toml::from_str(&text).map_err(|error| Error::Parse(path.to_owned(), error))?;
+        if let Some(directory) = path.parent() {
+            for file in [
+                &mut config.icon_active,
+                &mut config.icon_muted,
+                &mut config.sound_muted,
+                &mut config.sound_unmuted,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                *file = directory.join(&*file);
+            }
+        }
+        Ok(config)
+    }
+
+    // Volume in percent to keep the microphone at, if any
+    pub fn forced_volume(&self) -> Option<u32> {
+        self.force_keep_volume_at
+            .or(self.force_keep_volume_at_max.then_some(100))
+    }
+
+    // Configured hotkeys with what they do
+    pub fn hotkeys(&self) -> Vec<(Hotkey, HotkeyAction)> {
+        [
+            (self.hotkey_toggle_mute, HotkeyAction::ToggleMute),
+            (self.hotkey_mute, HotkeyAction::Mute),
+            (self.hotkey_unmute, HotkeyAction::Unmute),
+            (self.hotkey_push_to_talk, HotkeyAction::PushToTalk),
+            (self.hotkey_push_to_mute, HotkeyAction::PushToMute),
+        ]
+        .into_iter()
+        .filter_map(|(hotkey, action)| hotkey.map(|hotkey| (hotkey, action)))
+        .collect()
+    }
+
+    // How long push to talk and push to mute keep their state after the key is
+    // released, in milliseconds
+    pub fn hold_release_delay(&self) -> Duration {
+        Duration::from_millis(self.push_to_talk_release_delay.unwrap_or(0))
+    }
+
+    // Role of the default device to show
+    pub fn followed_role(&self) -> DeviceRole {
+        match self.follow_role {
+            Some(FollowRole::Communications) | None => DeviceRole::Communications,
+            Some(FollowRole::Console) => DeviceRole::Console,
+            Some(FollowRole::Multimedia) => DeviceRole::Multimedia,
+        }
+    }
+
+    // Defaults set when switching devices
+    pub fn default_roles(&self) -> Vec<DeviceRole> {
+        match self.default_device_roles {
+            Some(DefaultDeviceRoles::Communications) => vec![DeviceRole::Communications],
+            Some(DefaultDeviceRoles::Console) => vec![DeviceRole::Console, DeviceRole::Multimedia],
+            Some(DefaultDeviceRoles::All) | None => DeviceRole::ALL.to_vec(),
+        }
+    }
+
+    // Changes to show a notification for
+    pub fn notifications(&self) -> NotificationKinds {
+        NotificationKinds {
+            mute: self.notify_mute,
+            device: self.notify_device,
+            volume: self.notify_volume,
+            talking_while_muted: self.notify_talking_while_muted,
+        }
+    }
+
+    // When to notice talking into the muted microphone, if anything is done
+    // about it
+    pub fn talking_while_muted(&self) -> Option<TalkingWhileMuted> {
+        let default = TalkingWhileMuted::default();
+        (self.notify_talking_while_muted || self.flash_talking_while_muted).then(|| {
+            TalkingWhileMuted {
+                threshold_db: self
+                    .talking_while_muted_threshold
+                    .map_or(default.threshold_db, |threshold| threshold as f32),
+                hold_time: self
+                    .talking_while_muted_hold_time
+                    .map_or(default.hold_time, Duration::from_millis),
+            }
+        })
+    }
+
+    // Sound to play for a cue: the configured file, or the built-in sound when
+    // there is none, or nothing when cues are not enabled
+    pub fn sound(&self, cue: Cue) -> Option<Sound> {
+        let file = match cue {
+            Cue::Muted => &self.sound_muted,
+            Cue::Unmuted => &self.sound_unmuted,
+        };
+        match file {
+            _ if !self.sound_cues => None,
+            Some(path) => Some(Sound::File(path.clone())),
+            _ => Some(Sound::BuiltIn(cue)),
+        }
+    }
+
+    // Path of the config file to use: the one given on the command line or the
+    // default one
+    pub fn path(args: &CliArgs) -> Option<PathBuf> {
+        args.config.clone().or_else(default_path)
+    }
+
+    // Load the config file and apply the command line options to it. A missing
+    // default config file is not an error, a missing explicitly given one is.
+    pub fn load(args: &CliArgs) -> Result<Self, Error> {
+        let config = match Self::path(args) {
+            Some(path) if args.config.is_some() || path.exists() => Self::read(&path)?,
+            _ => Self::default(),
+        };
+        Ok(config.with_args(args))
+    }
+
+    // Apply the command line options, which take precedence
+    pub fn with_args(self, args: &CliArgs) -> Self {
+        Self {
+            force_keep_volume_at_max: self.force_keep_volume_at_max
+                || args.config_force_keep_volume_at_max,
+            force_keep_volume_at: args
+                .config_force_keep_volume_at
+                .or(args.config_force_keep_volume_at_max.then_some(100))
+                .or(self.force_keep_volume_at),
+            icon_theme: args.config_icon_theme.or(self.icon_theme),
+            icon_active: args.config_icon_active.clone().or(self.icon_active),
+            icon_muted: args.config_icon_muted.clone().or(self.icon_muted),
+            hotkey_toggle_mute: args.config_hotkey_toggle_mute.or(self.hotkey_toggle_mute),
+            hotkey_mute: args.config_hotkey_mute.or(self.hotkey_mute),
+            hotkey_unmute: args.config_hotkey_unmute.or(self.hotkey_unmute),
+            hotkey_push_to_talk: args.config_hotkey_push_to_talk.or(self.hotkey_push_to_talk),
+            hotkey_push_to_mute: args.config_hotkey_push_to_mute.or(self.hotkey_push_to_mute),
+            push_to_talk_release_delay: args
+                .config_push_to_talk_release_delay
+                .or(self.push_to_talk_release_delay),
+            notify_mute: self.notify_mute || args.config_notify_mute,
+            notify_device: self.notify_device || args.config_notify_device,
+            notify_volume: self.notify_volume || args.config_notify_volume,
+            sound_cues: self.sound_cues || args.config_sound_cues,
+            sound_muted: args.config_sound_muted.clone().or(self.sound_muted),
+            sound_unmuted: args.config_sound_unmuted.clone().or(self.sound_unmuted),
+            level_meter: self.level_meter || args.config_level_meter,
+            mute_all_devices: self.mute_all_devices || args.config_mute_all_devices,
+            mute_group: if args.config_mute_group.is_empty() {
+                self.mute_group
+            } else {
+                args.config_mute_group.clone()
+            },
+            pinned_device: args.config_pinned_device.clone().or(self.pinned_device),
+            follow_role: args.config_role.or(self.follow_role),
+            default_device_roles: args
+                .config_default_device_roles
+                .or(self.default_device_roles),
+            preferred_devices: if args.config_preferred_device.is_empty() {
+                self.preferred_devices
+            } else {
+                args.config_preferred_device.clone()
+            },
+            notify_talking_while_muted: self.notify_talking_while_muted
+                || args.config_notify_talking_while_muted,
+            flash_talking_while_muted: self.flash_talking_while_muted
+                || args.config_flash_talking_while_muted,
+            talking_while_muted_threshold: args
+                .config_talking_while_muted_threshold
+                .or(self.talking_while_muted_threshold),
+            talking_while_muted_hold_time: args
+                .config_talking_while_muted_hold_time
+                .or(self.talking_while_muted_hold_time),
+        }
+    }
+}
+
+// Watches the config file of a running instance so changes can be applied
+// without restarting. The file is polled by its modification time, which also
+// notices the file being created or removed.
+pub struct ConfigWatcher {
+    args: CliArgs,
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+}
+
+fn modified(path: Option<&Path>) -> Option<SystemTime> {
+    path.and_then(|path| std::fs::metadata(path).ok())
+        .and_then(|metadata| metadata.modified().ok())
+}
+
+impl ConfigWatcher {
+    // Start watching from the current state of the file, which is assumed to
+    // have been loaded already
+    pub fn new(args: CliArgs) -> Self {
+        let path = Config::path(&args);
+        let modified = modified(path.as_deref());
+        Self {
+            args,
+            path,
+            modified,
+        }
+    }
+
+    // Load the config again when the file changed since the last call
+    pub fn poll(&mut self) -> Option<Result<Config, Error>> {
+        let modified = modified(self.path.as_deref());
+        if modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+        Some(Config::load(&self.args))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argh::FromArgs;
+
+    fn args(args: &[&str]) -> CliArgs {
+        CliArgs::from_args(&["microphone-mute-indicator"], args).unwrap()
+    }
+
+    fn write_config(name: &str, text: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("{}-{}", CONFIG_DIRECTORY, name));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join(CONFIG_FILE);
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn config_file_is_read() {
+        let path = write_config(
+            "read",
+            "force-keep-volume-at-max = true\nicon-theme = \"inverted\"\nicon-muted = \"muted.ico\"\n\
+             level-meter = true\nmute-all-devices = true\npinned-device = \"*USB*\"\n",
+        );
+        assert_eq!(
+            Config::load(&args(&["--config", path.to_str().unwrap()])).unwrap(),
+            Config {
+                force_keep_volume_at_max: true,
+                force_keep_volume_at: None,
+                icon_theme: Some(IconTheme::Inverted),
+                icon_active: None,
+                icon_muted: Some(path.parent().unwrap().join("muted.ico")),
+                level_meter: true,
+                mute_all_devices: true,
+                pinned_device: Some("*USB*".to_owned()),
+                ..Config::default()
+            }
+        );
+    }
+
+    #[test]
+    fn command_line_overrides_config_file() {
+        let path = write_config(
+            "override",
+            "icon-theme = \"inverted\"\nicon-active = \"a.ico\"\n",
+        );
+        let config = Config::load(&args(&[
+            "--config",
+            path.to_str().unwrap(),
+            "--config-icon-theme",
+            "normal",
+            "--config-force-keep-volume-at-max",
+        ]))
+        .unwrap();
+        assert_eq!(config.icon_theme, Some(IconTheme::Normal));
+        assert_eq!(
+            config.icon_active,
+            Some(path.parent().unwrap().join("a.ico"))
+        );
+        assert_eq!(config.forced_volume(), Some(100));
+    }
+
+    #[test]
+    fn hotkeys_are_read() {
+        let path = write_config(
+            "hotkeys",
+            "hotkey-toggle-mute = \"Ctrl+Alt+M\"\nhotkey-push-to-talk = \"F13\"\n\
+             hotkey-push-to-mute = \"Mouse4\"\npush-to-talk-release-delay = 250\n",
+        );
+        let config = Config::load(&args(&[
+            "--config",
+            path.to_str().unwrap(),
+            "--config-hotkey-push-to-talk",
+            "Pause",
+        ]))
+        .unwrap();
+        assert_eq!(
+            config.hotkeys(),
+            [
+                ("Ctrl+Alt+M".parse().unwrap(), HotkeyAction::ToggleMute),
+                ("Pause".parse().unwrap(), HotkeyAction::PushToTalk),
+                ("Mouse4".parse().unwrap(), HotkeyAction::PushToMute),
+            ]
+        );
+        assert_eq!(config.hold_release_delay(), Duration::from_millis(250));
+        let path = write_config("invalid-hotkey", "hotkey-mute = \"Ctrl+\"\n");
+        assert!(matches!(
+            Config::load(&args(&["--config", path.to_str().unwrap()])),
+            Err(Error::Parse(..))
+        ));
+    }
+
+    #[test]
+    fn forced_volume_level_takes_precedence() {
+        let path = write_config(
+            "forced-volume",
+            "force-keep-volume-at-max = true\nforce-keep-volume-at = 80\n",
+        );
+        let config = args(&["--config", path.to_str().unwrap()]);
+        assert_eq!(Config::load(&config).unwrap().forced_volume(), Some(80));
+        let config = args(&[
+            "--config",
+            path.to_str().unwrap(),
+            "--config-force-keep-volume-at",
+            "90",
+        ]);
+        assert_eq!(Config::load(&config).unwrap().forced_volume(), Some(90));
+    }
+
+    #[test]
+    fn sounds_are_read_relative_to_config() {
+        let path = write_config("sounds", "sound-cues = true\nsound-muted = \"muted.wav\"\n");
+        let config = Config::load(&args(&["--config", path.to_str().unwrap()])).unwrap();
+        assert_eq!(
+            config.sound(Cue::Muted),
+            Some(Sound::File(path.parent().unwrap().join("muted.wav")))
+        );
+        assert_eq!(
+            config.sound(Cue::Unmuted),
+            Some(Sound::BuiltIn(Cue::Unmuted))
+        );
+        let config = Config::load(&args(&["--config-sound-muted", "muted.wav"])).unwrap();
+        assert_eq!(config.sound(Cue::Muted), None);
+    }
+
+    #[test]
+    fn notifications_are_combined() {
+        let path = write_config(
+            "notifications",
+            "notify-mute = true
+",
+        );
+        let config = Config::load(&args(&[
+            "--config",
+            path.to_str().unwrap(),
+            "--config-notify-volume",
+        ]))
+        .unwrap();
+        assert_eq!(
+            config.notifications(),
+            NotificationKinds {
+                mute: true,
+                device: false,
+                volume: true,
+                talking_while_muted: false,
+            }
+        );
+    }
+
+    #[test]
+    fn mute_group_is_replaced_by_command_line() {
+        let path = write_config("group", "mute-group = [\"Headset\", \"Desk microphone\"]\n");
+        let config = Config::load(&args(&["--config", path.to_str().unwrap()])).unwrap();
+        assert_eq!(config.mute_group, ["Headset", "Desk microphone"]);
+        let config = Config::load(&args(&[
+            "--config",
+            path.to_str().unwrap(),
+            "--config-mute-group",
+            "Webcam",
+            "--config-mute-group",
+            "Headset",
+        ]))
+        .unwrap();
+        assert_eq!(config.mute_group, ["Webcam", "Headset"]);
+    }
+
+    #[test]
+    fn default_device_roles_are_read() {
+        let path = write_config("roles", "default-device-roles = \"communications\"\n");
+        let config = Config::load(&args(&["--config", path.to_str().unwrap()])).unwrap();
+        assert_eq!(config.default_roles(), [DeviceRole::Communications]);
+        let config = Config::load(&args(&[
+            "--config",
+            path.to_str().unwrap(),
+            "--config-default-device-roles",
+            "all",
+        ]))
+        .unwrap();
+        assert_eq!(config.default_roles(), DeviceRole::ALL);
+        assert_eq!(Config::default().default_roles(), DeviceRole::ALL);
+    }
+
+    #[test]
+    fn followed_role_is_read() {
+        let path = write_config("follow", "follow-role = \"multimedia\"\n");
+        let config = Config::load(&args(&["--config", path.to_str().unwrap()])).unwrap();
+        assert_eq!(config.followed_role(), DeviceRole::Multimedia);
+        let config = Config::load(&args(&[
+            "--config",
+            path.to_str().unwrap(),
+            "--config-role",
+            "console",
+        ]))
+        .unwrap();
+        assert_eq!(config.followed_role(), DeviceRole::Console);
+        assert_eq!(
+            Config::default().followed_role(),
+            DeviceRole::Communications
+        );
+    }
+
+    #[test]
+    fn preferred_devices_keep_their_order() {
+        let path = write_config(
+            "preferred",
+            "preferred-devices = [\"*dock*\", \"Microphone Array*\"]\n",
+        );
+        let config = Config::load(&args(&["--config", path.to_str().unwrap()])).unwrap();
+        assert_eq!(config.preferred_devices, ["*dock*", "Microphone Array*"]);
+        let config = Config::load(&args(&[
+            "--config",
+            path.to_str().unwrap(),
+            "--config-preferred-device",
+            "Headset",
+        ]))
+        .unwrap();
+        assert_eq!(config.preferred_devices, ["Headset"]);
+    }
+
+    #[test]
+    fn talking_while_muted_needs_a_warning() {
+        let path = write_config(
+            "talking",
+            "talking-while-muted-threshold = -40
+talking-while-muted-hold-time = 500
+",
+        );
+        let config = Config::load(&args(&["--config", path.to_str().unwrap()])).unwrap();
+        assert_eq!(config.talking_while_muted(), None);
+        let config = Config::load(&args(&[
+            "--config",
+            path.to_str().unwrap(),
+            "--config-flash-talking-while-muted",
+            "--config-talking-while-muted-threshold",
+            "-35",
+        ]))
+        .unwrap();
+        assert_eq!(
+            config.talking_while_muted(),
+            Some(TalkingWhileMuted {
+                threshold_db: -35.0,
+                hold_time: Duration::from_millis(500),
+            })
+        );
+        let config = Config::load(&args(&["--config-notify-talking-while-muted"])).unwrap();
+        assert_eq!(
+            config.talking_while_muted(),
+            Some(TalkingWhileMuted::default())
+        );
+    }
+
+    #[test]
+    fn invalid_or_missing_config_file_is_an_error() {
+        let path = write_config("invalid", "icon-theme = \"purple\"\n");
+        assert!(matches!(
+            Config::load(&args(&["--config", path.to_str().unwrap()])),
+            Err(Error::Parse(..))
+        ));
+        assert!(matches!(
+            Config::load(&args(&["--config", "/nonexistent/config.toml"])),
+            Err(Error::Io(..))
+        ));
+    }
+
+    #[test]
+    fn watcher_reloads_changed_config_file() {
+        let path = write_config("watch", "force-keep-volume-at-max = true\n");
+        let mut watcher = ConfigWatcher::new(args(&["--config", path.to_str().unwrap()]));
+        assert!(watcher.poll().is_none());
+        std::fs::write(&path, "icon-theme = \"normal\"\n").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        assert_eq!(
+            watcher.poll().unwrap().unwrap(),
+            Config {
+                icon_theme: Some(IconTheme::Normal),
+                ..Config::default()
+            }
+        );
+        assert!(watcher.poll().is_none());
+    }
+}
@@ -0,0 +1,988 @@
+// State behind the indicator: the audio backend and the configuration applied to it
+
+use crate::{
+    audio::{
+        AudioBackend, AudioDevice, AudioEvent, AudioEventListener, CaptureStream, DeviceRole,
+        Result,
+    },
+    indicator::{
+        indicator_state, indicator_state_of_all, missing_pinned_device_label, IndicatorState,
+        LevelMeter, TalkingDetector, TalkingWhileMuted, LEVEL_METER_INTERVAL,
+    },
+    status::Status,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+// When the forced volume has to be restored more often than this within a
+// second something else keeps changing it, so we stop fighting it for a while
+const MAX_CORRECTIONS_PER_SECOND: usize = 5;
+const FORCED_VOLUME_PAUSE: Duration = Duration::from_secs(30);
+
+// Mute state forced while a push to talk or push to mute key is held, with the
+// state to restore once it is released
+struct Hold {
+    restore: Option<bool>,
+    release_at: Option<Instant>,
+}
+
+pub struct Controller {
+    audio_backend: Box<dyn AudioBackend>,
+    forced_volume: Option<u32>,
+    corrections: VecDeque<Instant>,
+    forced_volume_paused_until: Option<Instant>,
+    hold: Option<Hold>,
+    hold_release_delay: Duration,
+    // Whether muting and unmuting applies to all capture devices
+    mute_all: bool,
+    // Id and mute state of the current device when it was last seen, taken
+    // over by the next current device
+    last_mute_state: Option<(String, bool)>,
+    // Devices kept in the same mute state by id or name, with the mute states
+    // they were last seen in by id
+    mute_group: Vec<String>,
+    mute_group_states: HashMap<String, bool>,
+    // Pattern of the device used instead of a default one
+    pinned_device: Option<String>,
+    // Defaults set when switching devices without picking a role
+    default_roles: Vec<DeviceRole>,
+    // Device patterns in order of preference, with the ids of the devices
+    // present when the preferred device was last selected
+    preferred_devices: Vec<String>,
+    known_devices: Vec<String>,
+    // Level meter shown in the icon and detector for talking while muted, both
+    // fed from the input level which is next read at metering_due
+    level_meter: Option<LevelMeter>,
+    talking_detector: Option<TalkingDetector>,
+    metering_due: Option<Instant>,
+}
+
+impl Controller {
+    pub fn new(audio_backend: Box<dyn AudioBackend>) -> Self {
+        let mut controller = Self {
+            audio_backend,
+            forced_volume: None,
+            corrections: VecDeque::new(),
+            forced_volume_paused_until: None,
+            hold: None,
+            hold_release_delay: Duration::ZERO,
+            mute_all: false,
+            last_mute_state: None,
+            mute_group: Vec::new(),
+            mute_group_states: HashMap::new(),
+            pinned_device: None,
+            default_roles: DeviceRole::ALL.to_vec(),
+            preferred_devices: Vec::new(),
+            known_devices: Vec::new(),
+            level_meter: None,
+            talking_detector: None,
+            metering_due: None,
+        };
+        controller.remember_mute_state().ok();
+        controller
+    }
+
+    pub fn audio_backend(&self) -> &dyn AudioBackend {
+        self.audio_backend.as_ref()
+    }
+
+    // Volume in percent the current device is set back to whenever it changes
+    pub fn forced_volume(&self) -> Option<u32> {
+        self.forced_volume
+    }
+
+    pub fn set_forced_volume(&mut self, forced_volume: Option<u32>) {
+        self.forced_volume = forced_volume.map(|percent| percent.min(100));
+        self.corrections.clear();
+        self.forced_volume_paused_until = None;
+    }
+
+    // Whether restoring the forced volume is paused because something else
+    // keeps changing the volume
+    pub fn forced_volume_paused(&self) -> bool {
+        self.forced_volume_paused_at(Instant::now())
+    }
+
+    fn forced_volume_paused_at(&self, now: Instant) -> bool {
+        self.forced_volume_paused_until
+            .is_some_and(|until| now < until)
+    }
+
+    // Set the volume of the current device to the forced volume if it differs
+    pub fn apply_forced_volume(&self) -> Result<()> {
+        match self.forced_volume {
+            Some(percent) if self.volume_percent()? != Some(percent) => {
+                self.set_volume_percent(percent)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Mute and unmute all capture devices instead of only the current one
+    pub fn set_mute_all(&mut self, mute_all: bool) {
+        self.mute_all = mute_all;
+    }
+
+    // Keep the mute state of the given devices, by id or name, the same as
+    // whichever of them changed last. Starts from their current state.
+    pub fn set_mute_group(&mut self, mute_group: Vec<String>) -> Result<()> {
+        self.mute_group = mute_group;
+        self.mute_group_states.clear();
+        self.sync_mute_group()
+    }
+
+    // Mute states of the devices in the mute group by id, only reading the
+    // state of the devices in it
+    fn mute_group_states(&self) -> Result<Vec<(String, bool)>> {
+        let mut states = Vec::new();
+        let members = self
+            .audio_backend
+            .capture_devices()?
+            .into_iter()
+            .filter(|device| self.mute_group.iter().any(|member| device.matches(member)));
+        for device in members {
+            if let Some(muted) = self.audio_backend.device_muted(&device.id)? {
+                states.push((device.id, muted));
+            }
+        }
+        Ok(states)
+    }
+
+    // Bring the mute group in line with the device which changed since it was
+    // last seen. Devices joining the group take over the state of the others.
+    fn sync_mute_group(&mut self) -> Result<()> {
+        if self.mute_group.is_empty() {
+            return Ok(());
+        }
+        let mut states = self.mute_group_states()?;
+        let changed = states.iter().find(|(id, muted)| {
+            self.mute_group_states
+                .get(id)
+                .is_some_and(|last| last != muted)
+        });
+        let seen = states
+            .iter()
+            .find(|(id, _)| self.mute_group_states.contains_key(id));
+        if let Some((_, muted)) = changed.or(seen).cloned() {
+            for (id, state) in &mut states {
+                if *state != muted {
+                    self.audio_backend.set_device_muted(id, muted)?;
+                    *state = muted;
+                }
+            }
+        }
+        self.mute_group_states = states.into_iter().collect();
+        Ok(())
+    }
+
+    // Make the first present device matching the patterns the default one
+    // whenever devices are added or removed, starting right away
+    pub fn set_preferred_devices(&mut self, preferred_devices: Vec<String>) -> Result<()> {
+        self.preferred_devices = preferred_devices;
+        self.known_devices.clear();
+        self.select_preferred_device()
+    }
+
+    // Switch to the preferred device if the devices changed since last time,
+    // leaving a device picked by hand alone otherwise
+    fn select_preferred_device(&mut self) -> Result<()> {
+        if self.preferred_devices.is_empty() {
+            return Ok(());
+        }
+        let devices = self.audio_backend.capture_devices()?;
+        let ids: Vec<String> = devices.iter().map(|device| device.id.clone()).collect();
+        if ids == self.known_devices {
+            return Ok(());
+        }
+        self.known_devices = ids;
+        let preferred = self
+            .preferred_devices
+            .iter()
+            .find_map(|pattern| devices.iter().find(|device| device.matches(pattern)));
+        match preferred {
+            Some(device) if !self.is_default_device(&device.id)? => {
+                self.set_default_device(&device.id)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn remember_mute_state(&mut self) -> Result<()> {
+        if let (Some(device), Some(muted)) = (
+            self.audio_backend.default_device()?,
+            self.audio_backend.muted()?,
+        ) {
+            self.last_mute_state = Some((device.id, muted));
+        }
+        Ok(())
+    }
+
+    // A new current device takes over the mute state of the previous one, so
+    // switching devices never unmutes by accident
+    fn inherit_mute_state(&self) -> Result<()> {
+        let (Some((last_id, muted)), Some(device)) =
+            (&self.last_mute_state, self.audio_backend.default_device()?)
+        else {
+            return Ok(());
+        };
+        if device.id != *last_id && self.audio_backend.muted()?.is_some_and(|m| m != *muted) {
+            self.audio_backend.set_muted(*muted)?;
+        }
+        Ok(())
+    }
+
+    // How long the mute state is kept after a held key is released, so the end
+    // of a word is not cut off
+    pub fn set_hold_release_delay(&mut self, delay: Duration) {
+        self.hold_release_delay = delay;
+    }
+
+    // Force the mute state while a key is held: unmuted for push to talk and
+    // muted for push to mute. Pressing the key again before a pending release
+    // keeps the state that was there before the first press.
+    pub fn hold(&mut self, muted: bool) -> Result<()> {
+        match &mut self.hold {
+            Some(hold) => hold.release_at = None,
+            _ => {
+                self.hold = Some(Hold {
+                    restore: self.muted()?,
+                    release_at: None,
+                })
+            }
+        }
+        self.set_muted(muted)
+    }
+
+    // Restore the state from before the key was held after the release delay,
+    // which is done by finish_hold once the time from hold_release_due is reached
+    pub fn release_hold(&mut self) -> Result<()> {
+        self.release_hold_at(Instant::now())
+    }
+
+    fn release_hold_at(&mut self, now: Instant) -> Result<()> {
+        if let Some(hold) = &mut self.hold {
+            hold.release_at = Some(now + self.hold_release_delay);
+        }
+        self.finish_hold_at(now)
+    }
+
+    // When a released hold is due to be finished
+    pub fn hold_release_due(&self) -> Option<Instant> {
+        self.hold.as_ref().and_then(|hold| hold.release_at)
+    }
+
+    pub fn finish_hold(&mut self) -> Result<()> {
+        self.finish_hold_at(Instant::now())
+    }
+
+    fn finish_hold_at(&mut self, now: Instant) -> Result<()> {
+        match self.hold_release_due() {
+            Some(release_at) if release_at <= now => self.end_hold(),
+            _ => Ok(()),
+        }
+    }
+
+    // Restore the state from before the key was held right away, like when exiting
+    pub fn end_hold(&mut self) -> Result<()> {
+        match self.hold.take().and_then(|hold| hold.restore) {
+            Some(muted) => self.set_muted(muted),
+            _ => Ok(()),
+        }
+    }
+
+    // Measure the input level of the current device for showing it in the icon
+    pub fn set_level_meter(&mut self, enabled: bool) -> Result<()> {
+        if enabled != self.level_meter.is_some() {
+            self.level_meter = enabled.then(LevelMeter::default);
+        }
+        self.apply_metering()
+    }
+
+    // Notice talking into the muted microphone with the given settings, or not
+    pub fn set_talking_while_muted(&mut self, settings: Option<TalkingWhileMuted>) -> Result<()> {
+        if settings != self.talking_detector.map(|detector| detector.settings()) {
+            self.talking_detector = settings.map(TalkingDetector::new);
+        }
+        self.apply_metering()
+    }
+
+    // Read the input level only while something needs it: the level meter,
+    // which is only shown while unmuted, or noticing talking while muted. When
+    // the backend cannot measure it nothing is read, and enabling is tried again
+    // on the next call.
+    fn apply_metering(&mut self) -> Result<()> {
+        let enabled = if self.level_meter.is_some() || self.talking_detector.is_some() {
+            match self.audio_backend.muted()? {
+                Some(true) => self.talking_detector.is_some(),
+                Some(false) => self.level_meter.is_some(),
+                None => false,
+            }
+        } else {
+            false
+        };
+        if enabled != self.metering_due.is_some() {
+            self.audio_backend.set_metering(enabled)?;
+            self.metering_due = enabled.then(Instant::now);
+            if let Some(level_meter) = &mut self.level_meter {
+                *level_meter = LevelMeter::default();
+            }
+        }
+        Ok(())
+    }
+
+    // Level shown by the level meter, if it is enabled
+    pub fn level(&self) -> Option<u8> {
+        self.level_meter
+            .filter(|_| self.metering_due.is_some())
+            .map(|level_meter| level_meter.level())
+    }
+
+    // Whether the user is talking into the muted microphone, if that is noticed
+    pub fn talking_while_muted(&self) -> bool {
+        self.talking_detector
+            .is_some_and(|detector| detector.talking())
+    }
+
+    // When the input level is due to be read by update_metering
+    pub fn metering_due(&self) -> Option<Instant> {
+        self.metering_due
+    }
+
+    // Read the input level once it is due, returning whether the level shown or
+    // talking while muted changed
+    pub fn update_metering(&mut self) -> Result<bool> {
+        self.update_metering_at(Instant::now())
+    }
+
+    fn update_metering_at(&mut self, now: Instant) -> Result<bool> {
+        match self.metering_due {
+            Some(due) if due <= now => {
+                let peak = self.audio_backend.peak()?.unwrap_or(0.0);
+                let mut changed = false;
+                if let Some(level_meter) = &mut self.level_meter {
+                    changed |= level_meter.update(peak);
+                }
+                if self.talking_detector.is_some() {
+                    let muted = self.audio_backend.muted()?.unwrap_or(false);
+                    if let Some(detector) = &mut self.talking_detector {
+                        changed |= detector.update(peak, muted, now);
+                    }
+                }
+                self.metering_due = Some(now + LEVEL_METER_INTERVAL);
+                Ok(changed)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // Register the listener to be called whenever a change occurs. The events it
+    // receives should be passed to handle_audio_event on the thread owning the
+    // controller.
+    pub fn subscribe(&mut self, listener: AudioEventListener) -> Result<()> {
+        self.audio_backend.subscribe(listener)
+    }
+
+    // Keep the backend in line with the configuration after a change was reported
+    pub fn handle_audio_event(&mut self, event: AudioEvent) -> Result<()> {
+        self.handle_audio_event_at(event, Instant::now())
+    }
+
+    fn handle_audio_event_at(&mut self, event: AudioEvent, now: Instant) -> Result<()> {
+        if event == AudioEvent::Devices {
+            self.audio_backend.refresh()?;
+            self.select_preferred_device()?;
+            self.inherit_mute_state()?;
+        }
+        if event != AudioEvent::Streams {
+            self.sync_mute_group()?;
+            self.remember_mute_state()?;
+            self.apply_metering()?;
+        }
+        match (event, self.forced_volume) {
+            (AudioEvent::Devices, _) => Ok(()),
+            (AudioEvent::Volume, Some(percent))
+                if !self.forced_volume_paused_at(now)
+                    && self
+                        .volume_percent()?
+                        .is_some_and(|volume| volume != percent) =>
+            {
+                self.corrections
+                    .retain(|correction| now.duration_since(*correction) < Duration::from_secs(1));
+                if self.corrections.len() >= MAX_CORRECTIONS_PER_SECOND {
+                    self.corrections.clear();
+                    self.forced_volume_paused_until = Some(now + FORCED_VOLUME_PAUSE);
+                    return Ok(());
+                }
+                self.corrections.push_back(now);
+                self.set_volume_percent(percent)
+            }
+            (AudioEvent::Volume | AudioEvent::Streams, _) => Ok(()),
+        }
+    }
+
+    // What the indicator shows for the current device
+    pub fn state(&self) -> Result<IndicatorState> {
+        let mut state = if self.mute_all {
+            indicator_state_of_all(self.audio_backend.as_ref())?
+        } else {
+            indicator_state(self.audio_backend.as_ref())?
+        };
+        if let Some(pattern) = &self.pinned_device {
+            if self.audio_backend.default_device()?.is_none() {
+                state.tooltip = missing_pinned_device_label(pattern);
+            }
+        }
+        Ok(state)
+    }
+
+    // Everything known about the current device and the other devices, which
+    // only counts as muted when all devices are when muting all of them
+    pub fn status(&self) -> Result<Status> {
+        let mut status = Status::of(self.audio_backend.as_ref())?;
+        status.pinned_device = self.pinned_device.clone();
+        if self.mute_all && status.device.is_some() {
+            status.muted = self.muted()?;
+        }
+        Ok(status)
+    }
+
+    pub fn capture_devices(&self) -> Result<Vec<AudioDevice>> {
+        self.audio_backend.capture_devices()
+    }
+
+    pub fn default_device(&self) -> Result<Option<AudioDevice>> {
+        self.audio_backend.default_device()
+    }
+
+    pub fn capture_streams(&self) -> Result<Vec<CaptureStream>> {
+        self.audio_backend.capture_streams()
+    }
+
+    // Which defaults set_default_device changes
+    pub fn set_default_roles(&mut self, roles: Vec<DeviceRole>) {
+        self.default_roles = roles;
+    }
+
+    pub fn set_default_device(&self, id: &str) -> Result<()> {
+        self.audio_backend
+            .set_default_device(id, &self.default_roles)
+    }
+
+    pub fn set_default_device_for(&self, id: &str, roles: &[DeviceRole]) -> Result<()> {
+        self.audio_backend.set_default_device(id, roles)
+    }
+
+    // Use the first device matching the pattern, by id or name, instead of a
+    // default one, as soon as it is present
+    pub fn set_pinned_device(&mut self, pattern: Option<String>) -> Result<()> {
+        self.pinned_device = pattern.clone();
+        self.audio_backend.set_pinned_device(pattern);
+        self.audio_backend.refresh()?;
+        self.remember_mute_state()
+    }
+
+    // Show the default device for the given role instead of the communications
+    // one
+    pub fn set_followed_role(&mut self, role: DeviceRole) -> Result<()> {
+        self.audio_backend.set_followed_role(role);
+        self.audio_backend.refresh()?;
+        self.remember_mute_state()
+    }
+
+    // Id of the default device for a role
+    pub fn role_default_device(&self, role: DeviceRole) -> Result<Option<String>> {
+        self.audio_backend.role_default_device(role)
+    }
+
+    // Whether the device is the default for all roles set_default_device changes
+    pub fn is_default_device(&self, id: &str) -> Result<bool> {
+        for role in &self.default_roles {
+            if self.audio_backend.role_default_device(*role)?.as_deref() != Some(id) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // Mute state of the current device, or when muting all devices whether all
+    // of them are muted. Devices without a mute state do not count.
+    pub fn muted(&self) -> Result<Option<bool>> {
+        if !self.mute_all {
+            return self.audio_backend.muted();
+        }
+        let mut muted = None;
+        for device in self.audio_backend.capture_devices()? {
+            match self.audio_backend.device_muted(&device.id)? {
+                Some(false) => return Ok(Some(false)),
+                Some(true) => muted = Some(true),
+                _ => {}
+            }
+        }
+        Ok(muted)
+    }
+
+    pub fn set_muted(&self, muted: bool) -> Result<()> {
+        if !self.mute_all {
+            return self.audio_backend.set_muted(muted);
+        }
+        for device in self.audio_backend.capture_devices()? {
+            self.audio_backend.set_device_muted(&device.id, muted)?;
+        }
+        Ok(())
+    }
+
+    // Partially muted devices are all muted when muting all of them
+    pub fn toggle_mute(&self) -> Result<()> {
+        if !self.mute_all {
+            return self.audio_backend.toggle_mute();
+        }
+        match self.muted()? {
+            Some(muted) => self.set_muted(!muted),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn set_volume_to_max(&self) -> Result<()> {
+        self.audio_backend.set_volume_to_max()
+    }
+
+    // Volume of the current device in percent, if known
+    pub fn volume_percent(&self) -> Result<Option<u32>> {
+        Ok(self
+            .audio_backend
+            .volume()?
+            .map(|volume| (100f32 * volume).round() as u32))
+    }
+
+    // Set the volume of the current device in percent, limited to 100%
+    pub fn set_volume_percent(&self, percent: u32) -> Result<()> {
+        self.audio_backend
+            .set_volume(percent.min(100) as f32 / 100f32)
+    }
+
+    // Change the volume of the current device by the given number of percent,
+    // which does nothing when its volume is unknown
+    pub fn change_volume_percent(&self, delta: i32) -> Result<()> {
+        match self.volume_percent()? {
+            Some(percent) => {
+                self.set_volume_percent((percent as i32).saturating_add(delta).max(0) as u32)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{audio::mock::MockBackend, indicator::LEVEL_METER_STEPS};
+    use std::sync::mpsc;
+
+    // Controller on a microphone at 30% volume and an unused headset at 50%
+    fn controller(
+        forced_volume: Option<u32>,
+    ) -> (Controller, MockBackend, mpsc::Receiver<AudioEvent>) {
+        let mock = MockBackend::default();
+        mock.add_device("mic", "Microphone");
+        mock.add_device("headset", "Headset");
+        mock.set_device_state("mic", Some(false), Some(0.3));
+        mock.set_device_state("headset", Some(false), Some(0.5));
+        mock.set_default(Some("mic"));
+        let mut controller = Controller::new(Box::new(mock.clone()));
+        controller.set_forced_volume(forced_volume);
+        let (sender, receiver) = mpsc::channel();
+        controller
+            .subscribe(Box::new(move |event| {
+                sender.send(event).ok();
+            }))
+            .unwrap();
+        controller.handle_audio_event(AudioEvent::Devices).unwrap();
+        (controller, mock, receiver)
+    }
+
+    // Handle events like the front ends do until no more events are pending
+    fn run_events(controller: &mut Controller, receiver: &mpsc::Receiver<AudioEvent>) {
+        while let Ok(event) = receiver.try_recv() {
+            controller.handle_audio_event(event).unwrap();
+        }
+    }
+
+    #[test]
+    fn force_max_volume_restores_volume_once() {
+        let (mut controller, mock, receiver) = controller(Some(100));
+        mock.emit(AudioEvent::Volume);
+        run_events(&mut controller, &receiver);
+        assert_eq!(mock.device("mic").unwrap().volume, Some(1.0));
+        assert_eq!(mock.volume_changes(), 1);
+    }
+
+    #[test]
+    fn volume_is_left_alone_without_force_max_volume() {
+        let (mut controller, mock, receiver) = controller(None);
+        mock.emit(AudioEvent::Volume);
+        run_events(&mut controller, &receiver);
+        assert_eq!(mock.device("mic").unwrap().volume, Some(0.3));
+        assert_eq!(mock.volume_changes(), 0);
+    }
+
+    #[test]
+    fn force_max_volume_follows_default_device() {
+        let (mut controller, mock, receiver) = controller(Some(100));
+        controller.set_default_device("headset").unwrap();
+        run_events(&mut controller, &receiver);
+        mock.emit(AudioEvent::Volume);
+        run_events(&mut controller, &receiver);
+        assert_eq!(
+            controller.default_device().unwrap().map(|device| device.id),
+            Some("headset".to_owned())
+        );
+        assert_eq!(mock.device("headset").unwrap().volume, Some(1.0));
+        assert_eq!(mock.device("mic").unwrap().volume, Some(0.3));
+        assert_eq!(mock.volume_changes(), 1);
+    }
+
+    #[test]
+    fn volume_is_changed_in_steps_within_bounds() {
+        let (controller, mock, _receiver) = controller(None);
+        controller.change_volume_percent(5).unwrap();
+        assert_eq!(controller.volume_percent().unwrap(), Some(35));
+        controller.change_volume_percent(-50).unwrap();
+        assert_eq!(mock.device("mic").unwrap().volume, Some(0.0));
+        controller.set_volume_percent(150).unwrap();
+        assert_eq!(mock.device("mic").unwrap().volume, Some(1.0));
+    }
+
+    #[test]
+    fn forced_volume_is_restored_exactly() {
+        let (mut controller, mock, receiver) = controller(Some(80));
+        mock.set_device_state("mic", Some(false), Some(1.0));
+        mock.emit(AudioEvent::Volume);
+        run_events(&mut controller, &receiver);
+        assert_eq!(controller.volume_percent().unwrap(), Some(80));
+        assert_eq!(mock.volume_changes(), 1);
+    }
+
+    #[test]
+    fn forced_volume_backs_off_when_changed_too_often() {
+        let (mut controller, mock, _receiver) = controller(Some(80));
+        let start = Instant::now();
+        let fight = |controller: &mut Controller, at: Instant| {
+            mock.set_device_state("mic", Some(false), Some(0.3));
+            controller
+                .handle_audio_event_at(AudioEvent::Volume, at)
+                .unwrap();
+            controller.volume_percent().unwrap()
+        };
+        for i in 0..MAX_CORRECTIONS_PER_SECOND as u64 {
+            assert_eq!(
+                fight(&mut controller, start + Duration::from_millis(100 * i)),
+                Some(80)
+            );
+        }
+        let paused_at = start + Duration::from_millis(900);
+        assert_eq!(fight(&mut controller, paused_at), Some(30));
+        assert!(controller.forced_volume_paused_at(paused_at));
+        let resumed_at = paused_at + FORCED_VOLUME_PAUSE;
+        assert_eq!(fight(&mut controller, resumed_at), Some(80));
+        assert!(!controller.forced_volume_paused_at(resumed_at));
+    }
+
+    #[test]
+    fn push_to_talk_restores_mute_after_release_delay() {
+        let (mut controller, mock, _receiver) = controller(None);
+        controller.set_hold_release_delay(Duration::from_millis(300));
+        controller.set_muted(true).unwrap();
+        controller.hold(false).unwrap();
+        assert_eq!(controller.muted().unwrap(), Some(false));
+
+        let released_at = Instant::now();
+        controller.release_hold_at(released_at).unwrap();
+        assert_eq!(
+            controller.hold_release_due(),
+            Some(released_at + Duration::from_millis(300))
+        );
+        controller
+            .finish_hold_at(released_at + Duration::from_millis(200))
+            .unwrap();
+        assert_eq!(mock.device("mic").unwrap().muted, Some(false));
+        controller
+            .finish_hold_at(released_at + Duration::from_millis(300))
+            .unwrap();
+        assert_eq!(mock.device("mic").unwrap().muted, Some(true));
+        assert_eq!(controller.hold_release_due(), None);
+    }
+
+    #[test]
+    fn pressing_again_during_release_delay_keeps_original_state() {
+        let (mut controller, _mock, _receiver) = controller(None);
+        controller.set_hold_release_delay(Duration::from_millis(300));
+        controller.hold(true).unwrap();
+        controller.release_hold().unwrap();
+        controller.hold(true).unwrap();
+        assert_eq!(controller.hold_release_due(), None);
+        assert_eq!(controller.muted().unwrap(), Some(true));
+        controller.end_hold().unwrap();
+        assert_eq!(controller.muted().unwrap(), Some(false));
+    }
+
+    #[test]
+    fn push_to_mute_without_delay_restores_on_release() {
+        let (mut controller, _mock, _receiver) = controller(None);
+        controller.hold(true).unwrap();
+        assert_eq!(controller.muted().unwrap(), Some(true));
+        controller.release_hold().unwrap();
+        assert_eq!(controller.muted().unwrap(), Some(false));
+        // Without a hold nothing is restored
+        controller.set_muted(true).unwrap();
+        controller.end_hold().unwrap();
+        assert_eq!(controller.muted().unwrap(), Some(true));
+    }
+
+    #[test]
+    fn mute_all_applies_to_every_device() {
+        let (mut controller, mock, _receiver) = controller(None);
+        controller.set_mute_all(true);
+        mock.set_device_state("headset", Some(true), Some(0.5));
+        assert_eq!(controller.muted().unwrap(), Some(false));
+        assert!(controller.state().unwrap().partially_muted);
+        assert_eq!(controller.status().unwrap().muted, Some(false));
+
+        // Partially muted devices are all muted by toggling
+        controller.toggle_mute().unwrap();
+        assert_eq!(mock.device("mic").unwrap().muted, Some(true));
+        assert_eq!(mock.device("headset").unwrap().muted, Some(true));
+        assert_eq!(controller.muted().unwrap(), Some(true));
+        assert!(!controller.state().unwrap().partially_muted);
+        controller.toggle_mute().unwrap();
+        assert_eq!(mock.device("mic").unwrap().muted, Some(false));
+        assert_eq!(mock.device("headset").unwrap().muted, Some(false));
+
+        // Devices without a mute state do not count
+        controller.set_muted(true).unwrap();
+        mock.set_device_state("headset", None, None);
+        assert_eq!(controller.muted().unwrap(), Some(true));
+    }
+
+    #[test]
+    fn new_default_device_inherits_mute_state() {
+        let (mut controller, mock, receiver) = controller(None);
+        controller.set_muted(true).unwrap();
+        run_events(&mut controller, &receiver);
+        controller.set_default_device("headset").unwrap();
+        run_events(&mut controller, &receiver);
+        assert_eq!(mock.device("headset").unwrap().muted, Some(true));
+
+        // Also when something else switches devices
+        controller.set_muted(false).unwrap();
+        run_events(&mut controller, &receiver);
+        mock.set_default(Some("mic"));
+        mock.emit(AudioEvent::Devices);
+        run_events(&mut controller, &receiver);
+        assert_eq!(mock.device("mic").unwrap().muted, Some(false));
+    }
+
+    #[test]
+    fn mute_group_is_kept_in_lockstep() {
+        let (mut controller, mock, receiver) = controller(None);
+        mock.add_device("webcam", "Webcam");
+        controller
+            .set_mute_group(vec!["Microphone".to_owned(), "headset".to_owned()])
+            .unwrap();
+        mock.set_device_state("headset", Some(true), Some(0.5));
+        mock.emit(AudioEvent::Volume);
+        run_events(&mut controller, &receiver);
+        assert_eq!(mock.device("mic").unwrap().muted, Some(true));
+        assert_eq!(mock.device("webcam").unwrap().muted, Some(false));
+        controller.set_muted(false).unwrap();
+        run_events(&mut controller, &receiver);
+        assert_eq!(mock.device("headset").unwrap().muted, Some(false));
+
+        // Setting the group keeps the current states, while a device joining
+        // later takes over the state of the group
+        controller.set_muted(true).unwrap();
+        run_events(&mut controller, &receiver);
+        controller
+            .set_mute_group(vec![
+                "mic".to_owned(),
+                "headset".to_owned(),
+                "Webcam".to_owned(),
+            ])
+            .unwrap();
+        assert_eq!(mock.device("webcam").unwrap().muted, Some(false));
+        mock.add_device("usb", "USB microphone");
+        controller
+            .set_mute_group(vec!["mic".to_owned(), "usb".to_owned()])
+            .unwrap();
+        mock.remove_device("usb");
+        mock.emit(AudioEvent::Devices);
+        run_events(&mut controller, &receiver);
+        mock.add_device("usb", "USB microphone");
+        mock.emit(AudioEvent::Devices);
+        run_events(&mut controller, &receiver);
+        assert_eq!(mock.device("usb").unwrap().muted, Some(true));
+    }
+
+    #[test]
+    fn preferred_device_is_selected_when_devices_change() {
+        let (mut controller, mock, receiver) = controller(None);
+        controller
+            .set_preferred_devices(vec!["*dock*".to_owned(), "Microphone".to_owned()])
+            .unwrap();
+        run_events(&mut controller, &receiver);
+        assert_eq!(controller.default_device().unwrap().unwrap().id, "mic");
+
+        // Docking switches to the dock and undocking falls back
+        mock.add_device("dock", "USB dock microphone");
+        mock.emit(AudioEvent::Devices);
+        run_events(&mut controller, &receiver);
+        assert_eq!(controller.default_device().unwrap().unwrap().id, "dock");
+        mock.remove_device("dock");
+        mock.set_default(Some("headset"));
+        mock.emit(AudioEvent::Devices);
+        run_events(&mut controller, &receiver);
+        assert_eq!(controller.default_device().unwrap().unwrap().id, "mic");
+
+        // Picking a device by hand sticks until the devices change again
+        controller.set_default_device("headset").unwrap();
+        run_events(&mut controller, &receiver);
+        assert_eq!(controller.default_device().unwrap().unwrap().id, "headset");
+    }
+
+    #[test]
+    fn default_device_roles_are_switched_separately() {
+        let (mut controller, mock, receiver) = controller(None);
+        controller.set_default_roles(vec![DeviceRole::Console, DeviceRole::Multimedia]);
+        controller.set_default_device("headset").unwrap();
+        run_events(&mut controller, &receiver);
+        assert_eq!(controller.default_device().unwrap().unwrap().id, "mic");
+        assert!(controller.is_default_device("headset").unwrap());
+        assert_eq!(
+            mock.role_default_device(DeviceRole::Console).unwrap(),
+            Some("headset".to_owned())
+        );
+
+        controller
+            .set_default_device_for("headset", &[DeviceRole::Communications])
+            .unwrap();
+        run_events(&mut controller, &receiver);
+        assert_eq!(controller.default_device().unwrap().unwrap().id, "headset");
+        controller.set_default_roles(DeviceRole::ALL.to_vec());
+        assert!(controller.is_default_device("headset").unwrap());
+        assert!(!controller.is_default_device("mic").unwrap());
+    }
+
+    #[test]
+    fn pinned_device_is_used_instead_of_the_default() {
+        let (mut controller, mock, receiver) = controller(None);
+        controller
+            .set_pinned_device(Some("head*".to_owned()))
+            .unwrap();
+        assert_eq!(controller.default_device().unwrap().unwrap().id, "headset");
+        controller.toggle_mute().unwrap();
+        run_events(&mut controller, &receiver);
+        assert_eq!(mock.device("headset").unwrap().muted, Some(true));
+        assert_eq!(mock.device("mic").unwrap().muted, Some(false));
+
+        // Unplugging it leaves no current device until it is back
+        mock.remove_device("headset");
+        mock.emit(AudioEvent::Devices);
+        run_events(&mut controller, &receiver);
+        assert_eq!(
+            controller.state().unwrap().tooltip,
+            "No audio capture device matching head*!"
+        );
+        let status = controller.status().unwrap();
+        assert_eq!(status.device, None);
+        assert_eq!(status.pinned_device.as_deref(), Some("head*"));
+        mock.add_device("headset", "Headset");
+        mock.emit(AudioEvent::Devices);
+        run_events(&mut controller, &receiver);
+        assert_eq!(controller.default_device().unwrap().unwrap().id, "headset");
+
+        controller.set_pinned_device(None).unwrap();
+        assert_eq!(controller.default_device().unwrap().unwrap().id, "mic");
+    }
+
+    #[test]
+    fn current_device_follows_the_chosen_role() {
+        let (mut controller, _mock, receiver) = controller(None);
+        controller
+            .set_default_device_for("headset", &[DeviceRole::Console])
+            .unwrap();
+        run_events(&mut controller, &receiver);
+        assert_eq!(controller.default_device().unwrap().unwrap().id, "mic");
+        controller.set_followed_role(DeviceRole::Console).unwrap();
+        assert_eq!(controller.default_device().unwrap().unwrap().id, "headset");
+        assert_eq!(controller.volume_percent().unwrap(), Some(50));
+        controller
+            .set_default_device_for("mic", &[DeviceRole::Console])
+            .unwrap();
+        run_events(&mut controller, &receiver);
+        assert_eq!(controller.default_device().unwrap().unwrap().id, "mic");
+    }
+
+    #[test]
+    fn level_meter_is_updated_at_its_interval() {
+        let (mut controller, mock, receiver) = controller(None);
+        assert_eq!(controller.level(), None);
+        assert_eq!(controller.metering_due(), None);
+
+        controller.set_level_meter(true).unwrap();
+        let start = controller.metering_due().unwrap();
+        mock.set_peak(1.0);
+        assert!(controller.update_metering_at(start).unwrap());
+        assert_eq!(controller.level(), Some(LEVEL_METER_STEPS));
+        mock.set_peak(0.0);
+        let due = controller.metering_due().unwrap();
+        assert_eq!(due, start + LEVEL_METER_INTERVAL);
+        assert!(!controller
+            .update_metering_at(due - Duration::from_millis(1))
+            .unwrap());
+        assert!(controller.update_metering_at(due).unwrap());
+        assert_eq!(controller.level(), Some(LEVEL_METER_STEPS - 1));
+
+        controller.set_muted(true).unwrap();
+        run_events(&mut controller, &receiver);
+        assert_eq!(controller.level(), None);
+        assert_eq!(controller.audio_backend().peak().unwrap(), None);
+        controller.set_muted(false).unwrap();
+        run_events(&mut controller, &receiver);
+        assert_eq!(controller.level(), Some(0));
+
+        controller.set_level_meter(false).unwrap();
+        assert_eq!(controller.level(), None);
+        assert_eq!(controller.audio_backend().peak().unwrap(), None);
+    }
+
+    #[test]
+    fn talking_while_muted_keeps_metering_enabled_while_muted() {
+        let (mut controller, mock, receiver) = controller(None);
+        let settings = TalkingWhileMuted::default();
+        controller.set_talking_while_muted(Some(settings)).unwrap();
+        controller.set_level_meter(true).unwrap();
+        controller.set_level_meter(false).unwrap();
+        assert_eq!(controller.level(), None);
+        assert_eq!(controller.audio_backend().peak().unwrap(), None);
+
+        controller.set_muted(true).unwrap();
+        run_events(&mut controller, &receiver);
+        assert_eq!(controller.audio_backend().peak().unwrap(), Some(0.0));
+        mock.set_peak(0.5);
+        let start = controller.metering_due().unwrap();
+        assert!(!controller.update_metering_at(start).unwrap());
+        assert!(!controller
+            .update_metering_at(start + settings.hold_time / 2)
+            .unwrap());
+        assert!(controller
+            .update_metering_at(start + settings.hold_time)
+            .unwrap());
+        assert!(controller.talking_while_muted());
+
+        controller.set_talking_while_muted(None).unwrap();
+        assert!(!controller.talking_while_muted());
+        assert_eq!(controller.metering_due(), None);
+        assert_eq!(controller.audio_backend().peak().unwrap(), None);
+    }
+}
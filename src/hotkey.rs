@@ -0,0 +1,186 @@
+// Global keyboard shortcuts, written like Ctrl+Alt+M
+
+use serde::Deserialize;
+
+// Modifier keys which have to be held together with the key
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Modifiers {
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool,
+    pub super_key: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Key {
+    // Letter or digit, letters are upper case
+    Char(char),
+    // Function key F1 to F24
+    Function(u8),
+    Space,
+    Pause,
+    ScrollLock,
+    Insert,
+    Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    // Side mouse button 4 (back) or 5 (forward)
+    Mouse(u8),
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Hotkey {
+    pub modifiers: Modifiers,
+    pub key: Key,
+}
+
+// What a hotkey does. Push to talk unmutes and push to mute mutes while the key
+// is held.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HotkeyAction {
+    ToggleMute,
+    Mute,
+    Unmute,
+    PushToTalk,
+    PushToMute,
+}
+
+impl std::str::FromStr for Hotkey {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut modifiers = Modifiers::default();
+        let mut key = None;
+        for part in text.split('+').map(str::trim) {
+            if key.is_some() {
+                return Err(format!("Key must come last in hotkey {}", text));
+            }
+            match part.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => modifiers.ctrl = true,
+                "alt" => modifiers.alt = true,
+                "shift" => modifiers.shift = true,
+                "super" | "win" | "meta" => modifiers.super_key = true,
+                "space" => key = Some(Key::Space),
+                "pause" => key = Some(Key::Pause),
+                "scrolllock" => key = Some(Key::ScrollLock),
+                "insert" => key = Some(Key::Insert),
+                "delete" => key = Some(Key::Delete),
+                "home" => key = Some(Key::Home),
+                "end" => key = Some(Key::End),
+                "pageup" => key = Some(Key::PageUp),
+                "pagedown" => key = Some(Key::PageDown),
+                "mouse4" => key = Some(Key::Mouse(4)),
+                "mouse5" => key = Some(Key::Mouse(5)),
+                name => {
+                    let mut chars = name.chars();
+                    key = Some(match (chars.next(), chars.as_str()) {
+                        (Some(c), "") if c.is_ascii_alphanumeric() => {
+                            Key::Char(c.to_ascii_uppercase())
+                        }
+                        (Some('f'), number) => match number.parse() {
+                            Ok(number @ 1..=24) => Key::Function(number),
+                            _ => return Err(format!("Unknown key {} in hotkey {}", part, text)),
+                        },
+                        _ => return Err(format!("Unknown key {} in hotkey {}", part, text)),
+                    });
+                }
+            }
+        }
+        match key {
+            Some(key) => Ok(Self { modifiers, key }),
+            _ => Err(format!("Missing key in hotkey {}", text)),
+        }
+    }
+}
+
+impl std::fmt::Display for Hotkey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (held, name) in [
+            (self.modifiers.ctrl, "Ctrl"),
+            (self.modifiers.alt, "Alt"),
+            (self.modifiers.shift, "Shift"),
+            (self.modifiers.super_key, "Super"),
+        ] {
+            if held {
+                write!(f, "{}+", name)?;
+            }
+        }
+        match self.key {
+            Key::Char(c) => write!(f, "{}", c),
+            Key::Function(number) => write!(f, "F{}", number),
+            Key::Mouse(button) => write!(f, "Mouse{}", button),
+            key => write!(f, "{:?}", key),
+        }
+    }
+}
+
+impl TryFrom<String> for Hotkey {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        text.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hotkeys_are_parsed() {
+        assert_eq!(
+            "Ctrl+Alt+M".parse(),
+            Ok(Hotkey {
+                modifiers: Modifiers {
+                    ctrl: true,
+                    alt: true,
+                    ..Modifiers::default()
+                },
+                key: Key::Char('M'),
+            })
+        );
+        assert_eq!(
+            "super + shift + f12".parse(),
+            Ok(Hotkey {
+                modifiers: Modifiers {
+                    shift: true,
+                    super_key: true,
+                    ..Modifiers::default()
+                },
+                key: Key::Function(12),
+            })
+        );
+        assert_eq!(
+            "Pause".parse(),
+            Ok(Hotkey {
+                modifiers: Modifiers::default(),
+                key: Key::Pause,
+            })
+        );
+    }
+
+    #[test]
+    fn hotkeys_are_shown_as_parsed() {
+        for text in [
+            "Ctrl+Alt+M",
+            "Shift+Super+F12",
+            "Ctrl+PageDown",
+            "7",
+            "Mouse5",
+        ] {
+            assert_eq!(text.parse::<Hotkey>().unwrap().to_string(), text);
+        }
+    }
+
+    #[test]
+    fn invalid_hotkeys_are_rejected() {
+        for text in [
+            "", "Ctrl+Alt", "Ctrl+MM", "F25", "M+Ctrl", "Ctrl+é", "Mouse1",
+        ] {
+            assert!(text.parse::<Hotkey>().is_err(), "{}", text);
+        }
+    }
+}
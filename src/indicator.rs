@@ -0,0 +1,488 @@
+// Platform independent logic behind the notification area icon and its menu
+
+use crate::audio::{AudioBackend, AudioDevice, CaptureStream, Result};
+use std::time::{Duration, Instant};
+
+pub const LABEL_NO_DEFAULT_DEVICE: &str = "No default audio capture device found!";
+const LABEL_NO_PINNED_DEVICE: &str = "No audio capture device matching";
+const LABEL_MUTED: &str = "muted";
+const LABEL_VOLUME_UNKNOWN: &str = "volume unknown";
+pub const LABEL_CAPTURE_STREAMS: &str = "Applications using the microphone";
+pub const LABEL_NO_CAPTURE_STREAMS: &str = "No applications are using the microphone";
+
+// Recording volume levels offered in the menu, in percent
+pub const VOLUME_PRESETS: [u32; 4] = [25, 50, 75, 100];
+
+// The level meter in the icon is updated at most this often, and only redrawn
+// when it moves to another of its steps
+pub const LEVEL_METER_INTERVAL: Duration = Duration::from_millis(100);
+pub const LEVEL_METER_STEPS: u8 = 8;
+// Peaks below this many decibels show as an empty meter
+const LEVEL_METER_FLOOR_DB: f32 = -48.0;
+
+// Pauses between words shorter than this keep a stretch of talking going
+const TALKING_PAUSE: Duration = Duration::from_secs(1);
+
+// Menu label for the volume being kept at the given level
+pub fn forced_volume_label(percent: u32, paused: bool) -> String {
+    if paused {
+        format!(
+            "Keeping recording volume at {}% (paused, something else keeps changing it)",
+            percent
+        )
+    } else {
+        format!("Keeping recording volume at {}%", percent)
+    }
+}
+
+// Menu label for an application capturing audio, with its process id if known
+pub fn capture_stream_label(stream: &CaptureStream) -> String {
+    match stream.process_id {
+        Some(process_id) => format!("{} ({})", stream.application, process_id),
+        _ => stream.application.clone(),
+    }
+}
+
+// What the icon shows for the current device: whether it is active (not muted),
+// whether it is in use (active and an application is capturing from it),
+// whether only some devices are muted when muting all of them and the tooltip
+// text
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndicatorState {
+    pub active: bool,
+    pub in_use: bool,
+    pub partially_muted: bool,
+    pub tooltip: String,
+}
+
+pub fn indicator_state(audio_backend: &dyn AudioBackend) -> Result<IndicatorState> {
+    Ok(match audio_backend.default_device()? {
+        Some(device) => match audio_backend.muted()? {
+            Some(muted) => {
+                let volume = if muted {
+                    None
+                } else {
+                    audio_backend
+                        .volume()?
+                        .map(|volume| format!("{:.0}%", 100f32 * volume))
+                };
+                IndicatorState {
+                    active: volume.is_some(),
+                    in_use: volume.is_some() && !audio_backend.capture_streams()?.is_empty(),
+                    partially_muted: false,
+                    tooltip: format!(
+                        "{}: {}",
+                        device.name,
+                        volume.as_deref().unwrap_or(LABEL_MUTED)
+                    ),
+                }
+            }
+            _ => IndicatorState {
+                active: false,
+                in_use: false,
+                partially_muted: false,
+                tooltip: format!("{}: {}", device.name, LABEL_VOLUME_UNKNOWN),
+            },
+        },
+        _ => IndicatorState {
+            active: false,
+            in_use: false,
+            partially_muted: false,
+            tooltip: LABEL_NO_DEFAULT_DEVICE.to_owned(),
+        },
+    })
+}
+
+// Tooltip when no device matches the pinned device pattern
+pub fn missing_pinned_device_label(pattern: &str) -> String {
+    format!("{} {}!", LABEL_NO_PINNED_DEVICE, pattern)
+}
+
+// The indicator state when muting all devices, which is only fully muted or
+// active when all devices are and partially muted otherwise. Devices without a
+// mute state do not count.
+pub fn indicator_state_of_all(audio_backend: &dyn AudioBackend) -> Result<IndicatorState> {
+    let mut state = indicator_state(audio_backend)?;
+    let (mut muted, mut count) = (0, 0);
+    for device in audio_backend.capture_devices()? {
+        match audio_backend.device_muted(&device.id)? {
+            Some(true) => (muted, count) = (muted + 1, count + 1),
+            Some(false) => count += 1,
+            _ => {}
+        }
+    }
+    if muted > 0 && muted < count && audio_backend.default_device()?.is_some() {
+        state = IndicatorState {
+            active: false,
+            in_use: false,
+            partially_muted: true,
+            tooltip: format!("{} ({} of {} devices muted)", state.tooltip, muted, count),
+        };
+    }
+    Ok(state)
+}
+
+// Level shown by the meter in the icon. Louder peaks show right away while
+// quieter ones make the meter fall one step per update, so it does not flicker.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LevelMeter {
+    level: u8,
+}
+
+impl LevelMeter {
+    // Current level as a number of steps out of LEVEL_METER_STEPS
+    pub fn level(&self) -> u8 {
+        self.level
+    }
+
+    // Take in the peak since the last update as a scalar between 0.0 and 1.0,
+    // returning whether the level changed
+    pub fn update(&mut self, peak: f32) -> bool {
+        let db = 20.0 * peak.max(f32::MIN_POSITIVE).log10();
+        let target = ((1.0 - db / LEVEL_METER_FLOOR_DB).clamp(0.0, 1.0) * LEVEL_METER_STEPS as f32)
+            .ceil() as u8;
+        let level = target.max(self.level.saturating_sub(1));
+        std::mem::replace(&mut self.level, level) != level
+    }
+}
+
+// When talking into the muted microphone is noticed: input louder than the
+// threshold in decibels for at least the hold time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TalkingWhileMuted {
+    pub threshold_db: f32,
+    pub hold_time: Duration,
+}
+
+impl Default for TalkingWhileMuted {
+    fn default() -> Self {
+        Self {
+            threshold_db: -30.0,
+            hold_time: Duration::from_secs(1),
+        }
+    }
+}
+
+// Notices talking while muted from the input level. A stretch of talking starts
+// with a loud peak and lasts until a longer pause, and counts once it has gone
+// on for the hold time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TalkingDetector {
+    settings: TalkingWhileMuted,
+    // When the current stretch started and when it was last loud
+    stretch: Option<(Instant, Instant)>,
+    talking: bool,
+}
+
+impl TalkingDetector {
+    pub fn new(settings: TalkingWhileMuted) -> Self {
+        Self {
+            settings,
+            stretch: None,
+            talking: false,
+        }
+    }
+
+    pub fn settings(&self) -> TalkingWhileMuted {
+        self.settings
+    }
+
+    // Whether the user is talking while muted right now
+    pub fn talking(&self) -> bool {
+        self.talking
+    }
+
+    // Take in the peak since the last update and whether the microphone is
+    // muted, returning whether talking while muted started or stopped
+    pub fn update(&mut self, peak: f32, muted: bool, now: Instant) -> bool {
+        let loud =
+            muted && 20.0 * peak.max(f32::MIN_POSITIVE).log10() >= self.settings.threshold_db;
+        self.stretch = match self.stretch {
+            Some((start, last)) if muted && now < last + TALKING_PAUSE => {
+                Some((start, if loud { now } else { last }))
+            }
+            _ => loud.then_some((now, now)),
+        };
+        let talking = self
+            .stretch
+            .is_some_and(|(start, last)| last - start >= self.settings.hold_time);
+        std::mem::replace(&mut self.talking, talking) != talking
+    }
+}
+
+// Change to a menu listing devices. Positions of inserted and updated items are
+// also indexes into the new list of devices.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MenuEdit {
+    Insert(usize),
+    Update(usize),
+    Remove(usize),
+}
+
+// Bring the ids of the devices in a menu in line with the given devices, returning
+// the edits to make to the menu in order. Items are kept where possible so an
+// open menu does not jump around.
+pub fn diff_menu(menu_devices: &mut Vec<String>, devices: &[AudioDevice]) -> Vec<MenuEdit> {
+    let mut edits = Vec::new();
+    for (i, device) in devices.iter().enumerate() {
+        match (i..menu_devices.len()).find(|j| menu_devices[*j] == device.id) {
+            Some(j) => {
+                // Remove the items in between, which are not at this position anymore
+                for _ in i..j {
+                    menu_devices.remove(i);
+                    edits.push(MenuEdit::Remove(i));
+                }
+                edits.push(MenuEdit::Update(i));
+            }
+            _ => {
+                menu_devices.insert(i, device.id.clone());
+                edits.push(MenuEdit::Insert(i));
+            }
+        }
+    }
+    while menu_devices.len() > devices.len() {
+        menu_devices.pop();
+        edits.push(MenuEdit::Remove(devices.len()));
+    }
+    edits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::mock::MockBackend;
+
+    fn device(id: &str) -> AudioDevice {
+        AudioDevice {
+            id: id.to_owned(),
+            name: id.to_owned(),
+        }
+    }
+
+    fn microphone() -> MockBackend {
+        let mock = MockBackend::default();
+        mock.add_device("mic", "Microphone");
+        mock.add_device("headset", "Headset");
+        mock.set_default(Some("mic"));
+        let mut backend = mock.clone();
+        backend.refresh().unwrap();
+        backend
+    }
+
+    #[test]
+    fn indicator_shows_volume_when_active() {
+        let backend = microphone();
+        backend.set_device_state("mic", Some(false), Some(0.424));
+        assert_eq!(
+            indicator_state(&backend).unwrap(),
+            IndicatorState {
+                active: true,
+                in_use: false,
+                partially_muted: false,
+                tooltip: "Microphone: 42%".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn indicator_shows_in_use_when_active() {
+        let backend = microphone();
+        backend.set_streams("mic", &[("Firefox", 1234)]);
+        assert!(indicator_state(&backend).unwrap().in_use);
+        backend.set_streams("headset", &[("Recorder", 42)]);
+        backend.set_streams("mic", &[]);
+        assert!(!indicator_state(&backend).unwrap().in_use);
+
+        // Nothing can be heard while muted
+        backend.set_streams("mic", &[("Firefox", 1234)]);
+        backend.set_device_state("mic", Some(true), Some(1.0));
+        assert!(!indicator_state(&backend).unwrap().in_use);
+    }
+
+    #[test]
+    fn indicator_shows_muted() {
+        let backend = microphone();
+        backend.set_device_state("mic", Some(true), Some(1.0));
+        assert_eq!(
+            indicator_state(&backend).unwrap(),
+            IndicatorState {
+                active: false,
+                in_use: false,
+                partially_muted: false,
+                tooltip: "Microphone: muted".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn indicator_shows_unknown_state() {
+        let backend = microphone();
+        backend.set_device_state("mic", None, None);
+        assert_eq!(
+            indicator_state(&backend).unwrap(),
+            IndicatorState {
+                active: false,
+                in_use: false,
+                partially_muted: false,
+                tooltip: "Microphone: volume unknown".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn indicator_shows_missing_default_device() {
+        let mut backend = microphone();
+        backend.remove_device("mic");
+        assert_eq!(
+            indicator_state(&backend).unwrap(),
+            IndicatorState {
+                active: false,
+                in_use: false,
+                partially_muted: false,
+                tooltip: LABEL_NO_DEFAULT_DEVICE.to_owned(),
+            }
+        );
+        backend.set_default(None);
+        backend.refresh().unwrap();
+        assert_eq!(
+            indicator_state(&backend).unwrap(),
+            IndicatorState {
+                active: false,
+                in_use: false,
+                partially_muted: false,
+                tooltip: LABEL_NO_DEFAULT_DEVICE.to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn indicator_shows_partially_muted_devices() {
+        let backend = microphone();
+        backend.set_device_state("mic", Some(false), Some(0.424));
+        assert_eq!(
+            indicator_state_of_all(&backend).unwrap(),
+            indicator_state(&backend).unwrap()
+        );
+        backend.set_device_state("headset", Some(true), Some(1.0));
+        assert_eq!(
+            indicator_state_of_all(&backend).unwrap(),
+            IndicatorState {
+                active: false,
+                in_use: false,
+                partially_muted: true,
+                tooltip: "Microphone: 42% (1 of 2 devices muted)".to_owned(),
+            }
+        );
+        backend.set_device_state("mic", Some(true), Some(0.424));
+        assert_eq!(
+            indicator_state_of_all(&backend).unwrap(),
+            indicator_state(&backend).unwrap()
+        );
+    }
+
+    #[test]
+    fn level_meter_rises_at_once_and_falls_slowly() {
+        let mut meter = LevelMeter::default();
+        assert!(!meter.update(0.0));
+        assert!(meter.update(1.0));
+        assert_eq!(meter.level(), LEVEL_METER_STEPS);
+        assert!(!meter.update(1.0));
+        assert!(meter.update(0.0));
+        assert_eq!(meter.level(), LEVEL_METER_STEPS - 1);
+
+        // -24 dB is halfway
+        let mut meter = LevelMeter::default();
+        meter.update(0.063);
+        assert_eq!(meter.level(), LEVEL_METER_STEPS / 2);
+        for _ in 0..LEVEL_METER_STEPS {
+            meter.update(0.0001);
+        }
+        assert_eq!(meter.level(), 0);
+    }
+
+    #[test]
+    fn talking_while_muted_is_noticed_after_the_hold_time() {
+        let mut detector = TalkingDetector::new(TalkingWhileMuted::default());
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        // Loud input while unmuted is fine
+        assert!(!detector.update(1.0, false, at(0)));
+        assert!(!detector.update(1.0, false, at(2000)));
+
+        // Short pauses do not end a stretch of talking
+        assert!(!detector.update(0.5, true, at(3000)));
+        assert!(!detector.update(0.0001, true, at(3500)));
+        assert!(!detector.update(0.5, true, at(3900)));
+        assert!(detector.update(0.5, true, at(4200)));
+        assert!(detector.talking());
+        assert!(!detector.update(0.0001, true, at(4500)));
+
+        // A longer pause does
+        assert!(detector.update(0.0001, true, at(5300)));
+        assert!(!detector.talking());
+
+        // As does unmuting
+        assert!(!detector.update(0.5, true, at(5400)));
+        assert!(!detector.update(0.5, true, at(6000)));
+        assert!(detector.update(0.5, true, at(6500)));
+        assert!(detector.update(0.5, false, at(6600)));
+        assert!(!detector.talking());
+
+        // Input below the threshold is not talking
+        assert!(!detector.update(0.01, true, at(7000)));
+        assert!(!detector.update(0.01, true, at(9000)));
+    }
+
+    #[test]
+    fn menu_is_filled_and_emptied() {
+        let mut menu_devices = Vec::new();
+        let devices = [device("a"), device("b")];
+        assert_eq!(
+            diff_menu(&mut menu_devices, &devices),
+            [MenuEdit::Insert(0), MenuEdit::Insert(1)]
+        );
+        assert_eq!(menu_devices, ["a", "b"]);
+        assert_eq!(
+            diff_menu(&mut menu_devices, &devices),
+            [MenuEdit::Update(0), MenuEdit::Update(1)]
+        );
+        assert_eq!(
+            diff_menu(&mut menu_devices, &[]),
+            [MenuEdit::Remove(0), MenuEdit::Remove(0)]
+        );
+        assert!(menu_devices.is_empty());
+    }
+
+    #[test]
+    fn menu_keeps_existing_items() {
+        let mut menu_devices = vec!["a".to_owned(), "b".to_owned(), "c".to_owned()];
+        assert_eq!(
+            diff_menu(&mut menu_devices, &[device("c")]),
+            [
+                MenuEdit::Remove(0),
+                MenuEdit::Remove(0),
+                MenuEdit::Update(0)
+            ]
+        );
+        assert_eq!(menu_devices, ["c"]);
+        assert_eq!(
+            diff_menu(&mut menu_devices, &[device("a"), device("c"), device("d")]),
+            [
+                MenuEdit::Insert(0),
+                MenuEdit::Update(1),
+                MenuEdit::Insert(2)
+            ]
+        );
+        assert_eq!(menu_devices, ["a", "c", "d"]);
+        assert_eq!(
+            diff_menu(&mut menu_devices, &[device("a"), device("d")]),
+            [
+                MenuEdit::Update(0),
+                MenuEdit::Remove(1),
+                MenuEdit::Update(1)
+            ]
+        );
+        assert_eq!(menu_devices, ["a", "d"]);
+    }
+}
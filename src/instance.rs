@@ -0,0 +1,455 @@
+// Single instance support: the running indicator listens on a local endpoint
+// (a named pipe on Windows, a Unix socket elsewhere) and later invocations
+// forward their actions to it instead of starting another indicator
+
+use crate::CliArgs;
+use std::io::{BufRead, BufReader, Write};
+
+const ENDPOINT_NAME: &str = "microphone-mute-indicator";
+
+// Actions which can be forwarded to the running instance, sent one per line as
+// their name followed by their value, if any. Volumes are in percent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Mute,
+    Unmute,
+    ToggleMute,
+    SetVolumeToMax,
+    SetVolume(u32),
+    VolumeUp(u32),
+    VolumeDown(u32),
+}
+
+impl Action {
+    // Actions requested on the command line, in the order they are performed
+    pub fn from_args(args: &CliArgs) -> Vec<Action> {
+        [
+            args.action_mute.then_some(Action::Mute),
+            args.action_unmute.then_some(Action::Unmute),
+            args.action_toggle_mute.then_some(Action::ToggleMute),
+            args.action_set_volume_to_max
+                .then_some(Action::SetVolumeToMax),
+            args.action_set_volume.map(Action::SetVolume),
+            args.action_volume_up.map(Action::VolumeUp),
+            args.action_volume_down.map(Action::VolumeDown),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Mute => write!(f, "mute"),
+            Action::Unmute => write!(f, "unmute"),
+            Action::ToggleMute => write!(f, "toggle-mute"),
+            Action::SetVolumeToMax => write!(f, "set-volume-to-max"),
+            Action::SetVolume(percent) => write!(f, "set-volume {}", percent),
+            Action::VolumeUp(step) => write!(f, "volume-up {}", step),
+            Action::VolumeDown(step) => write!(f, "volume-down {}", step),
+        }
+    }
+}
+
+impl std::str::FromStr for Action {
+    type Err = ();
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut words = line.split_whitespace();
+        let name = words.next().ok_or(())?;
+        let value = words
+            .next()
+            .map(str::parse::<u32>)
+            .transpose()
+            .map_err(|_| ())?;
+        match (name, value, words.next()) {
+            ("mute", None, None) => Ok(Action::Mute),
+            ("unmute", None, None) => Ok(Action::Unmute),
+            ("toggle-mute", None, None) => Ok(Action::ToggleMute),
+            ("set-volume-to-max", None, None) => Ok(Action::SetVolumeToMax),
+            ("set-volume", Some(percent), None) => Ok(Action::SetVolume(percent)),
+            ("volume-up", Some(step), None) => Ok(Action::VolumeUp(step)),
+            ("volume-down", Some(step), None) => Ok(Action::VolumeDown(step)),
+            _ => Err(()),
+        }
+    }
+}
+
+// Function called for every action received from another invocation, from the
+// thread accepting connections
+pub type ActionListener = Box<dyn Fn(Action) + Send>;
+
+fn write_actions(mut stream: impl Write, actions: &[Action]) -> std::io::Result<()> {
+    for action in actions {
+        writeln!(stream, "{}", action)?;
+    }
+    stream.flush()
+}
+
+// Pass the actions of a connection to the listener, skipping unknown ones
+fn read_actions(stream: impl std::io::Read, listener: &ActionListener) {
+    for line in BufReader::new(stream).lines().map_while(|line| line.ok()) {
+        if let Ok(action) = line.parse() {
+            listener(action);
+        }
+    }
+}
+
+// The socket in the runtime directory, which only the user can access, or
+// otherwise in a directory of our own in the shared temporary directory
+#[cfg(not(windows))]
+fn endpoint_path() -> std::io::Result<std::path::PathBuf> {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(directory) => {
+            Ok(std::path::PathBuf::from(directory).join(format!("{}.socket", ENDPOINT_NAME)))
+        }
+        _ => {
+            let directory = std::env::temp_dir().join(format!(
+                "{}-{}",
+                ENDPOINT_NAME,
+                std::env::var("USER").unwrap_or_default()
+            ));
+            private_directory(&directory)?;
+            Ok(directory.join(format!("{}.socket", ENDPOINT_NAME)))
+        }
+    }
+}
+
+// Create a directory only the user can access, or check an existing one is
+// like that and not owned by someone else
+#[cfg(not(windows))]
+fn private_directory(directory: &std::path::Path) -> std::io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+    match std::fs::DirBuilder::new().mode(0o700).create(directory) {
+        Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => {}
+        result => result?,
+    }
+    let metadata = std::fs::symlink_metadata(directory)?;
+    // The process directory is owned by the user the process runs as
+    let uid = std::fs::metadata("/proc/self")?.uid();
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.permissions().mode() & 0o077 != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{} is accessible to other users", directory.display()),
+        ));
+    }
+    Ok(())
+}
+
+// Send the actions to the running instance, returning false if there is none
+#[cfg(not(windows))]
+pub fn forward(actions: &[Action]) -> std::io::Result<bool> {
+    forward_to(&endpoint_path()?, actions)
+}
+
+#[cfg(not(windows))]
+fn forward_to(path: &std::path::Path, actions: &[Action]) -> std::io::Result<bool> {
+    use std::io::ErrorKind;
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(stream) => write_actions(stream, actions).map(|()| true),
+        Err(error)
+            if matches!(
+                error.kind(),
+                ErrorKind::NotFound | ErrorKind::ConnectionRefused
+            ) =>
+        {
+            Ok(false)
+        }
+        Err(error) => Err(error),
+    }
+}
+
+// Endpoint of the running instance, removed again when dropped
+#[cfg(not(windows))]
+pub struct Endpoint {
+    path: std::path::PathBuf,
+}
+
+#[cfg(not(windows))]
+impl Endpoint {
+    // Start accepting actions from later invocations
+    pub fn listen(listener: ActionListener) -> std::io::Result<Self> {
+        Self::listen_at(endpoint_path()?, listener)
+    }
+
+    fn listen_at(path: std::path::PathBuf, listener: ActionListener) -> std::io::Result<Self> {
+        use std::os::unix::fs::PermissionsExt;
+        // Instances starting at the same time take turns, so the second one
+        // finds the socket of the first instead of replacing it
+        let lock = lock_endpoint(&path)?;
+        // A socket left behind by an instance which did not exit cleanly is
+        // replaced, but only when nobody is listening on it
+        if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                "another instance is already running",
+            ));
+        }
+        std::fs::remove_file(&path).ok();
+        let socket = std::os::unix::net::UnixListener::bind(&path)?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        drop(lock);
+        std::thread::spawn(move || {
+            for stream in socket.incoming().map_while(|stream| stream.ok()) {
+                read_actions(stream, &listener);
+            }
+        });
+        Ok(Self { path })
+    }
+}
+
+#[cfg(not(windows))]
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        // Instances starting meanwhile wait until both files are gone
+        let lock = lock_endpoint(&self.path);
+        std::fs::remove_file(&self.path).ok();
+        std::fs::remove_file(self.path.with_extension("lock")).ok();
+        drop(lock);
+    }
+}
+
+// Take the lock file next to the socket, held while the socket is checked and
+// replaced or removed. A lock file removed by an exiting instance while waiting
+// for it is not the lock anymore, so it is taken again.
+#[cfg(not(windows))]
+fn lock_endpoint(path: &std::path::Path) -> std::io::Result<std::fs::File> {
+    use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+    let lock_path = path.with_extension("lock");
+    loop {
+        let lock = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .mode(0o600)
+            .open(&lock_path)?;
+        lock.lock()?;
+        let locked = lock.metadata()?;
+        if std::fs::metadata(&lock_path)
+            .is_ok_and(|current| (current.dev(), current.ino()) == (locked.dev(), locked.ino()))
+        {
+            return Ok(lock);
+        }
+    }
+}
+
+#[cfg(windows)]
+fn endpoint_path() -> String {
+    format!(
+        "\\\\.\\pipe\\{}-{}",
+        ENDPOINT_NAME,
+        std::env::var("USERNAME").unwrap_or_default()
+    )
+}
+
+// Send the actions to the running instance, returning false if there is none
+#[cfg(windows)]
+pub fn forward(actions: &[Action]) -> std::io::Result<bool> {
+    use windows::Win32::Foundation::ERROR_PIPE_BUSY;
+    // The instance creates a new pipe instance right after reading from the
+    // previous one, so retry for a bit when it is busy
+    for _ in 0..20 {
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .open(endpoint_path())
+        {
+            Ok(pipe) => return write_actions(pipe, actions).map(|()| true),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(error) if error.raw_os_error() == Some(ERROR_PIPE_BUSY.0 as i32) => {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            }
+            Err(error) => return Err(error),
+        }
+    }
+    Err(std::io::ErrorKind::TimedOut.into())
+}
+
+// Endpoint of the running instance
+#[cfg(windows)]
+pub struct Endpoint;
+
+// Reads from a connected pipe instance
+#[cfg(windows)]
+struct PipeReader(windows::Win32::Foundation::HANDLE);
+
+#[cfg(windows)]
+impl std::io::Read for PipeReader {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        use windows::Win32::{Foundation::ERROR_BROKEN_PIPE, Storage::FileSystem::ReadFile};
+        let mut read = 0u32;
+        match unsafe { ReadFile(self.0, Some(buffer), Some(&mut read), None) } {
+            Ok(()) => Ok(read as usize),
+            // The other end closing the pipe is the end of the actions
+            Err(error) if error.code() == ERROR_BROKEN_PIPE.to_hresult() => Ok(0),
+            Err(error) => Err(error.into()),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Endpoint {
+    // Start accepting actions from later invocations
+    pub fn listen(listener: ActionListener) -> std::io::Result<Self> {
+        use windows::{
+            core::HSTRING,
+            Win32::{
+                Foundation::{CloseHandle, ERROR_PIPE_CONNECTED, HANDLE},
+                Storage::FileSystem::{FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_INBOUND},
+                System::Pipes::*,
+            },
+        };
+        let name = HSTRING::from(endpoint_path());
+        let create_pipe = move |first: bool| -> windows::core::Result<HANDLE> {
+            let pipe = unsafe {
+                CreateNamedPipeW(
+                    &name,
+                    if first {
+                        PIPE_ACCESS_INBOUND | FILE_FLAG_FIRST_PIPE_INSTANCE
+                    } else {
+                        PIPE_ACCESS_INBOUND
+                    },
+                    PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                    PIPE_UNLIMITED_INSTANCES,
+                    0,
+                    512,
+                    0,
+                    None,
+                )
+            };
+            if pipe.is_invalid() {
+                Err(windows::core::Error::from_win32())
+            } else {
+                Ok(pipe)
+            }
+        };
+
+        // Create the first instance here so failing to do so is reported
+        let pipe_handle = create_pipe(true)?.0 as isize;
+        std::thread::spawn(move || {
+            let mut pipe = HANDLE(pipe_handle as *mut std::ffi::c_void);
+            loop {
+                let connected = match unsafe { ConnectNamedPipe(pipe, None) } {
+                    Ok(()) => true,
+                    Err(error) => error.code() == ERROR_PIPE_CONNECTED.to_hresult(),
+                };
+                if connected {
+                    read_actions(PipeReader(pipe), &listener);
+                }
+                unsafe { CloseHandle(pipe) }.ok();
+                match create_pipe(false) {
+                    Ok(next_pipe) => pipe = next_pipe,
+                    _ => break,
+                }
+            }
+        });
+        Ok(Self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argh::FromArgs;
+
+    #[test]
+    fn actions_are_taken_from_args_in_order() {
+        let args = CliArgs::from_args(
+            &["microphone-mute-indicator"],
+            &[
+                "--action-volume-down",
+                "5",
+                "--action-set-volume-to-max",
+                "--action-exit",
+                "--action-mute",
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            Action::from_args(&args),
+            [Action::Mute, Action::SetVolumeToMax, Action::VolumeDown(5)]
+        );
+    }
+
+    #[test]
+    fn actions_are_sent_as_lines() {
+        for action in [
+            Action::Mute,
+            Action::Unmute,
+            Action::ToggleMute,
+            Action::SetVolumeToMax,
+            Action::SetVolume(42),
+            Action::VolumeUp(5),
+            Action::VolumeDown(10),
+        ] {
+            assert_eq!(action.to_string().parse(), Ok(action));
+        }
+        assert_eq!("set-volume".parse::<Action>(), Err(()));
+        assert_eq!("mute 5".parse::<Action>(), Err(()));
+        assert_eq!("explode".parse::<Action>(), Err(()));
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn actions_are_forwarded_to_running_instance() {
+        use std::sync::mpsc;
+        let path = std::env::temp_dir().join(format!("{}-test.socket", ENDPOINT_NAME));
+        std::fs::remove_file(&path).ok();
+        assert!(!forward_to(&path, &[Action::Mute]).unwrap());
+
+        let (sender, receiver) = mpsc::channel();
+        let endpoint = Endpoint::listen_at(
+            path.clone(),
+            Box::new(move |action| {
+                sender.send(action).ok();
+            }),
+        )
+        .unwrap();
+        assert!(forward_to(&path, &[Action::ToggleMute, Action::SetVolumeToMax]).unwrap());
+        assert_eq!(receiver.recv().unwrap(), Action::ToggleMute);
+        assert_eq!(receiver.recv().unwrap(), Action::SetVolumeToMax);
+
+        drop(endpoint);
+        assert!(!path.exists());
+        assert!(!path.with_extension("lock").exists());
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn only_a_stale_socket_is_replaced() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("{}-stale-test.socket", ENDPOINT_NAME));
+        std::fs::remove_file(&path).ok();
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let endpoint = Endpoint::listen_at(path.clone(), Box::new(|_| {})).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let error = Endpoint::listen_at(path.clone(), Box::new(|_| {})).err();
+        assert_eq!(
+            error.map(|error| error.kind()),
+            Some(std::io::ErrorKind::AddrInUse)
+        );
+        assert!(forward_to(&path, &[Action::Mute]).unwrap());
+        drop(endpoint);
+        assert!(!path.exists());
+        assert!(!path.with_extension("lock").exists());
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn private_directory_is_not_shared() {
+        use std::os::unix::fs::PermissionsExt;
+        let directory = std::env::temp_dir().join(format!("{}-private-test", ENDPOINT_NAME));
+        std::fs::remove_dir(&directory).ok();
+        private_directory(&directory).unwrap();
+        let mode = std::fs::metadata(&directory).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        private_directory(&directory).unwrap();
+        std::fs::set_permissions(&directory, std::fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(private_directory(&directory).is_err());
+        std::fs::remove_dir(&directory).unwrap();
+    }
+}
@@ -0,0 +1,12 @@
+// Microphone mute control and status tracking behind the mute indicator, which
+// can also be embedded in other tools
+// Author: Daniël van de Giessen <daniel@dvdgiessen.nl>
+
+pub mod audio;
+mod controller;
+pub mod indicator;
+pub mod notification;
+pub mod sound;
+pub mod status;
+
+pub use controller::Controller;
@@ -0,0 +1,448 @@
+// Front end for Linux, with a notification area icon when a StatusNotifierWatcher is available
+
+mod hotkeys;
+mod notifications;
+mod sound;
+mod tray;
+
+use crate::{
+    config::{Config, ConfigWatcher},
+    hotkey::HotkeyAction,
+    instance::{self, Action, Endpoint},
+    print_status, CliArgs, IconTheme,
+};
+use hotkeys::Hotkeys;
+use microphone_mute_indicator::{
+    audio::{self, AudioEvent, DeviceRole, Result},
+    notification::{Notification, Notifier},
+    sound::CueTracker,
+    Controller,
+};
+use notifications::Notifications;
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use std::{
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+use tray::{Tray, TrayAction, TrayState};
+
+// How often the config file is checked for changes
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// Messages handled by the main loop
+enum Message {
+    Audio(AudioEvent),
+    Tray(TrayAction),
+    Action(Action),
+    // Hotkey pressed (true) or released (false)
+    Hotkey(HotkeyAction, bool),
+    // Interrupted or asked to terminate
+    Exit,
+}
+
+fn perform(controller: &Controller, action: Action) -> Result<()> {
+    match action {
+        Action::Mute => controller.set_muted(true),
+        Action::Unmute => controller.set_muted(false),
+        Action::ToggleMute => controller.toggle_mute(),
+        Action::SetVolumeToMax => controller.set_volume_to_max(),
+        Action::SetVolume(percent) => controller.set_volume_percent(percent),
+        Action::VolumeUp(step) => controller.change_volume_percent(step as i32),
+        Action::VolumeDown(step) => controller.change_volume_percent(-(step as i32)),
+    }
+}
+
+// Exiting is left to the main loop
+fn perform_tray(controller: &Controller, action: TrayAction) -> Result<()> {
+    match action {
+        TrayAction::ToggleMute => controller.toggle_mute(),
+        TrayAction::SetDefaultDevice(id) => controller.set_default_device(&id),
+        TrayAction::SetVolumeToMax => controller.set_volume_to_max(),
+        TrayAction::SetVolume(percent) => controller.set_volume_percent(percent),
+        TrayAction::Exit => Ok(()),
+    }
+}
+
+// Describe the current device for the tray icon
+fn tray_state(controller: &Controller, config: &Config) -> Result<TrayState> {
+    let indicator = controller.state()?;
+    Ok(TrayState {
+        muted: !indicator.active && !indicator.partially_muted,
+        in_use: indicator.in_use,
+        partially_muted: indicator.partially_muted,
+        level: controller.level().filter(|_| indicator.active),
+        tooltip: indicator.tooltip,
+        devices: controller.capture_devices()?,
+        default_device: controller.default_device()?.map(|device| device.id),
+        forced_volume: controller.forced_volume(),
+        forced_volume_paused: controller.forced_volume_paused(),
+        volume: controller.volume_percent()?,
+        streams: controller.capture_streams()?,
+        inverted: config.icon_theme == Some(IconTheme::Inverted),
+    })
+}
+
+// Push to talk and push to mute act while their key is held, the other hotkeys
+// act on press
+fn perform_hotkey(controller: &mut Controller, action: HotkeyAction, pressed: bool) -> Result<()> {
+    match (action, pressed) {
+        (HotkeyAction::ToggleMute, true) => controller.toggle_mute(),
+        (HotkeyAction::Mute, true) => controller.set_muted(true),
+        (HotkeyAction::Unmute, true) => controller.set_muted(false),
+        (HotkeyAction::PushToTalk, true) => controller.hold(false),
+        (HotkeyAction::PushToMute, true) => controller.hold(true),
+        (HotkeyAction::PushToTalk | HotkeyAction::PushToMute, false) => controller.release_hold(),
+        _ => Ok(()),
+    }
+}
+
+// Show the current state in the tray icon, if there is one, keeping what is
+// shown for update_tray_level
+fn update_tray(
+    tray: &Option<Tray>,
+    shown_state: &mut Option<TrayState>,
+    controller: &Controller,
+    config: &Config,
+) {
+    if let Some(tray) = tray {
+        match tray_state(controller, config) {
+            Ok(state) => {
+                *shown_state = Some(state.clone());
+                if let Err(error) = tray.update(state) {
+                    eprintln!("Cannot update the tray icon: {}", error);
+                }
+            }
+            Err(error) => eprintln!("Cannot read the microphone state: {}", error),
+        }
+    }
+}
+
+// Show a new input level with the rest of the state shown last, instead of
+// reading all of it from the audio system again for every level meter update
+fn update_tray_level(
+    tray: &Option<Tray>,
+    shown_state: &mut Option<TrayState>,
+    controller: &Controller,
+) {
+    if let (Some(tray), Some(state)) = (tray, shown_state) {
+        state.level = controller
+            .level()
+            .filter(|_| !state.muted && !state.partially_muted);
+        if let Err(error) = tray.update(state.clone()) {
+            eprintln!("Cannot update the tray icon: {}", error);
+        }
+    }
+}
+
+fn set_hotkeys(hotkeys: &Option<Hotkeys>, config: &Config) {
+    if let Some(hotkeys) = hotkeys {
+        if let Err(error) = hotkeys.set_hotkeys(&config.hotkeys()) {
+            eprintln!("{}", error);
+        }
+    }
+}
+
+fn show_notification(
+    notifications: &mut Option<Notifications>,
+    notification: Option<Notification>,
+) {
+    if let (Some(notifications), Some(notification)) = (notifications, notification) {
+        if let Err(error) = notifications.show(&notification) {
+            eprintln!("Cannot show a notification: {}", error);
+        }
+    }
+}
+
+fn set_metering(controller: &mut Controller, config: &Config) {
+    if let Err(error) = controller.set_level_meter(config.level_meter) {
+        eprintln!("Cannot measure the input level with parec: {}", error);
+    }
+}
+
+fn set_mute_group(controller: &mut Controller, config: &Config) {
+    if let Err(error) = controller.set_mute_group(config.mute_group.clone()) {
+        eprintln!(
+            "Cannot synchronize the mute state of the devices: {}",
+            error
+        );
+    }
+}
+
+fn set_preferred_devices(controller: &mut Controller, config: &Config) {
+    if let Err(error) = controller.set_preferred_devices(config.preferred_devices.clone()) {
+        eprintln!("Cannot switch to the preferred device: {}", error);
+    }
+}
+
+fn set_pinned_device(controller: &mut Controller, config: &Config) {
+    if let Err(error) = controller.set_pinned_device(config.pinned_device.clone()) {
+        eprintln!("Cannot switch to the pinned device: {}", error);
+    }
+}
+
+fn warn_about_custom_icons(config: &Config) {
+    if config.icon_active.is_some() || config.icon_muted.is_some() {
+        eprintln!("Custom icons are not supported on this platform, ignoring them.");
+    }
+}
+
+// PulseAudio and PipeWire pass silence for muted sources, so talking while
+// muted cannot be noticed
+fn warn_about_talking_while_muted(config: &Config) {
+    if config.talking_while_muted().is_some() {
+        eprintln!(
+            "Warning about talking while muted is not supported on this platform, ignoring it."
+        );
+    }
+}
+
+fn warn_about_default_device_roles(config: &Config) {
+    if config.default_roles() != DeviceRole::ALL
+        || config.followed_role() != DeviceRole::Communications
+    {
+        eprintln!("Separate default devices per role are not supported on this platform, ignoring the roles.");
+    }
+}
+
+pub fn run() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    // Parse CLI arguments
+    let args: CliArgs = argh::from_env();
+
+    // Leave the actions to the running instance if there is one, unless we
+    // need to report the state after them
+    let actions = Action::from_args(&args);
+    if !args.query && instance::forward(&actions)? {
+        return Ok(());
+    }
+
+    let mut config = Config::load(&args)?;
+    warn_about_custom_icons(&config);
+    warn_about_default_device_roles(&config);
+    warn_about_talking_while_muted(&config);
+
+    // Set up the audio backend
+    let mut controller = Controller::new(audio::default_backend()?);
+    controller.set_forced_volume(config.forced_volume());
+    controller.set_hold_release_delay(config.hold_release_delay());
+    controller.set_mute_all(config.mute_all_devices);
+    set_mute_group(&mut controller, &config);
+    set_preferred_devices(&mut controller, &config);
+    set_pinned_device(&mut controller, &config);
+
+    // Execute actions
+    for action in actions {
+        perform(&controller, action)?;
+    }
+    if args.query {
+        let status = controller.status()?;
+        print_status(&status, args.format);
+        std::process::exit(status.exit_code());
+    }
+    if args.action_exit {
+        return Ok(());
+    }
+
+    // Keep running to show the state and apply the configuration until exited
+    let (sender, receiver) = mpsc::channel();
+    controller.subscribe(Box::new({
+        let sender = sender.clone();
+        move |event| {
+            sender.send(Message::Audio(event)).ok();
+        }
+    }))?;
+    // Exit like from the tray menu to not leave a held key in effect
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    std::thread::spawn({
+        let sender = sender.clone();
+        move || {
+            if signals.forever().next().is_some() {
+                sender.send(Message::Exit).ok();
+            }
+        }
+    });
+    let _endpoint = match Endpoint::listen(Box::new({
+        let sender = sender.clone();
+        move |action| {
+            sender.send(Message::Action(action)).ok();
+        }
+    })) {
+        Ok(endpoint) => Some(endpoint),
+        // Started at the same time as another instance, which keeps running
+        Err(error) if error.kind() == std::io::ErrorKind::AddrInUse => {
+            eprintln!("Another instance is already running.");
+            return Ok(());
+        }
+        Err(error) => {
+            eprintln!("Cannot accept actions from other invocations: {}", error);
+            None
+        }
+    };
+    let hotkeys = match Hotkeys::new(Box::new({
+        let sender = sender.clone();
+        move |action, pressed| {
+            sender.send(Message::Hotkey(action, pressed)).ok();
+        }
+    })) {
+        Ok(hotkeys) => Some(hotkeys),
+        // Only a problem when hotkeys are configured, like without X11
+        Err(error) if !config.hotkeys().is_empty() => {
+            eprintln!("Cannot use hotkeys: {}", error);
+            None
+        }
+        Err(_) => None,
+    };
+    set_hotkeys(&hotkeys, &config);
+    let tray = match Tray::new(Box::new(move |action| {
+        sender.send(Message::Tray(action)).ok();
+    })) {
+        Ok(tray) => Some(tray),
+        Err(error) => {
+            eprintln!("Cannot show the tray icon: {}", error);
+            None
+        }
+    };
+    let mut notifications = match Notifications::new() {
+        Ok(notifications) => Some(notifications),
+        // Only a problem when notifications are enabled
+        Err(error) if config.notifications() != Default::default() => {
+            eprintln!("Cannot show notifications: {}", error);
+            None
+        }
+        Err(_) => None,
+    };
+    let mut notifier = Notifier::new(config.notifications());
+    let mut cue_tracker = CueTracker::default();
+    controller.apply_forced_volume()?;
+    set_metering(&mut controller, &config);
+    notifier.update(controller.status()?);
+    cue_tracker.update(controller.muted()?);
+    let mut shown_state = None;
+    if let Some(tray) = &tray {
+        let state = tray_state(&controller, &config)?;
+        shown_state = Some(state.clone());
+        tray.update(state)?;
+    }
+    let mut config_watcher = ConfigWatcher::new(args);
+    let mut config_poll_due = Instant::now() + CONFIG_POLL_INTERVAL;
+    loop {
+        // Wake up in time to finish a released push to talk, to show a
+        // throttled notification, to read the input level and to check the
+        // config file
+        let timeout = [
+            controller.hold_release_due(),
+            notifier.pending_due(),
+            controller.metering_due(),
+            Some(config_poll_due),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+        .map_or(CONFIG_POLL_INTERVAL, |due| {
+            due.saturating_duration_since(Instant::now())
+        });
+        let message = match receiver.recv_timeout(timeout) {
+            Ok(Message::Tray(TrayAction::Exit) | Message::Exit)
+            | Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Ok(message) => Some(message),
+            Err(mpsc::RecvTimeoutError::Timeout) => None,
+        };
+        // A failure, like switching to a device which was just unplugged, is
+        // reported without stopping the indicator
+        let handled = (|| -> Result<bool> {
+            match message {
+                Some(Message::Audio(event)) => {
+                    let paused = controller.forced_volume_paused();
+                    controller.handle_audio_event(event)?;
+                    if !paused && controller.forced_volume_paused() {
+                        eprintln!(
+                            "The recording volume keeps being changed, not restoring it for a while."
+                        );
+                    }
+                    show_notification(&mut notifications, notifier.update(controller.status()?));
+                    let sound = cue_tracker
+                        .update(controller.muted()?)
+                        .and_then(|cue| config.sound(cue));
+                    if let Some(sound) = sound {
+                        if let Err(error) = sound::play(&sound) {
+                            eprintln!("Cannot play a sound with paplay: {}", error);
+                        }
+                    }
+                }
+                Some(Message::Tray(action)) => perform_tray(&controller, action)?,
+                Some(Message::Action(action)) => perform(&controller, action)?,
+                Some(Message::Hotkey(action, pressed)) => {
+                    perform_hotkey(&mut controller, action, pressed)?
+                }
+                Some(Message::Exit) | None => return Ok(false),
+            }
+            Ok(true)
+        })();
+        // Timers are run after every message too, a steady stream of messages
+        // like from the level meter would otherwise keep delaying them
+        let mut level_changed = false;
+        let timed = (|| -> Result<bool> {
+            controller.finish_hold()?;
+            show_notification(&mut notifications, notifier.flush());
+            level_changed = controller.update_metering()?;
+            if Instant::now() < config_poll_due {
+                return Ok(false);
+            }
+            config_poll_due = Instant::now() + CONFIG_POLL_INTERVAL;
+            match config_watcher.poll() {
+                Some(Ok(new_config)) => {
+                    let previous = std::mem::replace(&mut config, new_config);
+                    warn_about_custom_icons(&config);
+                    warn_about_default_device_roles(&config);
+                    warn_about_talking_while_muted(&config);
+                    controller.set_forced_volume(config.forced_volume());
+                    controller.set_hold_release_delay(config.hold_release_delay());
+                    controller.set_mute_all(config.mute_all_devices);
+                    // Options which switch devices are only applied again when
+                    // they changed, so saving the file keeps a device picked by hand
+                    if config.mute_group != previous.mute_group {
+                        set_mute_group(&mut controller, &config);
+                    }
+                    if config.preferred_devices != previous.preferred_devices {
+                        set_preferred_devices(&mut controller, &config);
+                    }
+                    if config.pinned_device != previous.pinned_device {
+                        set_pinned_device(&mut controller, &config);
+                    }
+                    notifier.set_kinds(config.notifications());
+                    set_hotkeys(&hotkeys, &config);
+                    set_metering(&mut controller, &config);
+                    controller.apply_forced_volume()?;
+                    Ok(true)
+                }
+                Some(Err(error)) => {
+                    eprintln!("Keeping the current configuration: {}", error);
+                    Ok(false)
+                }
+                None => Ok(false),
+            }
+        })();
+        let mut changed = false;
+        for result in [handled, timed] {
+            match result {
+                Ok(result_changed) => changed |= result_changed,
+                Err(error) => {
+                    eprintln!("{}", error);
+                    changed = true;
+                }
+            }
+        }
+        if changed {
+            update_tray(&tray, &mut shown_state, &controller, &config);
+        } else if level_changed {
+            update_tray_level(&tray, &mut shown_state, &controller);
+        }
+    }
+
+    // Do not leave the microphone in the state of a key still being held
+    controller.end_hold()?;
+
+    Ok(())
+}
@@ -0,0 +1,305 @@
+// Global hotkeys for Linux, grabbed from the X server
+
+use crate::hotkey::{Hotkey, HotkeyAction, Key, Modifiers};
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::{Arc, Mutex},
+};
+use x11rb::{
+    connection::Connection,
+    errors::ConnectionError,
+    protocol::{
+        xkb::{self, ConnectionExt as _},
+        xproto::{self, ButtonIndex, ConnectionExt as _, EventMask, GrabMode, ModMask},
+        Event,
+    },
+    rust_connection::RustConnection,
+};
+
+// Function called when the key of a hotkey is pressed (true) or released
+// (false), from the thread receiving the X events
+pub type HotkeyListener = Box<dyn Fn(HotkeyAction, bool) + Send>;
+
+// Modifiers hotkeys consist of: Shift, Control, Mod1 (Alt) and Mod4 (Super).
+// Other ones like Lock (Caps Lock) and Mod2 (Num Lock) are ignored by grabbing
+// the key with every combination of them.
+const MODIFIERS_MASK: u16 = 0x0001 | 0x0004 | 0x0008 | 0x0040;
+const IGNORED_MODIFIERS: [u16; 4] = [0, 0x0002, 0x0010, 0x0002 | 0x0010];
+
+// Key or mouse button a hotkey is grabbed on
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum Input {
+    Key(u8),
+    Button(u8),
+}
+
+// Keysym of a key, or the X button number of a mouse button: the side buttons
+// come after the buttons used for scrolling
+fn keysym_or_button(key: Key) -> Result<u32, u8> {
+    match key {
+        Key::Char(c) => Ok(c.to_ascii_lowercase() as u32),
+        Key::Function(number) => Ok(0xffbe + number as u32 - 1),
+        Key::Space => Ok(0x0020),
+        Key::Pause => Ok(0xff13),
+        Key::ScrollLock => Ok(0xff14),
+        Key::Insert => Ok(0xff63),
+        Key::Delete => Ok(0xffff),
+        Key::Home => Ok(0xff50),
+        Key::End => Ok(0xff57),
+        Key::PageUp => Ok(0xff55),
+        Key::PageDown => Ok(0xff56),
+        Key::Mouse(button) => Err(button + 4),
+    }
+}
+
+fn modifier_mask(modifiers: Modifiers) -> u16 {
+    [
+        (modifiers.shift, ModMask::SHIFT),
+        (modifiers.ctrl, ModMask::CONTROL),
+        (modifiers.alt, ModMask::M1),
+        (modifiers.super_key, ModMask::M4),
+    ]
+    .into_iter()
+    .filter(|(held, _)| *held)
+    .fold(0, |mask, (_, modifier)| mask | modifier.bits())
+}
+
+// Grabbed key or button with its modifier mask
+type Binding = (Input, u16);
+
+pub struct Hotkeys {
+    connection: Arc<RustConnection>,
+    root: xproto::Window,
+    bindings: Arc<Mutex<HashMap<Binding, HotkeyAction>>>,
+}
+
+impl Hotkeys {
+    pub fn new(listener: HotkeyListener) -> Result<Self, Box<dyn Error>> {
+        let (connection, screen) = RustConnection::connect(None)?;
+        let root = connection.setup().roots[screen].root;
+
+        // Only report a release when the key is actually released, instead of
+        // a release and a press for every repeat while it is held
+        connection.xkb_use_extension(1, 0)?.reply()?;
+        connection
+            .xkb_per_client_flags(
+                xkb::ID::USE_CORE_KBD.into(),
+                xkb::PerClientFlag::DETECTABLE_AUTO_REPEAT,
+                xkb::PerClientFlag::DETECTABLE_AUTO_REPEAT,
+                0u32.into(),
+                0u32.into(),
+                0u32.into(),
+            )?
+            .reply()?;
+
+        let connection = Arc::new(connection);
+        let bindings = Arc::new(Mutex::new(HashMap::<Binding, HotkeyAction>::new()));
+        std::thread::spawn({
+            let connection = connection.clone();
+            let bindings = bindings.clone();
+            move || {
+                // Keys currently held down, repeated presses are ignored and
+                // releases are reported regardless of the modifiers
+                let mut pressed = HashMap::new();
+                while let Ok(event) = connection.wait_for_event() {
+                    let (input, state, press) = match event {
+                        Event::KeyPress(event) => (Input::Key(event.detail), event.state, true),
+                        Event::KeyRelease(event) => (Input::Key(event.detail), event.state, false),
+                        Event::ButtonPress(event) => {
+                            (Input::Button(event.detail), event.state, true)
+                        }
+                        Event::ButtonRelease(event) => {
+                            (Input::Button(event.detail), event.state, false)
+                        }
+                        _ => continue,
+                    };
+                    if press {
+                        let binding = (input, u16::from(state) & MODIFIERS_MASK);
+                        let action = bindings.lock().unwrap().get(&binding).copied();
+                        if let Some(action) = action {
+                            if pressed.insert(input, action).is_none() {
+                                listener(action, true);
+                            }
+                        }
+                    } else if let Some(action) = pressed.remove(&input) {
+                        listener(action, false);
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            connection,
+            root,
+            bindings,
+        })
+    }
+
+    fn grab(&self, input: Input, modifiers: u16) -> bool {
+        IGNORED_MODIFIERS.iter().all(|ignored| {
+            let modifiers = ModMask::from(modifiers | ignored);
+            match input {
+                Input::Key(keycode) => self.connection.grab_key(
+                    false,
+                    self.root,
+                    modifiers,
+                    keycode,
+                    GrabMode::ASYNC,
+                    GrabMode::ASYNC,
+                ),
+                Input::Button(button) => self.connection.grab_button(
+                    false,
+                    self.root,
+                    EventMask::BUTTON_PRESS | EventMask::BUTTON_RELEASE,
+                    GrabMode::ASYNC,
+                    GrabMode::ASYNC,
+                    x11rb::NONE,
+                    x11rb::NONE,
+                    ButtonIndex::from(button),
+                    modifiers,
+                ),
+            }
+            .is_ok_and(|cookie| cookie.check().is_ok())
+        })
+    }
+
+    fn ungrab(&self, input: Input, modifiers: u16) -> Result<(), ConnectionError> {
+        for ignored in IGNORED_MODIFIERS {
+            let modifiers = ModMask::from(modifiers | ignored);
+            match input {
+                Input::Key(keycode) => self.connection.ungrab_key(keycode, self.root, modifiers)?,
+                Input::Button(button) => self.connection.ungrab_button(
+                    ButtonIndex::from(button),
+                    self.root,
+                    modifiers,
+                )?,
+            };
+        }
+        Ok(())
+    }
+
+    // Replace the grabbed hotkeys. Hotkeys which cannot be grabbed, usually
+    // because another application already did, are reported as an error after
+    // grabbing the other ones.
+    pub fn set_hotkeys(&self, hotkeys: &[(Hotkey, HotkeyAction)]) -> Result<(), Box<dyn Error>> {
+        let mut bindings = self.bindings.lock().unwrap();
+        for (input, modifiers) in bindings.drain().map(|(binding, _)| binding) {
+            self.ungrab(input, modifiers)?;
+        }
+
+        let setup = self.connection.setup();
+        let mapping = self
+            .connection
+            .get_keyboard_mapping(setup.min_keycode, setup.max_keycode - setup.min_keycode + 1)?
+            .reply()?;
+        let mut failed = Vec::new();
+        for (hotkey, action) in hotkeys {
+            let input = match keysym_or_button(hotkey.key) {
+                Ok(keysym) => mapping
+                    .keysyms
+                    .chunks(mapping.keysyms_per_keycode.max(1) as usize)
+                    .position(|keysyms| keysyms.contains(&keysym))
+                    .map(|i| Input::Key(setup.min_keycode + i as u8)),
+                Err(button) => Some(Input::Button(button)),
+            };
+            let Some(input) = input else {
+                failed.push(hotkey.to_string());
+                continue;
+            };
+            let modifiers = modifier_mask(hotkey.modifiers);
+            if self.grab(input, modifiers) {
+                bindings.insert((input, modifiers), *action);
+            } else {
+                self.ungrab(input, modifiers)?;
+                failed.push(hotkey.to_string());
+            }
+        }
+        self.connection.flush()?;
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Cannot use hotkeys {}", failed.join(", ")).into())
+        }
+    }
+}
+
+impl Drop for Hotkeys {
+    fn drop(&mut self) {
+        self.set_hotkeys(&[]).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::mpsc, time::Duration};
+    use x11rb::{protocol::xtest::ConnectionExt as _, wrapper::ConnectionExt as _};
+
+    // Needs an X server with the XTEST extension, like Xvfb:
+    // xvfb-run cargo test -- --ignored
+    #[test]
+    #[ignore = "needs an X server"]
+    fn grabbed_hotkeys_report_presses_and_releases() {
+        let (sender, receiver) = mpsc::channel();
+        let hotkeys = Hotkeys::new(Box::new(move |action, pressed| {
+            sender.send((action, pressed)).ok();
+        }))
+        .unwrap();
+        hotkeys
+            .set_hotkeys(&[
+                ("Ctrl+F9".parse().unwrap(), HotkeyAction::ToggleMute),
+                ("F10".parse().unwrap(), HotkeyAction::PushToTalk),
+            ])
+            .unwrap();
+
+        // Press the keys through a separate connection like a user would
+        let (connection, screen) = RustConnection::connect(None).unwrap();
+        let root = connection.setup().roots[screen].root;
+        let mapping = connection
+            .get_keyboard_mapping(
+                connection.setup().min_keycode,
+                connection.setup().max_keycode - connection.setup().min_keycode + 1,
+            )
+            .unwrap()
+            .reply()
+            .unwrap();
+        let keycode = |keysym| {
+            connection.setup().min_keycode
+                + mapping
+                    .keysyms
+                    .chunks(mapping.keysyms_per_keycode as usize)
+                    .position(|keysyms| keysyms.contains(&keysym))
+                    .unwrap() as u8
+        };
+        let (control, f9, f10) = (keycode(0xffe3), keycode(0xffc6), keycode(0xffc7));
+        for (event_type, keycode) in [
+            (xproto::KEY_PRESS_EVENT, control),
+            (xproto::KEY_PRESS_EVENT, f9),
+            (xproto::KEY_RELEASE_EVENT, f9),
+            (xproto::KEY_RELEASE_EVENT, control),
+            (xproto::KEY_PRESS_EVENT, f10),
+            (xproto::KEY_RELEASE_EVENT, f10),
+        ] {
+            connection
+                .xtest_fake_input(event_type, keycode, 0, root, 0, 0, 0)
+                .unwrap();
+        }
+        connection.sync().unwrap();
+
+        let mut events = Vec::new();
+        while let Ok(event) = receiver.recv_timeout(Duration::from_secs(1)) {
+            events.push(event);
+        }
+        assert_eq!(
+            events,
+            [
+                (HotkeyAction::ToggleMute, true),
+                (HotkeyAction::ToggleMute, false),
+                (HotkeyAction::PushToTalk, true),
+                (HotkeyAction::PushToTalk, false),
+            ]
+        );
+    }
+}
@@ -0,0 +1,69 @@
+// Desktop notifications for Linux using org.freedesktop.Notifications over D-Bus
+
+use microphone_mute_indicator::notification::Notification;
+use std::collections::HashMap;
+use zbus::{blocking::Connection, proxy, zvariant::Value};
+
+const APP_NAME: &str = "Microphone Mute Indicator";
+const ICON_ACTIVE: &str = "audio-input-microphone";
+const ICON_MUTED: &str = "microphone-sensitivity-muted";
+
+// Let the notification server decide how long notifications are shown
+const EXPIRE_TIMEOUT_DEFAULT: i32 = -1;
+
+#[proxy(
+    interface = "org.freedesktop.Notifications",
+    default_service = "org.freedesktop.Notifications",
+    default_path = "/org/freedesktop/Notifications"
+)]
+trait Notifications {
+    #[allow(clippy::too_many_arguments)]
+    fn notify(
+        &self,
+        app_name: &str,
+        replaces_id: u32,
+        app_icon: &str,
+        summary: &str,
+        body: &str,
+        actions: &[&str],
+        hints: HashMap<&str, Value<'_>>,
+        expire_timeout: i32,
+    ) -> zbus::Result<u32>;
+}
+
+pub struct Notifications {
+    proxy: NotificationsProxyBlocking<'static>,
+    // Id of the last notification, which the next one replaces so they do not
+    // pile up
+    id: u32,
+}
+
+impl Notifications {
+    pub fn new() -> zbus::Result<Self> {
+        let connection = Connection::session()?;
+        Ok(Self {
+            proxy: NotificationsProxyBlocking::new(&connection)?,
+            id: 0,
+        })
+    }
+
+    pub fn show(&mut self, notification: &Notification) -> zbus::Result<()> {
+        // Transient notifications are not kept in the history of the server
+        let hints = HashMap::from([("transient", Value::from(true))]);
+        self.id = self.proxy.notify(
+            APP_NAME,
+            self.id,
+            if notification.muted {
+                ICON_MUTED
+            } else {
+                ICON_ACTIVE
+            },
+            &notification.title,
+            &notification.body,
+            &[],
+            hints,
+            EXPIRE_TIMEOUT_DEFAULT,
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+// Playing sound cues on Linux with paplay, which plays on the default PulseAudio
+// (or PipeWire) sink
+
+use crate::config::Sound;
+use microphone_mute_indicator::sound::cue_wav;
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+// Start playing the sound without waiting for it to finish
+pub fn play(sound: &Sound) -> std::io::Result<()> {
+    let mut command = Command::new("paplay");
+    command.stdout(Stdio::null()).stderr(Stdio::null());
+    let wav = match sound {
+        Sound::File(path) => {
+            command.arg(path).stdin(Stdio::null());
+            None
+        }
+        // paplay reads the file from its input when none is given
+        Sound::BuiltIn(cue) => {
+            command.stdin(Stdio::piped());
+            Some(cue_wav(*cue))
+        }
+    };
+    let mut child = command.spawn()?;
+    let stdin = child.stdin.take();
+    std::thread::spawn(move || {
+        if let (Some(mut stdin), Some(wav)) = (stdin, wav) {
+            stdin.write_all(&wav).ok();
+        }
+        child.wait().ok();
+    });
+    Ok(())
+}
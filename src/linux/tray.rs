@@ -0,0 +1,833 @@
+// Notification area icon for Linux using StatusNotifierItem and dbusmenu over D-Bus
+
+use microphone_mute_indicator::{
+    audio::{AudioDevice, CaptureStream},
+    indicator::{
+        capture_stream_label, forced_volume_label, LABEL_CAPTURE_STREAMS, LABEL_NO_CAPTURE_STREAMS,
+        LEVEL_METER_STEPS, VOLUME_PRESETS,
+    },
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use zbus::{
+    blocking::{connection, fdo::DBusProxy, Connection},
+    fdo, interface,
+    object_server::SignalEmitter,
+    proxy,
+    zvariant::{ObjectPath, Value},
+};
+
+const ITEM_PATH: &str = "/StatusNotifierItem";
+const MENU_PATH: &str = "/MenuBar";
+const WATCHER_NAME: &str = "org.kde.StatusNotifierWatcher";
+
+const ITEM_ID: &str = "microphone-mute-indicator";
+const ITEM_TITLE: &str = "Microphone Mute Indicator";
+
+// Sizes of the icon pixmaps offered to the host
+const ICON_SIZES: [i32; 4] = [16, 24, 32, 48];
+const ICON_COLOR_NORMAL: [u8; 3] = [0xff, 0xff, 0xff];
+const ICON_COLOR_INVERTED: [u8; 3] = [0x1f, 0x1f, 0x1f];
+const ICON_COLOR_MUTED: [u8; 3] = [0xe8, 0x11, 0x23];
+const ICON_COLOR_IN_USE: [u8; 3] = [0xff, 0x8c, 0x00];
+const ICON_COLOR_LEVEL: [u8; 3] = [0x3c, 0xd0, 0x4b];
+const ICON_COLOR_PARTIALLY_MUTED: [u8; 3] = [0xf2, 0xc8, 0x11];
+
+// Menu item ids, volume presets, applications using the microphone and capture
+// devices are numbered within their own ranges, leaving out items beyond them
+const IDM_ROOT: i32 = 0;
+const IDM_NO_DEVICES: i32 = 1;
+const IDM_SEPARATOR: i32 = 2;
+const IDM_SET_MAX_VOLUME: i32 = 3;
+const IDM_EXIT: i32 = 4;
+const IDM_VOLUME: i32 = 5;
+const IDM_CAPTURE_STREAMS: i32 = 6;
+const IDM_VOLUME_PRESETS: i32 = 0x100;
+const IDM_STREAMS: i32 = 0x1000;
+const IDM_DEVICES: i32 = 0x2000;
+const IDM_DEVICES_END: i32 = 0x3000;
+
+const LABEL_EXIT: &str = "E_xit";
+const LABEL_SET_MAX_VOLUME: &str = "Set recording volume to 100%";
+const LABEL_VOLUME: &str = "Recording _volume";
+const LABEL_NO_CAPTURE_DEVICES: &str = "No audio capture devices found";
+
+// Everything shown by the tray icon and its menu
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrayState {
+    pub muted: bool,
+    pub in_use: bool,
+    // Only some devices are muted, shown with a yellow slash
+    pub partially_muted: bool,
+    // Steps of the level meter to draw, if it is shown
+    pub level: Option<u8>,
+    pub tooltip: String,
+    pub devices: Vec<AudioDevice>,
+    pub default_device: Option<String>,
+    pub forced_volume: Option<u32>,
+    pub forced_volume_paused: bool,
+    pub volume: Option<u32>,
+    pub streams: Vec<CaptureStream>,
+    pub inverted: bool,
+}
+
+// Actions requested by the user through the tray icon
+#[derive(Clone, Debug, PartialEq)]
+pub enum TrayAction {
+    ToggleMute,
+    SetDefaultDevice(String),
+    SetVolumeToMax,
+    SetVolume(u32),
+    Exit,
+}
+
+// Function called when the user requests an action, from the D-Bus thread
+pub type TrayActionListener = Box<dyn Fn(TrayAction) + Send + Sync>;
+
+// Icon as width, height and ARGB32 data in network byte order
+type Pixmap = (i32, i32, Vec<u8>);
+
+// Menu item as id, properties and children
+type MenuLayout = (i32, HashMap<String, Value<'static>>, Vec<Value<'static>>);
+
+struct MenuItem {
+    id: i32,
+    properties: HashMap<String, Value<'static>>,
+    children: Vec<MenuItem>,
+}
+
+impl MenuItem {
+    fn new(id: i32, properties: HashMap<String, Value<'static>>) -> Self {
+        Self {
+            id,
+            properties,
+            children: Vec::new(),
+        }
+    }
+
+    // Find this item or one of its descendants
+    fn find(self, id: i32) -> Option<MenuItem> {
+        if self.id == id {
+            Some(self)
+        } else {
+            self.children.into_iter().find_map(|child| child.find(id))
+        }
+    }
+
+    // This item and all its descendants
+    fn flatten(self) -> Vec<(i32, HashMap<String, Value<'static>>)> {
+        let mut items = vec![(self.id, self.properties)];
+        for child in self.children {
+            items.extend(child.flatten());
+        }
+        items
+    }
+
+    // Layout of this item with its descendants up to the given depth, with -1
+    // meaning all of them
+    fn layout(self, depth: i32, property_names: &[String]) -> MenuLayout {
+        let children = if depth == 0 {
+            Vec::new()
+        } else {
+            self.children
+                .into_iter()
+                .map(|child| Value::from(child.layout(depth - 1, property_names)))
+                .collect()
+        };
+        (
+            self.id,
+            filter_properties(self.properties, property_names),
+            children,
+        )
+    }
+}
+
+// State shared with the D-Bus interfaces, the revision is bumped whenever the menu changes
+struct Shared {
+    state: TrayState,
+    revision: u32,
+}
+
+// Distance from point p to the line segment between a and b
+fn segment_distance(p: (f32, f32), a: (f32, f32), b: (f32, f32)) -> f32 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let t = (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / (dx * dx + dy * dy)).clamp(0.0, 1.0);
+    ((p.0 - a.0 - t * dx).powi(2) + (p.1 - a.1 - t * dy).powi(2)).sqrt()
+}
+
+// Color of the microphone icon at a point in the unit square, None if transparent
+fn icon_sample(
+    x: f32,
+    y: f32,
+    muted: bool,
+    partially_muted: bool,
+    in_use: bool,
+    level: Option<u8>,
+    color: [u8; 3],
+) -> Option<[u8; 3]> {
+    // Bar along the left edge rising with the input level
+    if let Some(level) = level {
+        let top = 0.9 - 0.8 * level as f32 / LEVEL_METER_STEPS as f32;
+        if (0.04..0.14).contains(&x) && (top..0.9).contains(&y) {
+            return Some(ICON_COLOR_LEVEL);
+        }
+    }
+    // Dot in the top right corner while an application is listening
+    if in_use && (x - 0.84).hypot(y - 0.16) < 0.12 {
+        return Some(ICON_COLOR_IN_USE);
+    }
+    if muted || partially_muted {
+        // Red or yellow slash with a transparent border to separate it from the
+        // microphone
+        let slash = segment_distance((x, y), (0.2, 0.14), (0.8, 0.86));
+        if slash < 0.055 {
+            return Some(if muted {
+                ICON_COLOR_MUTED
+            } else {
+                ICON_COLOR_PARTIALLY_MUTED
+            });
+        } else if slash < 0.11 {
+            return None;
+        }
+    }
+    let capsule = segment_distance((x, y), (0.5, 0.22), (0.5, 0.42)) < 0.13;
+    let holder = y >= 0.42 && ((x - 0.5).hypot(y - 0.42) - 0.25).abs() < 0.035;
+    let stem = segment_distance((x, y), (0.5, 0.67), (0.5, 0.84)) < 0.035;
+    let base = segment_distance((x, y), (0.32, 0.86), (0.68, 0.86)) < 0.035;
+    (capsule || holder || stem || base).then_some(color)
+}
+
+// Draw the microphone icon with 4x4 supersampling for anti-aliasing
+fn icon_pixmap(
+    size: i32,
+    muted: bool,
+    partially_muted: bool,
+    in_use: bool,
+    level: Option<u8>,
+    color: [u8; 3],
+) -> Pixmap {
+    const SAMPLES: u32 = 4;
+    let mut data = Vec::with_capacity((size * size * 4) as usize);
+    for py in 0..size {
+        for px in 0..size {
+            let mut count = 0;
+            let mut sum = [0u32; 3];
+            for sy in 0..SAMPLES {
+                for sx in 0..SAMPLES {
+                    let x = (px as f32 + (sx as f32 + 0.5) / SAMPLES as f32) / size as f32;
+                    let y = (py as f32 + (sy as f32 + 0.5) / SAMPLES as f32) / size as f32;
+                    if let Some(sample) =
+                        icon_sample(x, y, muted, partially_muted, in_use, level, color)
+                    {
+                        count += 1;
+                        sum.iter_mut()
+                            .zip(sample)
+                            .for_each(|(sum, value)| *sum += value as u32);
+                    }
+                }
+            }
+            data.push((count * 255 / (SAMPLES * SAMPLES)) as u8);
+            data.extend(sum.map(|sum| sum.checked_div(count).unwrap_or(0) as u8));
+        }
+    }
+    (size, size, data)
+}
+
+// The whole menu, starting from the root item
+fn menu(state: &TrayState) -> MenuItem {
+    let mut items = Vec::new();
+    if state.devices.is_empty() {
+        items.push(MenuItem::new(
+            IDM_NO_DEVICES,
+            HashMap::from([
+                ("label".to_owned(), Value::from(LABEL_NO_CAPTURE_DEVICES)),
+                ("enabled".to_owned(), Value::from(false)),
+            ]),
+        ));
+    }
+    let devices = state
+        .devices
+        .iter()
+        .take((IDM_DEVICES_END - IDM_DEVICES) as usize);
+    for (i, device) in devices.enumerate() {
+        let device_is_default = state.default_device.as_ref() == Some(&device.id);
+        items.push(MenuItem::new(
+            IDM_DEVICES + i as i32,
+            HashMap::from([
+                (
+                    "label".to_owned(),
+                    Value::from(device.name.replace('_', "__")),
+                ),
+                ("toggle-type".to_owned(), Value::from("radio")),
+                (
+                    "toggle-state".to_owned(),
+                    Value::from(device_is_default as i32),
+                ),
+            ]),
+        ));
+    }
+    items.push(MenuItem::new(
+        IDM_SEPARATOR,
+        HashMap::from([("type".to_owned(), Value::from("separator"))]),
+    ));
+    items.push(MenuItem {
+        id: IDM_CAPTURE_STREAMS,
+        properties: HashMap::from([
+            (
+                "label".to_owned(),
+                Value::from(if state.streams.is_empty() {
+                    LABEL_NO_CAPTURE_STREAMS
+                } else {
+                    LABEL_CAPTURE_STREAMS
+                }),
+            ),
+            ("children-display".to_owned(), Value::from("submenu")),
+            ("enabled".to_owned(), Value::from(!state.streams.is_empty())),
+        ]),
+        children: state
+            .streams
+            .iter()
+            .take((IDM_DEVICES - IDM_STREAMS) as usize)
+            .enumerate()
+            .map(|(i, stream)| {
+                MenuItem::new(
+                    IDM_STREAMS + i as i32,
+                    HashMap::from([
+                        (
+                            "label".to_owned(),
+                            Value::from(capture_stream_label(stream).replace('_', "__")),
+                        ),
+                        ("enabled".to_owned(), Value::from(false)),
+                    ]),
+                )
+            })
+            .collect(),
+    });
+    items.push(MenuItem {
+        id: IDM_VOLUME,
+        properties: HashMap::from([
+            ("label".to_owned(), Value::from(LABEL_VOLUME)),
+            ("children-display".to_owned(), Value::from("submenu")),
+            (
+                "enabled".to_owned(),
+                Value::from(state.forced_volume.is_none()),
+            ),
+        ]),
+        children: VOLUME_PRESETS
+            .iter()
+            .enumerate()
+            .map(|(i, preset)| {
+                MenuItem::new(
+                    IDM_VOLUME_PRESETS + i as i32,
+                    HashMap::from([
+                        ("label".to_owned(), Value::from(format!("{}%", preset))),
+                        ("toggle-type".to_owned(), Value::from("radio")),
+                        (
+                            "toggle-state".to_owned(),
+                            Value::from((state.volume == Some(*preset)) as i32),
+                        ),
+                    ]),
+                )
+            })
+            .collect(),
+    });
+    items.push(MenuItem::new(
+        IDM_SET_MAX_VOLUME,
+        if let Some(percent) = state.forced_volume {
+            HashMap::from([
+                (
+                    "label".to_owned(),
+                    Value::from(forced_volume_label(percent, state.forced_volume_paused)),
+                ),
+                ("enabled".to_owned(), Value::from(false)),
+                ("toggle-type".to_owned(), Value::from("checkmark")),
+                ("toggle-state".to_owned(), Value::from(1)),
+            ])
+        } else {
+            HashMap::from([("label".to_owned(), Value::from(LABEL_SET_MAX_VOLUME))])
+        },
+    ));
+    items.push(MenuItem::new(
+        IDM_EXIT,
+        HashMap::from([("label".to_owned(), Value::from(LABEL_EXIT))]),
+    ));
+    MenuItem {
+        id: IDM_ROOT,
+        properties: HashMap::from([("children-display".to_owned(), Value::from("submenu"))]),
+        children: items,
+    }
+}
+
+// Action of clicking the menu item with the given id, if it has one
+fn clicked_action(state: &TrayState, id: i32) -> Option<TrayAction> {
+    match id {
+        IDM_SET_MAX_VOLUME => Some(TrayAction::SetVolumeToMax),
+        IDM_EXIT => Some(TrayAction::Exit),
+        id if (IDM_VOLUME_PRESETS..IDM_STREAMS).contains(&id) => VOLUME_PRESETS
+            .get((id - IDM_VOLUME_PRESETS) as usize)
+            .map(|preset| TrayAction::SetVolume(*preset)),
+        id if (IDM_DEVICES..IDM_DEVICES_END).contains(&id) => state
+            .devices
+            .get((id - IDM_DEVICES) as usize)
+            .map(|device| TrayAction::SetDefaultDevice(device.id.clone())),
+        _ => None,
+    }
+}
+
+// Only keep the requested properties, an empty list means all of them
+fn filter_properties(
+    mut properties: HashMap<String, Value<'static>>,
+    names: &[String],
+) -> HashMap<String, Value<'static>> {
+    if !names.is_empty() {
+        properties.retain(|name, _| names.contains(name));
+    }
+    properties
+}
+
+struct StatusNotifierItem {
+    shared: Arc<Mutex<Shared>>,
+    listener: Arc<TrayActionListener>,
+}
+
+#[interface(name = "org.kde.StatusNotifierItem")]
+impl StatusNotifierItem {
+    fn activate(&self, _x: i32, _y: i32) {
+        (self.listener)(TrayAction::ToggleMute);
+    }
+
+    fn secondary_activate(&self, _x: i32, _y: i32) {}
+
+    fn context_menu(&self, _x: i32, _y: i32) {}
+
+    fn scroll(&self, _delta: i32, _orientation: &str) {}
+
+    #[zbus(property)]
+    fn category(&self) -> &str {
+        "Hardware"
+    }
+
+    #[zbus(property)]
+    fn id(&self) -> &str {
+        ITEM_ID
+    }
+
+    #[zbus(property)]
+    fn title(&self) -> &str {
+        ITEM_TITLE
+    }
+
+    #[zbus(property)]
+    fn status(&self) -> &str {
+        "Active"
+    }
+
+    #[zbus(property)]
+    fn window_id(&self) -> i32 {
+        0
+    }
+
+    #[zbus(property)]
+    fn icon_name(&self) -> &str {
+        ""
+    }
+
+    #[zbus(property)]
+    fn icon_pixmap(&self) -> Vec<Pixmap> {
+        let (muted, partially_muted, in_use, level, inverted) = {
+            let state = &self.shared.lock().unwrap().state;
+            (
+                state.muted,
+                state.partially_muted,
+                state.in_use,
+                state.level,
+                state.inverted,
+            )
+        };
+        let color = if inverted {
+            ICON_COLOR_INVERTED
+        } else {
+            ICON_COLOR_NORMAL
+        };
+        ICON_SIZES
+            .iter()
+            .map(|size| icon_pixmap(*size, muted, partially_muted, in_use, level, color))
+            .collect()
+    }
+
+    #[zbus(property)]
+    fn overlay_icon_name(&self) -> &str {
+        ""
+    }
+
+    #[zbus(property)]
+    fn overlay_icon_pixmap(&self) -> Vec<Pixmap> {
+        Vec::new()
+    }
+
+    #[zbus(property)]
+    fn attention_icon_name(&self) -> &str {
+        ""
+    }
+
+    #[zbus(property)]
+    fn attention_icon_pixmap(&self) -> Vec<Pixmap> {
+        Vec::new()
+    }
+
+    #[zbus(property)]
+    fn attention_movie_name(&self) -> &str {
+        ""
+    }
+
+    #[zbus(property)]
+    fn tool_tip(&self) -> (String, Vec<Pixmap>, String, String) {
+        let tooltip = self.shared.lock().unwrap().state.tooltip.clone();
+        (String::new(), Vec::new(), tooltip, String::new())
+    }
+
+    #[zbus(property)]
+    fn item_is_menu(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn menu(&self) -> ObjectPath<'_> {
+        ObjectPath::from_static_str_unchecked(MENU_PATH)
+    }
+
+    #[zbus(signal)]
+    async fn new_icon(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn new_tool_tip(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+}
+
+struct DBusMenu {
+    shared: Arc<Mutex<Shared>>,
+    listener: Arc<TrayActionListener>,
+}
+
+#[interface(name = "com.canonical.dbusmenu")]
+impl DBusMenu {
+    fn get_layout(
+        &self,
+        parent_id: i32,
+        recursion_depth: i32,
+        property_names: Vec<String>,
+    ) -> fdo::Result<(u32, MenuLayout)> {
+        let shared = self.shared.lock().unwrap();
+        let layout = menu(&shared.state)
+            .find(parent_id)
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("Unknown menu item {}", parent_id)))?
+            .layout(recursion_depth, &property_names);
+        Ok((shared.revision, layout))
+    }
+
+    fn get_group_properties(
+        &self,
+        ids: Vec<i32>,
+        property_names: Vec<String>,
+    ) -> Vec<(i32, HashMap<String, Value<'static>>)> {
+        menu(&self.shared.lock().unwrap().state)
+            .flatten()
+            .into_iter()
+            .filter(|(id, _)| ids.is_empty() || ids.contains(id))
+            .map(|(id, properties)| (id, filter_properties(properties, &property_names)))
+            .collect()
+    }
+
+    fn get_property(&self, id: i32, name: &str) -> fdo::Result<Value<'static>> {
+        menu(&self.shared.lock().unwrap().state)
+            .find(id)
+            .and_then(|mut item| item.properties.remove(name))
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("Unknown property {} of {}", name, id)))
+    }
+
+    fn event(&self, id: i32, event_id: &str, _data: Value<'_>, _timestamp: u32) {
+        if event_id != "clicked" {
+            return;
+        }
+        let action = clicked_action(&self.shared.lock().unwrap().state, id);
+        if let Some(action) = action {
+            (self.listener)(action);
+        }
+    }
+
+    fn event_group(&self, events: Vec<(i32, String, Value<'_>, u32)>) -> Vec<i32> {
+        for (id, event_id, data, timestamp) in events {
+            self.event(id, &event_id, data, timestamp);
+        }
+        Vec::new()
+    }
+
+    fn about_to_show(&self, _id: i32) -> bool {
+        false
+    }
+
+    fn about_to_show_group(&self, _ids: Vec<i32>) -> (Vec<i32>, Vec<i32>) {
+        (Vec::new(), Vec::new())
+    }
+
+    #[zbus(property)]
+    fn version(&self) -> u32 {
+        3
+    }
+
+    #[zbus(property)]
+    fn text_direction(&self) -> &str {
+        "ltr"
+    }
+
+    #[zbus(property)]
+    fn status(&self) -> &str {
+        "normal"
+    }
+
+    #[zbus(property)]
+    fn icon_theme_path(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[zbus(signal)]
+    async fn layout_updated(
+        emitter: &SignalEmitter<'_>,
+        revision: u32,
+        parent: i32,
+    ) -> zbus::Result<()>;
+}
+
+#[proxy(
+    interface = "org.kde.StatusNotifierWatcher",
+    default_service = "org.kde.StatusNotifierWatcher",
+    default_path = "/StatusNotifierWatcher"
+)]
+trait StatusNotifierWatcher {
+    fn register_status_notifier_item(&self, service: &str) -> zbus::Result<()>;
+}
+
+// Register our item with the watcher, which shows it in the panel
+fn register(connection: &Connection) -> zbus::Result<()> {
+    let service = connection
+        .unique_name()
+        .map(|name| name.to_string())
+        .unwrap_or_default();
+    StatusNotifierWatcherProxyBlocking::new(connection)?.register_status_notifier_item(&service)
+}
+
+pub struct Tray {
+    connection: Connection,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Tray {
+    pub fn new(listener: TrayActionListener) -> zbus::Result<Self> {
+        let shared = Arc::new(Mutex::new(Shared {
+            state: TrayState::default(),
+            revision: 0,
+        }));
+        let listener = Arc::new(listener);
+        let connection = connection::Builder::session()?
+            .serve_at(
+                ITEM_PATH,
+                StatusNotifierItem {
+                    shared: shared.clone(),
+                    listener: listener.clone(),
+                },
+            )?
+            .serve_at(
+                MENU_PATH,
+                DBusMenu {
+                    shared: shared.clone(),
+                    listener,
+                },
+            )?
+            .build()?;
+
+        // Register now and again whenever the watcher is (re)started
+        if let Err(error) = register(&connection) {
+            eprintln!(
+                "Cannot register the tray icon, waiting for a StatusNotifierWatcher: {}",
+                error
+            );
+        }
+        let owner_changes = DBusProxy::new(&connection)?
+            .receive_name_owner_changed_with_args(&[(0, WATCHER_NAME)])?;
+        std::thread::spawn({
+            let connection = connection.clone();
+            move || {
+                for signal in owner_changes {
+                    if signal.args().is_ok_and(|args| args.new_owner.is_some()) {
+                        register(&connection).ok();
+                    }
+                }
+            }
+        });
+
+        Ok(Self { connection, shared })
+    }
+
+    // Show the given state, notifying the host of what changed
+    pub fn update(&self, state: TrayState) -> zbus::Result<()> {
+        let (icon_changed, tooltip_changed, revision) = {
+            let mut shared = self.shared.lock().unwrap();
+            let icon_changed = shared.state.muted != state.muted
+                || shared.state.partially_muted != state.partially_muted
+                || shared.state.in_use != state.in_use
+                || shared.state.level != state.level
+                || shared.state.inverted != state.inverted;
+            let tooltip_changed = shared.state.tooltip != state.tooltip;
+            let menu_changed = shared.state.devices != state.devices
+                || shared.state.default_device != state.default_device
+                || shared.state.forced_volume != state.forced_volume
+                || shared.state.forced_volume_paused != state.forced_volume_paused
+                || shared.state.volume != state.volume
+                || shared.state.streams != state.streams;
+            if menu_changed {
+                shared.revision += 1;
+            }
+            shared.state = state;
+            (
+                icon_changed,
+                tooltip_changed,
+                menu_changed.then_some(shared.revision),
+            )
+        };
+        let item = SignalEmitter::new(self.connection.inner(), ITEM_PATH)?;
+        if icon_changed {
+            zbus::block_on(StatusNotifierItem::new_icon(&item))?;
+        }
+        if tooltip_changed {
+            zbus::block_on(StatusNotifierItem::new_tool_tip(&item))?;
+        }
+        if let Some(revision) = revision {
+            let menu = SignalEmitter::new(self.connection.inner(), MENU_PATH)?;
+            zbus::block_on(DBusMenu::layout_updated(&menu, revision, IDM_ROOT))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(id: &str, name: &str) -> AudioDevice {
+        AudioDevice {
+            id: id.to_owned(),
+            name: name.to_owned(),
+        }
+    }
+
+    fn stream(application: &str, process_id: u32) -> CaptureStream {
+        CaptureStream {
+            application: application.to_owned(),
+            process_id: Some(process_id),
+        }
+    }
+
+    fn state() -> TrayState {
+        TrayState {
+            devices: vec![device("a", "Mic_A"), device("b", "Headset")],
+            default_device: Some("b".to_owned()),
+            volume: Some(50),
+            streams: vec![stream("Firefox", 42)],
+            ..Default::default()
+        }
+    }
+
+    fn property(item: &MenuItem, name: &str) -> Option<Value<'static>> {
+        item.properties.get(name).cloned()
+    }
+
+    #[test]
+    fn menu_lists_devices_streams_and_volumes() {
+        let menu = menu(&state());
+        let ids: Vec<i32> = menu.children.iter().map(|item| item.id).collect();
+        assert_eq!(
+            ids,
+            [
+                IDM_DEVICES,
+                IDM_DEVICES + 1,
+                IDM_SEPARATOR,
+                IDM_CAPTURE_STREAMS,
+                IDM_VOLUME,
+                IDM_SET_MAX_VOLUME,
+                IDM_EXIT
+            ]
+        );
+        let (first, second) = (&menu.children[0], &menu.children[1]);
+        assert_eq!(property(first, "label"), Some(Value::from("Mic__A")));
+        assert_eq!(property(first, "toggle-state"), Some(Value::from(0)));
+        assert_eq!(property(second, "toggle-state"), Some(Value::from(1)));
+
+        let streams = &menu.children[3];
+        assert_eq!(property(streams, "enabled"), Some(Value::from(true)));
+        assert_eq!(streams.children.len(), 1);
+        assert_eq!(streams.children[0].id, IDM_STREAMS);
+        assert_eq!(
+            property(&streams.children[0], "label"),
+            Some(Value::from("Firefox (42)"))
+        );
+
+        let volumes = &menu.children[4];
+        let checked: Vec<i32> = volumes
+            .children
+            .iter()
+            .filter(|item| property(item, "toggle-state") == Some(Value::from(1)))
+            .map(|item| item.id)
+            .collect();
+        assert_eq!(checked, [IDM_VOLUME_PRESETS + 1]);
+    }
+
+    #[test]
+    fn menu_without_devices_says_so() {
+        let menu = menu(&TrayState::default());
+        let first = &menu.children[0];
+        assert_eq!(first.id, IDM_NO_DEVICES);
+        assert_eq!(property(first, "enabled"), Some(Value::from(false)));
+        let streams = menu.find(IDM_CAPTURE_STREAMS).unwrap();
+        assert_eq!(property(&streams, "enabled"), Some(Value::from(false)));
+    }
+
+    #[test]
+    fn many_streams_do_not_reuse_device_ids() {
+        let mut state = state();
+        state.streams = (0..5000).map(|i| stream("Recorder", i)).collect();
+        let items = menu(&state).flatten();
+        let mut ids: Vec<i32> = items.iter().map(|(id, _)| *id).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), items.len());
+        assert!(
+            ids.iter()
+                .filter(|id| (IDM_STREAMS..IDM_DEVICES).contains(id))
+                .count()
+                <= (IDM_DEVICES - IDM_STREAMS) as usize
+        );
+    }
+
+    #[test]
+    fn clicks_are_mapped_to_actions() {
+        let state = state();
+        assert_eq!(
+            clicked_action(&state, IDM_DEVICES + 1),
+            Some(TrayAction::SetDefaultDevice("b".to_owned()))
+        );
+        assert_eq!(clicked_action(&state, IDM_DEVICES + 2), None);
+        assert_eq!(
+            clicked_action(&state, IDM_VOLUME_PRESETS + 2),
+            Some(TrayAction::SetVolume(VOLUME_PRESETS[2]))
+        );
+        assert_eq!(
+            clicked_action(&state, IDM_SET_MAX_VOLUME),
+            Some(TrayAction::SetVolumeToMax)
+        );
+        assert_eq!(clicked_action(&state, IDM_EXIT), Some(TrayAction::Exit));
+        assert_eq!(clicked_action(&state, IDM_STREAMS), None);
+        assert_eq!(clicked_action(&state, IDM_CAPTURE_STREAMS), None);
+        assert_eq!(clicked_action(&state, IDM_DEVICES_END), None);
+    }
+}
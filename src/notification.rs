@@ -0,0 +1,323 @@
+// Notifications about changes to the microphone, made by us or by something
+// else like a headset button or a video call application
+
+use crate::status::Status;
+use std::time::{Duration, Instant};
+
+// Notifications of the same kind are shown at most this often, a newer one
+// replaces the one waiting to be shown so dragging a volume slider only shows
+// where it ended up
+pub const NOTIFICATION_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NotificationKind {
+    Mute,
+    Device,
+    Volume,
+    TalkingWhileMuted,
+}
+
+impl NotificationKind {
+    fn index(self) -> usize {
+        match self {
+            NotificationKind::Mute => 0,
+            NotificationKind::Device => 1,
+            NotificationKind::Volume => 2,
+            NotificationKind::TalkingWhileMuted => 3,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Notification {
+    pub kind: NotificationKind,
+    // Whether the microphone is muted now, for picking an icon
+    pub muted: bool,
+    pub title: String,
+    pub body: String,
+}
+
+// Which changes to notify about
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NotificationKinds {
+    pub mute: bool,
+    pub device: bool,
+    pub volume: bool,
+    pub talking_while_muted: bool,
+}
+
+impl NotificationKinds {
+    fn contains(&self, kind: NotificationKind) -> bool {
+        match kind {
+            NotificationKind::Mute => self.mute,
+            NotificationKind::Device => self.device,
+            NotificationKind::Volume => self.volume,
+            NotificationKind::TalkingWhileMuted => self.talking_while_muted,
+        }
+    }
+}
+
+// Turns changes in the status into notifications. The front ends pass the
+// status after every change and show what comes out, and call flush once
+// pending_due is reached to show throttled notifications.
+pub struct Notifier {
+    kinds: NotificationKinds,
+    last: Option<Status>,
+    shown_at: [Option<Instant>; 4],
+    // Throttled notification of each kind with when it is due to be shown
+    pending: [Option<(Notification, Instant)>; 4],
+}
+
+impl Notifier {
+    pub fn new(kinds: NotificationKinds) -> Self {
+        Self {
+            kinds,
+            last: None,
+            shown_at: [None; 4],
+            pending: Default::default(),
+        }
+    }
+
+    pub fn set_kinds(&mut self, kinds: NotificationKinds) {
+        self.kinds = kinds;
+        for pending in &mut self.pending {
+            if pending
+                .as_ref()
+                .is_some_and(|(notification, _)| !kinds.contains(notification.kind))
+            {
+                *pending = None;
+            }
+        }
+    }
+
+    pub fn update(&mut self, status: Status) -> Option<Notification> {
+        self.update_at(status, Instant::now())
+    }
+
+    fn update_at(&mut self, status: Status, now: Instant) -> Option<Notification> {
+        let notification = self
+            .last
+            .replace(status)
+            .and_then(|last| describe_change(&last, self.last.as_ref()?))
+            .filter(|notification| self.kinds.contains(notification.kind))?;
+        self.throttle(notification, now)
+    }
+
+    // Warning about talking into the muted microphone, passed when the front
+    // end notices it started
+    pub fn talking_while_muted(&mut self, status: &Status) -> Option<Notification> {
+        self.talking_while_muted_at(status, Instant::now())
+    }
+
+    fn talking_while_muted_at(&mut self, status: &Status, now: Instant) -> Option<Notification> {
+        let notification = Notification {
+            kind: NotificationKind::TalkingWhileMuted,
+            muted: true,
+            title: "You are talking while muted".to_owned(),
+            body: status.device.as_ref()?.name.clone(),
+        };
+        self.kinds
+            .contains(notification.kind)
+            .then(|| self.throttle(notification, now))?
+    }
+
+    // Show the notification now, or later when one of the same kind was shown
+    // less than NOTIFICATION_INTERVAL ago
+    fn throttle(&mut self, notification: Notification, now: Instant) -> Option<Notification> {
+        let index = notification.kind.index();
+        match self.shown_at[index] {
+            Some(at) if now < at + NOTIFICATION_INTERVAL => {
+                self.pending[index] = Some((notification, at + NOTIFICATION_INTERVAL));
+                None
+            }
+            _ => {
+                self.shown_at[index] = Some(now);
+                self.pending[index] = None;
+                Some(notification)
+            }
+        }
+    }
+
+    // When the first throttled notification is due to be shown
+    pub fn pending_due(&self) -> Option<Instant> {
+        self.pending.iter().flatten().map(|(_, due)| *due).min()
+    }
+
+    pub fn flush(&mut self) -> Option<Notification> {
+        self.flush_at(Instant::now())
+    }
+
+    fn flush_at(&mut self, now: Instant) -> Option<Notification> {
+        match self.pending_due() {
+            Some(due) if due <= now => {
+                let (notification, _) = self
+                    .pending
+                    .iter_mut()
+                    .find(|pending| pending.as_ref().is_some_and(|(_, at)| *at == due))?
+                    .take()?;
+                self.shown_at[notification.kind.index()] = Some(now);
+                Some(notification)
+            }
+            _ => None,
+        }
+    }
+}
+
+// The most important change between two states: switching devices, then
+// muting or unmuting, then the volume
+fn describe_change(last: &Status, status: &Status) -> Option<Notification> {
+    let device = status.device.as_ref()?;
+    let muted = status.muted.unwrap_or(false);
+    let mute_label = if muted { "muted" } else { "unmuted" };
+    if last.device.as_ref().map(|device| &device.id) != Some(&device.id) {
+        Some(Notification {
+            kind: NotificationKind::Device,
+            muted,
+            title: format!("Switched to {}", device.name),
+            body: format!("The microphone is {}.", mute_label),
+        })
+    } else if status.muted.is_some() && last.muted != status.muted {
+        Some(Notification {
+            kind: NotificationKind::Mute,
+            muted,
+            title: format!("Microphone {}", mute_label),
+            body: device.name.clone(),
+        })
+    } else {
+        status
+            .volume
+            .filter(|_| last.volume != status.volume)
+            .map(|volume| Notification {
+                kind: NotificationKind::Volume,
+                muted,
+                title: format!("Recording volume {}%", volume),
+                body: device.name.clone(),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::AudioDevice;
+
+    fn status(id: &str, muted: bool, volume: u32) -> Status {
+        let device = AudioDevice {
+            id: id.to_owned(),
+            name: format!("Microphone {}", id),
+        };
+        Status {
+            device: Some(device.clone()),
+            pinned_device: None,
+            muted: Some(muted),
+            volume: Some(volume),
+            streams: Vec::new(),
+            devices: vec![device],
+        }
+    }
+
+    fn all_kinds() -> NotificationKinds {
+        NotificationKinds {
+            mute: true,
+            device: true,
+            volume: true,
+            talking_while_muted: true,
+        }
+    }
+
+    #[test]
+    fn changes_are_notified() {
+        let mut notifier = Notifier::new(all_kinds());
+        let now = Instant::now();
+        assert_eq!(notifier.update_at(status("a", false, 50), now), None);
+        let notification = notifier.update_at(status("a", true, 50), now).unwrap();
+        assert_eq!(notification.kind, NotificationKind::Mute);
+        assert_eq!(notification.title, "Microphone muted");
+        assert_eq!(notification.body, "Microphone a");
+        let notification = notifier.update_at(status("b", true, 80), now).unwrap();
+        assert_eq!(notification.kind, NotificationKind::Device);
+        assert_eq!(notification.title, "Switched to Microphone b");
+        let notification = notifier.update_at(status("b", true, 60), now).unwrap();
+        assert_eq!(notification.title, "Recording volume 60%");
+        assert_eq!(notifier.update_at(status("b", true, 60), now), None);
+    }
+
+    #[test]
+    fn disabled_kinds_are_not_notified() {
+        let mut notifier = Notifier::new(NotificationKinds {
+            mute: true,
+            ..NotificationKinds::default()
+        });
+        let now = Instant::now();
+        notifier.update_at(status("a", false, 50), now);
+        assert_eq!(notifier.update_at(status("a", false, 60), now), None);
+        assert_eq!(notifier.update_at(status("b", false, 60), now), None);
+        assert!(notifier.update_at(status("b", true, 60), now).is_some());
+    }
+
+    #[test]
+    fn talking_while_muted_is_notified_when_enabled() {
+        let now = Instant::now();
+        let notification = Notifier::new(all_kinds())
+            .talking_while_muted_at(&status("a", true, 50), now)
+            .unwrap();
+        assert_eq!(notification.kind, NotificationKind::TalkingWhileMuted);
+        assert!(notification.muted);
+        assert_eq!(notification.body, "Microphone a");
+        let mut notifier = Notifier::new(NotificationKinds::default());
+        assert_eq!(
+            notifier.talking_while_muted_at(&status("a", true, 50), now),
+            None
+        );
+    }
+
+    #[test]
+    fn volume_changes_are_throttled_to_the_last_one() {
+        let mut notifier = Notifier::new(all_kinds());
+        let start = Instant::now();
+        notifier.update_at(status("a", false, 50), start);
+        assert!(notifier.update_at(status("a", false, 51), start).is_some());
+        for (i, volume) in (52..60).enumerate() {
+            let at = start + Duration::from_millis(10 * i as u64);
+            assert_eq!(notifier.update_at(status("a", false, volume), at), None);
+        }
+        let due = notifier.pending_due().unwrap();
+        assert_eq!(due, start + NOTIFICATION_INTERVAL);
+        assert_eq!(notifier.flush_at(due - Duration::from_millis(1)), None);
+        let notification = notifier.flush_at(due).unwrap();
+        assert_eq!(notification.title, "Recording volume 59%");
+        assert_eq!(notifier.pending_due(), None);
+
+        // Other kinds are not held back by the volume changes
+        notifier.update_at(status("a", false, 40), due);
+        assert!(notifier.update_at(status("a", true, 40), due).is_some());
+    }
+
+    #[test]
+    fn throttled_notifications_of_other_kinds_are_kept() {
+        let mut notifier = Notifier::new(all_kinds());
+        let start = Instant::now();
+        notifier.update_at(status("a", false, 50), start);
+        assert!(notifier.update_at(status("a", true, 50), start).is_some());
+        let unmuted_at = start + Duration::from_millis(500);
+        assert_eq!(notifier.update_at(status("a", false, 50), unmuted_at), None);
+        let volume = notifier
+            .update_at(status("a", false, 60), unmuted_at)
+            .unwrap();
+        assert_eq!(volume.kind, NotificationKind::Volume);
+        assert_eq!(notifier.update_at(status("a", false, 70), unmuted_at), None);
+
+        let due = start + NOTIFICATION_INTERVAL;
+        assert_eq!(notifier.pending_due(), Some(due));
+        let notification = notifier.flush_at(due).unwrap();
+        assert_eq!(notification.kind, NotificationKind::Mute);
+        assert!(!notification.muted);
+        let due = unmuted_at + NOTIFICATION_INTERVAL;
+        assert_eq!(notifier.pending_due(), Some(due));
+        assert_eq!(
+            notifier.flush_at(due).unwrap().title,
+            "Recording volume 70%"
+        );
+        assert_eq!(notifier.pending_due(), None);
+    }
+}
@@ -0,0 +1,116 @@
+// Short sounds played when the microphone is muted or unmuted, so the change
+// can be heard without looking at the icon
+
+use std::f32::consts::PI;
+
+const SAMPLE_RATE: u32 = 22050;
+const TONE_MILLISECONDS: u32 = 70;
+const TONE_VOLUME: f32 = 0.3;
+// Fading each tone in and out avoids clicks
+const FADE_MILLISECONDS: u32 = 10;
+
+// Tones of the built-in cues: falling when muting, rising when unmuting
+const TONES_MUTED: [f32; 2] = [880.0, 587.33];
+const TONES_UNMUTED: [f32; 2] = [587.33, 880.0];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cue {
+    Muted,
+    Unmuted,
+}
+
+// Decides when to play a cue: whenever the mute state flips, but not for the
+// state found when starting
+#[derive(Default)]
+pub struct CueTracker {
+    last: Option<bool>,
+}
+
+impl CueTracker {
+    pub const fn new() -> Self {
+        Self { last: None }
+    }
+
+    pub fn update(&mut self, muted: Option<bool>) -> Option<Cue> {
+        let muted = muted?;
+        match self.last.replace(muted) {
+            Some(last) if last != muted => Some(if muted { Cue::Muted } else { Cue::Unmuted }),
+            _ => None,
+        }
+    }
+}
+
+// Built-in sound of a cue as a WAV file
+pub fn cue_wav(cue: Cue) -> Vec<u8> {
+    let tones = match cue {
+        Cue::Muted => TONES_MUTED,
+        Cue::Unmuted => TONES_UNMUTED,
+    };
+    let tone_samples = SAMPLE_RATE * TONE_MILLISECONDS / 1000;
+    let fade_samples = SAMPLE_RATE * FADE_MILLISECONDS / 1000;
+    let samples = tones
+        .iter()
+        .flat_map(|frequency| {
+            (0..tone_samples).map(move |i| {
+                let fade = (i.min(tone_samples - 1 - i) as f32 / fade_samples as f32).min(1.0);
+                let phase = 2.0 * PI * frequency * i as f32 / SAMPLE_RATE as f32;
+                (phase.sin() * fade * TONE_VOLUME * i16::MAX as f32) as i16
+            })
+        })
+        .collect::<Vec<_>>();
+    wav(&samples)
+}
+
+// WAV file of 16 bit mono samples
+fn wav(samples: &[i16]) -> Vec<u8> {
+    let data_len = 2 * samples.len() as u32;
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    // PCM with one channel
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(2 * SAMPLE_RATE).to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+    wav
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cues_play_when_mute_flips() {
+        let mut tracker = CueTracker::default();
+        assert_eq!(tracker.update(Some(false)), None);
+        assert_eq!(tracker.update(Some(false)), None);
+        assert_eq!(tracker.update(Some(true)), Some(Cue::Muted));
+        assert_eq!(tracker.update(None), None);
+        assert_eq!(tracker.update(Some(false)), Some(Cue::Unmuted));
+    }
+
+    #[test]
+    fn built_in_cues_are_valid_wav_files() {
+        for cue in [Cue::Muted, Cue::Unmuted] {
+            let wav = cue_wav(cue);
+            assert_eq!(&wav[0..4], b"RIFF");
+            assert_eq!(&wav[8..16], b"WAVEfmt ");
+            let riff_len = u32::from_le_bytes(wav[4..8].try_into().unwrap());
+            let data_len = u32::from_le_bytes(wav[40..44].try_into().unwrap());
+            assert_eq!(riff_len as usize, wav.len() - 8);
+            assert_eq!(data_len as usize, wav.len() - 44);
+            // Two tones of 16 bit samples
+            assert_eq!(data_len, 2 * 2 * (SAMPLE_RATE * TONE_MILLISECONDS / 1000));
+        }
+        assert_ne!(cue_wav(Cue::Muted), cue_wav(Cue::Unmuted));
+    }
+}
@@ -0,0 +1,181 @@
+// Snapshot of the microphone state for reporting it to scripts
+
+use crate::{
+    audio::{AudioBackend, AudioDevice, CaptureStream, Result},
+    indicator::{capture_stream_label, missing_pinned_device_label},
+};
+use serde::Serialize;
+
+// Exit codes reflecting the mute state of the current device
+pub const EXIT_CODE_UNMUTED: i32 = 0;
+pub const EXIT_CODE_MUTED: i32 = 1;
+pub const EXIT_CODE_UNKNOWN: i32 = 2;
+
+const LABEL_NONE: &str = "none";
+const LABEL_UNKNOWN: &str = "unknown";
+
+// The current device with its mute state, volume in percent and the applications
+// capturing from it, and all devices. When pinned to a device, the pattern it
+// is chosen by.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Status {
+    pub device: Option<AudioDevice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pinned_device: Option<String>,
+    pub muted: Option<bool>,
+    pub volume: Option<u32>,
+    pub streams: Vec<CaptureStream>,
+    pub devices: Vec<AudioDevice>,
+}
+
+impl Status {
+    pub fn of(audio_backend: &dyn AudioBackend) -> Result<Self> {
+        let device = audio_backend.default_device()?;
+        let (muted, volume, streams) = if device.is_some() {
+            (
+                audio_backend.muted()?,
+                audio_backend
+                    .volume()?
+                    .map(|volume| (100f32 * volume).round() as u32),
+                audio_backend.capture_streams()?,
+            )
+        } else {
+            (None, None, Vec::new())
+        };
+        Ok(Self {
+            device,
+            pinned_device: None,
+            muted,
+            volume,
+            streams,
+            devices: audio_backend.capture_devices()?,
+        })
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self.muted {
+            Some(false) => EXIT_CODE_UNMUTED,
+            Some(true) => EXIT_CODE_MUTED,
+            _ => EXIT_CODE_UNKNOWN,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Status is always valid JSON")
+    }
+
+    // Human readable form, with the current device marked in the device list
+    pub fn to_text(&self) -> String {
+        let mut text = format!(
+            "Device: {}\nMuted: {}\nVolume: {}\nUsed by:{}\n",
+            match (&self.device, &self.pinned_device) {
+                (Some(device), _) => format!("{} ({})", device.name, device.id),
+                (_, Some(pattern)) =>
+                    format!("{} ({})", LABEL_NONE, missing_pinned_device_label(pattern)),
+                _ => LABEL_NONE.to_owned(),
+            },
+            match self.muted {
+                Some(true) => "yes",
+                Some(false) => "no",
+                _ => LABEL_UNKNOWN,
+            },
+            self.volume
+                .map(|volume| format!("{}%", volume))
+                .as_deref()
+                .unwrap_or(LABEL_UNKNOWN),
+            if self.streams.is_empty() {
+                format!(" {}", LABEL_NONE)
+            } else {
+                String::new()
+            },
+        );
+        for stream in &self.streams {
+            text += &format!("  {}\n", capture_stream_label(stream));
+        }
+        text += "Devices:\n";
+        for device in &self.devices {
+            let marker = if self.device.as_ref() == Some(device) {
+                '*'
+            } else {
+                ' '
+            };
+            text += &format!("{} {} ({})\n", marker, device.name, device.id);
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::mock::MockBackend;
+
+    fn microphone(muted: bool) -> MockBackend {
+        let mut backend = MockBackend::default();
+        backend.add_device("mic", "Microphone");
+        backend.add_device("headset", "Headset");
+        backend.set_device_state("mic", Some(muted), Some(0.424));
+        backend.set_default(Some("mic"));
+        backend.set_streams("mic", &[("Firefox", 1234), ("Recorder", 42)]);
+        backend.refresh().unwrap();
+        backend
+    }
+
+    #[test]
+    fn status_is_reported_as_text() {
+        let status = Status::of(&microphone(true)).unwrap();
+        assert_eq!(status.exit_code(), EXIT_CODE_MUTED);
+        assert_eq!(
+            status.to_text(),
+            "Device: Microphone (mic)\nMuted: yes\nVolume: 42%\n\
+             Used by:\n  Firefox (1234)\n  Recorder (42)\nDevices:\n\
+             * Microphone (mic)\n  Headset (headset)\n"
+        );
+    }
+
+    #[test]
+    fn status_is_reported_as_json() {
+        let status = Status::of(&microphone(false)).unwrap();
+        assert_eq!(status.exit_code(), EXIT_CODE_UNMUTED);
+        let json: serde_json::Value = serde_json::from_str(&status.to_json()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "device": {"id": "mic", "name": "Microphone"},
+                "muted": false,
+                "volume": 42,
+                "streams": [
+                    {"application": "Firefox", "process_id": 1234},
+                    {"application": "Recorder", "process_id": 42},
+                ],
+                "devices": [
+                    {"id": "mic", "name": "Microphone"},
+                    {"id": "headset", "name": "Headset"},
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn status_without_default_device_is_unknown() {
+        let mut backend = microphone(false);
+        backend.set_default(None);
+        backend.refresh().unwrap();
+        let status = Status::of(&backend).unwrap();
+        assert_eq!(status.exit_code(), EXIT_CODE_UNKNOWN);
+        assert!(status
+            .to_text()
+            .starts_with("Device: none\nMuted: unknown\nVolume: unknown\nUsed by: none\n"));
+    }
+
+    #[test]
+    fn missing_pinned_device_is_reported() {
+        let mut status = Status::of(&MockBackend::default()).unwrap();
+        status.pinned_device = Some("*USB*".to_owned());
+        assert!(status
+            .to_text()
+            .starts_with("Device: none (No audio capture device matching *USB*!)\n"));
+        let json: serde_json::Value = serde_json::from_str(&status.to_json()).unwrap();
+        assert_eq!(json["pinned_device"], "*USB*");
+    }
+}
@@ -0,0 +1,2116 @@
+// Notification area icon for Windows
+
+use crate::{
+    config::{Config, ConfigWatcher, Sound},
+    hotkey::{Hotkey, HotkeyAction, Key},
+    instance::{self, Action, Endpoint},
+    print_status, CliArgs, IconTheme,
+};
+use microphone_mute_indicator::{
+    audio::{self, AudioDevice, AudioEvent, CaptureStream, DeviceRole},
+    indicator::{
+        capture_stream_label, diff_menu, forced_volume_label, IndicatorState, MenuEdit,
+        LABEL_CAPTURE_STREAMS, LABEL_NO_CAPTURE_STREAMS, LABEL_NO_DEFAULT_DEVICE,
+        LEVEL_METER_INTERVAL, LEVEL_METER_STEPS, VOLUME_PRESETS,
+    },
+    notification::{Notification, Notifier},
+    sound::{cue_wav, Cue, CueTracker},
+    Controller,
+};
+use std::{cell::RefCell, ffi::c_void, path::Path, sync::mpsc};
+use windows::{
+    core::*,
+    Win32::{
+        Foundation::*,
+        Graphics::{Gdi, Gdi::*},
+        Media::Audio::{PlaySoundW, SND_ASYNC, SND_FILENAME, SND_MEMORY, SND_NODEFAULT},
+        System::{
+            Com::*, Console::*, LibraryLoader::GetModuleHandleW, Registry::*,
+            SystemInformation::GetSystemDirectoryW, Threading::*,
+        },
+        UI::{Input::KeyboardAndMouse::*, Shell::*, WindowsAndMessaging::*},
+    },
+};
+
+const WM_APP_NOTIFYICON: u32 = WM_APP + 1;
+const WM_APP_CALLBACK_ENDPOINT: u32 = WM_APP + 2;
+const WM_APP_CALLBACK_VOLUME: u32 = WM_APP + 3;
+const WM_APP_ACTION: u32 = WM_APP + 4;
+const WM_APP_MOUSE_HOTKEY: u32 = WM_APP + 5;
+const WM_APP_CALLBACK_STREAMS: u32 = WM_APP + 6;
+
+// Timer for checking the config file for changes
+const IDT_CONFIG_POLL: usize = 1;
+const CONFIG_POLL_INTERVAL_MS: u32 = 2000;
+
+// Timer for checking whether the push to talk or push to mute key is still
+// held, since there is no message when a hotkey is released
+const IDT_HELD_HOTKEY: usize = 2;
+const HELD_HOTKEY_INTERVAL_MS: u32 = 20;
+
+// Timer for restoring the mute state after the release delay of a held hotkey
+const IDT_HOLD_RELEASE: usize = 3;
+
+// Timer for showing a notification held back by throttling
+const IDT_NOTIFICATION: usize = 4;
+
+// Timer for checking which applications are capturing audio, since WASAPI only
+// reports that to callbacks registered on every session
+const IDT_CAPTURE_STREAMS: usize = 5;
+const CAPTURE_STREAMS_POLL_INTERVAL_MS: u32 = 2000;
+
+// Timer for reading the input level while the level meter or the talking while
+// muted warning needs it
+const IDT_METERING: usize = 6;
+
+// Timer for flashing the icon while talking while muted
+const IDT_TALKING_FLASH: usize = 7;
+const TALKING_FLASH_INTERVAL_MS: u32 = 500;
+
+const IDM_EXIT: u16 = 0u16.wrapping_sub(1);
+const IDM_OPEN_SOUNDCONTROLPANEL: u16 = 0u16.wrapping_sub(2);
+const IDM_SET_MAX_VOLUME: u16 = 0u16.wrapping_sub(3);
+const IDM_SEPARATOR: u16 = 0u16.wrapping_sub(4);
+const IDM_NO_ENDPOINTS: u16 = 0u16.wrapping_sub(5);
+const IDM_CAPTURE_STREAMS: u16 = 0u16.wrapping_sub(6);
+const IDM_CAPTURE_STREAM: u16 = 0u16.wrapping_sub(7);
+const IDM_VOLUME_PRESETS: u16 = 0u16.wrapping_sub(16);
+// Ids of the devices in the submenus setting the default device for a role
+const IDM_COMMUNICATIONS_DEVICES: u16 = 0x4000;
+const IDM_CONSOLE_DEVICES: u16 = 0x5000;
+const IDM_ROLE_DEVICES_END: u16 = 0x6000;
+
+const LABEL_EXIT: PCWSTR = w!("E&xit\0");
+const LABEL_OPEN_SOUNDCONTROLPANEL: PCWSTR = w!("Open Sound Control Panel\0");
+const LABEL_SET_MAX_VOLUME: PCWSTR = w!("Set recording volume to 100%\0");
+const LABEL_VOLUME: PCWSTR = w!("Recording &volume\0");
+const LABEL_NO_CAPTURE_DEVICES: PCWSTR = w!("No audio capture devices found\0");
+const LABEL_COMMUNICATIONS_DEVICE: PCWSTR = w!("Default device for &calls\0");
+const LABEL_CONSOLE_DEVICE: PCWSTR = w!("Default device for &other applications\0");
+
+const ICON_FILE_DEFAULT: PCWSTR = w!("%SystemRoot%\\System32\\SndVolSSO.dll");
+const ICON_INDEX_ACTIVE_DEFAULT: u32 = 141u32.wrapping_neg();
+const ICON_INDEX_MUTED_DEFAULT: u32 = 140u32.wrapping_neg();
+const ICON_COLOR_IN_USE: u32 = 0xffff8c00;
+const ICON_COLOR_LEVEL: u32 = 0xff3cd04b;
+const ICON_COLOR_TALKING: u32 = 0x00e81123;
+const ICON_COLOR_PARTIALLY_MUTED: u32 = 0xfff2c811;
+
+// Message received when the taskbar is (re)created
+thread_local!(static WM_TASKBAR_CREATED: RefCell<Option<u32>> = const { RefCell::new(None) });
+
+// Controller for interacting with the audio system
+thread_local!(static CONTROLLER: RefCell<Option<Controller>> = const { RefCell::new(None) });
+
+// Icon configuration: path buffer, path, icon index and theme override
+type IconConfig = (Option<Vec<u16>>, PCWSTR, u32, Option<IconTheme>);
+thread_local!(static CONFIG_ICON_THEME: RefCell<IconTheme> = const { RefCell::new(IconTheme::Auto) });
+thread_local!(static CONFIG_ICON_ACTIVE: RefCell<IconConfig> = const { RefCell::new((None, ICON_FILE_DEFAULT, ICON_INDEX_ACTIVE_DEFAULT, None)) });
+thread_local!(static CONFIG_ICON_MUTED: RefCell<IconConfig> = const { RefCell::new((None, ICON_FILE_DEFAULT, ICON_INDEX_MUTED_DEFAULT, None)) });
+
+// Actions forwarded by other invocations, waiting for WM_APP_ACTION
+thread_local!(static ACTIONS: RefCell<Option<mpsc::Receiver<Action>>> = const { RefCell::new(None) });
+
+// Configuration applied last, to tell which options changed on a reload
+thread_local!(static CONFIG: RefCell<Option<Config>> = const { RefCell::new(None) });
+
+// Watcher for applying changes to the config file while running
+thread_local!(static CONFIG_WATCHER: RefCell<Option<ConfigWatcher>> = const { RefCell::new(None) });
+
+// Configured hotkeys, registered with their index as id
+thread_local!(static HOTKEYS: RefCell<Vec<(Hotkey, HotkeyAction)>> = const { RefCell::new(Vec::new()) });
+
+// Id and virtual key of the push to talk or push to mute hotkey while it is held
+thread_local!(static HELD_HOTKEY: RefCell<Option<(usize, u32)>> = const { RefCell::new(None) });
+
+// Hook receiving mouse input for mouse hotkeys, with the window to pass them to
+thread_local!(static MOUSE_HOOK: RefCell<Option<(HHOOK, HWND)>> = const { RefCell::new(None) });
+
+// Turns changes of the microphone into notifications
+thread_local!(static NOTIFIER: RefCell<Option<Notifier>> = const { RefCell::new(None) });
+
+// Sounds played when the microphone is muted and unmuted
+thread_local!(static SOUNDS: RefCell<(Option<Sound>, Option<Sound>)> = const { RefCell::new((None, None)) });
+thread_local!(static CUE_TRACKER: RefCell<CueTracker> = const { RefCell::new(CueTracker::new()) });
+
+// Built-in sounds, kept around since they are played asynchronously from memory
+thread_local!(static BUILT_IN_SOUNDS: RefCell<Vec<(Cue, Vec<u8>)>> = const { RefCell::new(Vec::new()) });
+
+// Applications last seen capturing from the current device
+thread_local!(static CAPTURE_STREAMS: RefCell<Vec<CaptureStream>> = const { RefCell::new(Vec::new()) });
+
+// Icons for active, in use and muted states
+thread_local!(static ICON_ACTIVE: RefCell<Option<HICON>> = const { RefCell::new(None) });
+thread_local!(static ICON_ACTIVE_INVERTED: RefCell<Option<HICON>> = const { RefCell::new(None) });
+thread_local!(static ICON_IN_USE: RefCell<Option<HICON>> = const { RefCell::new(None) });
+thread_local!(static ICON_IN_USE_INVERTED: RefCell<Option<HICON>> = const { RefCell::new(None) });
+thread_local!(static ICON_MUTED: RefCell<Option<HICON>> = const { RefCell::new(None) });
+thread_local!(static ICON_MUTED_INVERTED: RefCell<Option<HICON>> = const { RefCell::new(None) });
+thread_local!(static ICON_PARTIALLY_MUTED: RefCell<Option<HICON>> = const { RefCell::new(None) });
+thread_local!(static ICON_PARTIALLY_MUTED_INVERTED: RefCell<Option<HICON>> = const { RefCell::new(None) });
+
+// Icon with the level meter drawn into it, created again whenever the level changes
+thread_local!(static ICON_LEVEL: RefCell<Option<HICON>> = const { RefCell::new(None) });
+
+// Red muted icon alternated with the normal one while talking while muted, and
+// whether it is shown right now when flashing
+thread_local!(static ICON_TALKING: RefCell<Option<HICON>> = const { RefCell::new(None) });
+thread_local!(static TALKING_FLASH: RefCell<Option<bool>> = const { RefCell::new(None) });
+thread_local!(static CONFIG_FLASH_TALKING_WHILE_MUTED: RefCell<bool> = const { RefCell::new(false) });
+
+// Notify icon data registered to show in the notification tray
+thread_local!(static NOTIFY_ICON_DATA: RefCell<Option<NOTIFYICONDATAW>> = const { RefCell::new(None) });
+
+// Context menu shown when right-clicking the notify icon
+thread_local!(static MENU: RefCell<Option<HMENU>> = const { RefCell::new(None) });
+thread_local!(static MENU_VOLUME: RefCell<Option<HMENU>> = const { RefCell::new(None) });
+thread_local!(static MENU_CAPTURE_STREAMS: RefCell<Option<HMENU>> = const { RefCell::new(None) });
+thread_local!(static MENU_AUDIO_ENDPOINTS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) });
+thread_local!(static MENU_DEFAULT_ROLES: RefCell<Vec<(DeviceRole, HMENU)>> = const { RefCell::new(Vec::new()) });
+thread_local!(static MENU_DEFAULT_ROLE_DEVICES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) });
+
+// Icon configuration for a custom icon, or the default icon when none is given
+fn icon_config(path: Option<&Path>, default_index: u32, theme: Option<IconTheme>) -> IconConfig {
+    match path {
+        Some(path) => {
+            let path_buffer = path
+                .as_os_str()
+                .encode_wide()
+                .chain(std::iter::once(0))
+                .collect::<Vec<_>>();
+            let path = PCWSTR(path_buffer.as_ptr());
+            (Some(path_buffer), path, 0, theme)
+        }
+        _ => (None, ICON_FILE_DEFAULT, default_index, None),
+    }
+}
+
+// Set the configuration options, replacing any previously set ones
+fn apply_config(config: &Config) {
+    // Options which switch devices are only applied again when they changed,
+    // so saving the config file keeps a device picked by hand
+    let previous = CONFIG.with(|global| global.replace(Some(config.clone())));
+    let changed = |same: fn(&Config, &Config) -> bool| {
+        previous
+            .as_ref()
+            .is_none_or(|previous| !same(previous, config))
+    };
+    CONFIG_ICON_THEME.with(|global| global.replace(config.icon_theme.unwrap_or(IconTheme::Auto)));
+    let custom_icon_theme = config.icon_theme.or(Some(IconTheme::Normal));
+    CONFIG_ICON_ACTIVE.with(|global| {
+        global.replace(icon_config(
+            config.icon_active.as_deref(),
+            ICON_INDEX_ACTIVE_DEFAULT,
+            custom_icon_theme,
+        ))
+    });
+    CONFIG_ICON_MUTED.with(|global| {
+        global.replace(icon_config(
+            config.icon_muted.as_deref(),
+            ICON_INDEX_MUTED_DEFAULT,
+            custom_icon_theme,
+        ))
+    });
+    CONTROLLER.with(|global| {
+        if let Some(controller) = global.borrow_mut().as_mut() {
+            controller.set_forced_volume(config.forced_volume());
+            controller.set_hold_release_delay(config.hold_release_delay());
+            controller.set_mute_all(config.mute_all_devices);
+            controller.set_default_roles(config.default_roles());
+            if changed(|a, b| a.followed_role() == b.followed_role()) {
+                if let Err(error) = controller.set_followed_role(config.followed_role()) {
+                    eprintln!("Cannot follow the default device for the role: {}", error);
+                }
+            }
+            if changed(|a, b| a.pinned_device == b.pinned_device) {
+                if let Err(error) = controller.set_pinned_device(config.pinned_device.clone()) {
+                    eprintln!("Cannot switch to the pinned device: {}", error);
+                }
+            }
+            if changed(|a, b| a.mute_group == b.mute_group) {
+                if let Err(error) = controller.set_mute_group(config.mute_group.clone()) {
+                    eprintln!(
+                        "Cannot synchronize the mute state of the devices: {}",
+                        error
+                    );
+                }
+            }
+            if changed(|a, b| a.preferred_devices == b.preferred_devices) {
+                if let Err(error) =
+                    controller.set_preferred_devices(config.preferred_devices.clone())
+                {
+                    eprintln!("Cannot switch to the preferred device: {}", error);
+                }
+            }
+            if let Err(error) = controller
+                .set_level_meter(config.level_meter)
+                .and_then(|_| controller.set_talking_while_muted(config.talking_while_muted()))
+            {
+                eprintln!("Cannot measure the input level: {}", error);
+            }
+        }
+    });
+    NOTIFIER.with(|global| {
+        if let Some(notifier) = global.borrow_mut().as_mut() {
+            notifier.set_kinds(config.notifications());
+        }
+    });
+    SOUNDS.with(|global| global.replace((config.sound(Cue::Muted), config.sound(Cue::Unmuted))));
+    CONFIG_FLASH_TALKING_WHILE_MUTED
+        .with(|global| global.replace(config.flash_talking_while_muted));
+    HOTKEYS.with(|global| global.replace(config.hotkeys()));
+}
+
+fn virtual_key(key: Key) -> u32 {
+    match key {
+        Key::Char(c) => c as u32,
+        Key::Function(number) => VK_F1.0 as u32 + number as u32 - 1,
+        Key::Space => VK_SPACE.0 as u32,
+        Key::Pause => VK_PAUSE.0 as u32,
+        Key::ScrollLock => VK_SCROLL.0 as u32,
+        Key::Insert => VK_INSERT.0 as u32,
+        Key::Delete => VK_DELETE.0 as u32,
+        Key::Home => VK_HOME.0 as u32,
+        Key::End => VK_END.0 as u32,
+        Key::PageUp => VK_PRIOR.0 as u32,
+        Key::PageDown => VK_NEXT.0 as u32,
+        Key::Mouse(4) => VK_XBUTTON1.0 as u32,
+        Key::Mouse(_) => VK_XBUTTON2.0 as u32,
+    }
+}
+
+fn hotkey_modifiers(hotkey: &Hotkey) -> HOT_KEY_MODIFIERS {
+    [
+        (hotkey.modifiers.ctrl, MOD_CONTROL),
+        (hotkey.modifiers.alt, MOD_ALT),
+        (hotkey.modifiers.shift, MOD_SHIFT),
+        (hotkey.modifiers.super_key, MOD_WIN),
+    ]
+    .into_iter()
+    .filter(|(held, _)| *held)
+    .fold(HOT_KEY_MODIFIERS(0), |modifiers, (_, modifier)| {
+        modifiers | modifier
+    })
+}
+
+// Modifiers currently held, for matching mouse hotkeys
+fn held_modifiers() -> HOT_KEY_MODIFIERS {
+    let held = |key: VIRTUAL_KEY| unsafe { GetAsyncKeyState(key.0 as i32) } < 0;
+    [
+        (held(VK_CONTROL), MOD_CONTROL),
+        (held(VK_MENU), MOD_ALT),
+        (held(VK_SHIFT), MOD_SHIFT),
+        (held(VK_LWIN) || held(VK_RWIN), MOD_WIN),
+    ]
+    .into_iter()
+    .filter(|(held, _)| *held)
+    .fold(HOT_KEY_MODIFIERS(0), |modifiers, (_, modifier)| {
+        modifiers | modifier
+    })
+}
+
+// Register the configured hotkeys for the window, replacing the ones registered
+// before. Hotkeys which another application already registered are reported.
+// RegisterHotKey does not support mouse buttons, so those are taken from a low
+// level mouse hook instead.
+fn register_hotkeys(window: HWND, previous_count: usize) {
+    for id in 0..previous_count {
+        unsafe { UnregisterHotKey(window, id as i32) }.ok();
+    }
+    let mut mouse_hotkeys = false;
+    HOTKEYS.with(|global| {
+        for (id, (hotkey, _)) in global.borrow().iter().enumerate() {
+            if matches!(hotkey.key, Key::Mouse(_)) {
+                mouse_hotkeys = true;
+                continue;
+            }
+            if let Err(error) = unsafe {
+                RegisterHotKey(
+                    window,
+                    id as i32,
+                    hotkey_modifiers(hotkey) | MOD_NOREPEAT,
+                    virtual_key(hotkey.key),
+                )
+            } {
+                eprintln!("Cannot use hotkey {}: {}", hotkey, error);
+            }
+        }
+    });
+    MOUSE_HOOK.with(|global| {
+        let mut mouse_hook = global.borrow_mut();
+        match (*mouse_hook, mouse_hotkeys) {
+            (None, true) => {
+                match unsafe { SetWindowsHookExW(WH_MOUSE_LL, Some(mouse_hook_callback), None, 0) }
+                {
+                    Ok(hook) => *mouse_hook = Some((hook, window)),
+                    Err(error) => eprintln!("Cannot use mouse hotkeys: {}", error),
+                }
+            }
+            (Some((hook, _)), false) => {
+                unsafe { UnhookWindowsHookEx(hook) }.ok();
+                *mouse_hook = None;
+            }
+            _ => {}
+        }
+    });
+}
+
+// Callback for mouse input, passing presses and releases of the side buttons
+// configured as hotkeys on to the window instead of the other applications
+extern "system" fn mouse_hook_callback(code: i32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    let (hook, window) = MOUSE_HOOK
+        .with(|global| *global.borrow())
+        .unwrap_or_default();
+    let message = wparam.0 as u32;
+    if code == HC_ACTION as i32 && (message == WM_XBUTTONDOWN || message == WM_XBUTTONUP) {
+        let info = unsafe { &*(lparam.0 as *const MSLLHOOKSTRUCT) };
+        let key = match (info.mouseData >> 16) as u16 {
+            XBUTTON1 => Key::Mouse(4),
+            _ => Key::Mouse(5),
+        };
+        let pressed = message == WM_XBUTTONDOWN;
+        // Releases are matched regardless of the modifiers, which may have
+        // been released first
+        let id = HOTKEYS.with(|global| {
+            global.borrow().iter().position(|(hotkey, _)| {
+                hotkey.key == key && (!pressed || hotkey_modifiers(hotkey) == held_modifiers())
+            })
+        });
+        if let Some(id) = id {
+            unsafe {
+                PostMessageW(
+                    window,
+                    WM_APP_MOUSE_HOTKEY,
+                    WPARAM(id),
+                    LPARAM(pressed as isize),
+                )
+            }
+            .ok();
+            return LRESULT(1);
+        }
+    }
+    unsafe { CallNextHookEx(hook, code, wparam, lparam) }
+}
+
+// Function for performing the action of a pressed or released hotkey
+fn handle_hotkey(window: HWND, id: usize, pressed: bool) -> Result<()> {
+    let Some((hotkey, action)) = HOTKEYS.with(|global| global.borrow().get(id).copied()) else {
+        return Ok(());
+    };
+    match (action, pressed) {
+        (HotkeyAction::ToggleMute, true) => toggle_mute(),
+        (HotkeyAction::Mute, true) => set_muted(true),
+        (HotkeyAction::Unmute, true) => set_muted(false),
+        (HotkeyAction::PushToTalk | HotkeyAction::PushToMute, true) => {
+            // There is no message when a key is released, so check for it
+            if !matches!(hotkey.key, Key::Mouse(_)) {
+                HELD_HOTKEY.with(|global| global.replace(Some((id, virtual_key(hotkey.key)))));
+                unsafe { SetTimer(window, IDT_HELD_HOTKEY, HELD_HOTKEY_INTERVAL_MS, None) };
+            }
+            CONTROLLER.with(|global| match global.borrow_mut().as_mut() {
+                Some(controller) => Ok(controller.hold(action == HotkeyAction::PushToMute)?),
+                _ => Ok(()),
+            })
+        }
+        (HotkeyAction::PushToTalk | HotkeyAction::PushToMute, false) => {
+            CONTROLLER.with(|global| match global.borrow_mut().as_mut() {
+                Some(controller) => {
+                    controller.release_hold()?;
+                    // Finish the hold once the release delay has passed
+                    if let Some(release_at) = controller.hold_release_due() {
+                        let delay = release_at.saturating_duration_since(std::time::Instant::now());
+                        unsafe {
+                            SetTimer(
+                                window,
+                                IDT_HOLD_RELEASE,
+                                delay.as_millis().max(1) as u32,
+                                None,
+                            )
+                        };
+                    }
+                    Ok(())
+                }
+                _ => Ok(()),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+// Function for releasing a held hotkey once its key is no longer held
+fn check_held_hotkey(window: HWND) -> Result<()> {
+    let released = HELD_HOTKEY.with(|global| match *global.borrow() {
+        Some((id, key)) if unsafe { GetAsyncKeyState(key as i32) } >= 0 => Some(id),
+        _ => None,
+    });
+    match released {
+        Some(id) => {
+            HELD_HOTKEY.with(|global| global.replace(None));
+            unsafe { KillTimer(window, IDT_HELD_HOTKEY) }.ok();
+            handle_hotkey(window, id, false)
+        }
+        _ => Ok(()),
+    }
+}
+
+// Function for restoring the mute state once a released hotkey's delay passed
+fn finish_hold(window: HWND) -> Result<()> {
+    unsafe { KillTimer(window, IDT_HOLD_RELEASE) }.ok();
+    CONTROLLER.with(|global| match global.borrow_mut().as_mut() {
+        Some(controller) => Ok(controller.finish_hold()?),
+        _ => Ok(()),
+    })
+}
+
+// Apply the config file again if it changed, keeping the current configuration
+// when the new one cannot be loaded
+fn reload_config(window: HWND) -> Result<()> {
+    match CONFIG_WATCHER.with(|global| global.borrow_mut().as_mut().and_then(ConfigWatcher::poll)) {
+        Some(Ok(config)) => {
+            let hotkey_count = HOTKEYS.with(|global| global.borrow().len());
+            apply_config(&config);
+            register_hotkeys(window, hotkey_count);
+            set_metering_timer(window);
+            update_talking_flash(window)?;
+            let instance: HINSTANCE = unsafe { GetModuleHandleW(None)?.into() };
+            load_icons(instance)?;
+            update_notify_icon()?;
+            // The menu is built around the forced volume option, so start over
+            destroy_menu()?;
+            CONTROLLER.with(|global| match &*global.borrow() {
+                Some(controller) => Ok(controller.apply_forced_volume()?),
+                _ => Ok(()),
+            })
+        }
+        Some(Err(error)) => {
+            eprintln!("Keeping the current configuration: {}", error);
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+// Run the metering timer while the input level is read
+fn set_metering_timer(window: HWND) {
+    let enabled = CONTROLLER.with(|global| {
+        global
+            .borrow()
+            .as_ref()
+            .is_some_and(|controller| controller.metering_due().is_some())
+    });
+    if enabled {
+        unsafe {
+            SetTimer(
+                window,
+                IDT_METERING,
+                LEVEL_METER_INTERVAL.as_millis() as u32,
+                None,
+            )
+        };
+    } else {
+        unsafe { KillTimer(window, IDT_METERING) }.ok();
+    }
+}
+
+// Read the input level, warning when talking while muted starts and redrawing
+// the icon when what it shows changed
+fn update_metering(window: HWND) -> Result<()> {
+    let (changed, talking_started) =
+        CONTROLLER.with(|global| match global.borrow_mut().as_mut() {
+            Some(controller) => {
+                let talking = controller.talking_while_muted();
+                let changed = controller.update_metering()?;
+                Ok::<_, Error>((changed, !talking && controller.talking_while_muted()))
+            }
+            _ => Ok((false, false)),
+        })?;
+    if talking_started {
+        notify_talking_while_muted(window)?;
+    }
+    if changed {
+        update_talking_flash(window)?;
+        update_notify_icon()?;
+    }
+    Ok(())
+}
+
+// Flash the icon while talking while muted, when configured
+fn update_talking_flash(window: HWND) -> Result<()> {
+    let flash = CONFIG_FLASH_TALKING_WHILE_MUTED.with(|global| *global.borrow())
+        && CONTROLLER.with(|global| {
+            global
+                .borrow()
+                .as_ref()
+                .is_some_and(Controller::talking_while_muted)
+        });
+    if flash != TALKING_FLASH.with(|global| global.borrow().is_some()) {
+        TALKING_FLASH.with(|global| global.replace(flash.then_some(true)));
+        if flash {
+            unsafe { SetTimer(window, IDT_TALKING_FLASH, TALKING_FLASH_INTERVAL_MS, None) };
+        } else {
+            unsafe { KillTimer(window, IDT_TALKING_FLASH) }.ok();
+        }
+        update_notify_icon()?;
+    }
+    Ok(())
+}
+
+// Function for toggling mute, used when clicking the icon
+fn toggle_mute() -> Result<()> {
+    CONTROLLER.with(|global| match &*global.borrow() {
+        Some(controller) => Ok(controller.toggle_mute()?),
+        _ => Ok(()),
+    })
+}
+
+// Functions for setting the muted state
+fn set_muted(muted: bool) -> Result<()> {
+    CONTROLLER.with(|global| match &*global.borrow() {
+        Some(controller) => Ok(controller.set_muted(muted)?),
+        _ => Ok(()),
+    })
+}
+
+// Function for opening the Sound Control Panel
+fn open_sound_control_panel_recording_tab() -> Result<()> {
+    let system_directory = {
+        let mut buffer = [0u16; MAX_PATH as usize];
+        let length = unsafe { GetSystemDirectoryW(Some(&mut buffer)) };
+        if length == 0 || length > buffer.len() as u32 {
+            Err(Error::from_win32())
+        } else {
+            Ok(buffer
+                .into_iter()
+                .take(length as usize)
+                .chain(std::iter::once(0))
+                .collect::<Vec<u16>>())
+        }
+    }?;
+    let command = system_directory
+        .clone()
+        .into_iter()
+        .take_while(|c| *c != 0)
+        .chain("\\control.exe".encode_utf16())
+        .chain(std::iter::once(0))
+        .collect::<Vec<u16>>();
+    let mut arguments = command
+        .clone()
+        .into_iter()
+        .take_while(|c| *c != 0)
+        .chain(" mmsys.cpl,,1".to_string().encode_utf16())
+        .chain(std::iter::once(0))
+        .collect::<Vec<u16>>();
+    unsafe {
+        CreateProcessW(
+            PCWSTR(command.as_ptr()),
+            PWSTR(arguments.as_mut_ptr()),
+            None,
+            None,
+            FALSE,
+            DETACHED_PROCESS,
+            None,
+            PCWSTR(system_directory.as_ptr()),
+            &STARTUPINFOW {
+                ..Default::default()
+            },
+            &mut PROCESS_INFORMATION {
+                ..Default::default()
+            },
+        )
+    }
+}
+
+// Function for setting the volume to the maximum value
+fn set_volume_to_max() -> Result<()> {
+    CONTROLLER.with(|global| match &*global.borrow() {
+        Some(controller) => Ok(controller.set_volume_to_max()?),
+        _ => Ok(()),
+    })
+}
+
+// Function for setting the volume in percent
+fn set_volume_percent(percent: u32) -> Result<()> {
+    CONTROLLER.with(|global| match &*global.borrow() {
+        Some(controller) => Ok(controller.set_volume_percent(percent)?),
+        _ => Ok(()),
+    })
+}
+
+// Function for raising or lowering the volume by a number of percent
+fn change_volume_percent(delta: i32) -> Result<()> {
+    CONTROLLER.with(|global| match &*global.borrow() {
+        Some(controller) => Ok(controller.change_volume_percent(delta)?),
+        _ => Ok(()),
+    })
+}
+
+// Function for performing an action requested on the command line
+fn perform_action(action: Action) -> Result<()> {
+    match action {
+        Action::Mute => set_muted(true),
+        Action::Unmute => set_muted(false),
+        Action::ToggleMute => toggle_mute(),
+        Action::SetVolumeToMax => set_volume_to_max(),
+        Action::SetVolume(percent) => set_volume_percent(percent),
+        Action::VolumeUp(step) => change_volume_percent(step as i32),
+        Action::VolumeDown(step) => change_volume_percent(-(step as i32)),
+    }
+}
+
+// Function for applying the configuration after the audio backend reported a change
+fn handle_audio_event(event: AudioEvent) -> Result<()> {
+    CONTROLLER.with(|global| match global.borrow_mut().as_mut() {
+        Some(controller) => {
+            let paused = controller.forced_volume_paused();
+            controller.handle_audio_event(event)?;
+            if !paused && controller.forced_volume_paused() {
+                eprintln!(
+                    "The recording volume keeps being changed, not restoring it for a while."
+                );
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    })
+}
+
+// Function for setting the default audio device
+fn set_default_audio_capture_device(device_id: &str) -> Result<()> {
+    CONTROLLER.with(|global| match &*global.borrow() {
+        Some(controller) => Ok(controller.set_default_device(device_id)?),
+        _ => Ok(()),
+    })
+}
+
+// Set the default device for a single role, by its position in the submenu
+fn set_role_default_audio_capture_device(role: DeviceRole, i: usize) -> Result<()> {
+    // The submenu for other applications covers multimedia too
+    let roles = match role {
+        DeviceRole::Communications => [DeviceRole::Communications].as_slice(),
+        _ => [DeviceRole::Console, DeviceRole::Multimedia].as_slice(),
+    };
+    let device_id = MENU_DEFAULT_ROLE_DEVICES.with(|global| global.borrow().get(i).cloned());
+    CONTROLLER.with(|global| match (&*global.borrow(), device_id) {
+        (Some(controller), Some(device_id)) => {
+            Ok(controller.set_default_device_for(&device_id, roles)?)
+        }
+        _ => Ok(()),
+    })
+}
+
+// Create a copy of the icon with its pixels changed by the given function, which
+// receives the ARGB pixels bottom row first along with the width and height
+fn modify_icon(icon: HICON, modify: impl FnOnce(&mut [u32], i32, i32)) -> Result<HICON> {
+    unsafe {
+        // Get info about the icon
+        let mut icon_info: ICONINFO = Default::default();
+        GetIconInfo(icon, &mut icon_info)?;
+
+        // Check we have a color icon we can modify
+        if icon_info.hbmColor.is_invalid() {
+            return Ok(icon);
+        }
+
+        // Retrieve the icon bitmap from the handle
+        let mut icon_bitmap: BITMAP = Default::default();
+        assert!(
+            Gdi::GetObjectW(
+                icon_info.hbmColor,
+                std::mem::size_of::<BITMAP>() as i32,
+                Some(&mut icon_bitmap as *mut _ as *mut _)
+            ) as usize
+                == std::mem::size_of::<BITMAP>(),
+            "Failed to read icon bitmap"
+        );
+
+        // Create device context for accessing the icon
+        let icon_dc: HDC = CreateCompatibleDC(None);
+        let icon_dc_prevobj = Gdi::SelectObject(icon_dc, icon_info.hbmColor);
+
+        // Create new 32-bit RGBA bitmap to contain the modified icon
+        let mut modified_info = BITMAPV5HEADER {
+            bV5Size: std::mem::size_of::<BITMAPV5HEADER>() as u32,
+            bV5Width: icon_bitmap.bmWidth,
+            bV5Height: icon_bitmap.bmHeight,
+            bV5Planes: 1,
+            bV5BitCount: 32,
+            bV5Compression: BI_BITFIELDS,
+            bV5RedMask: 0x00ff0000,
+            bV5GreenMask: 0x0000ff00,
+            bV5BlueMask: 0x000000ff,
+            bV5AlphaMask: 0xff000000,
+            ..Default::default()
+        };
+
+        // Pointer to the RGBA pixels of the modified bitmap
+        let mut modified_pixels: *mut u32 = std::ptr::null_mut();
+
+        // Create modified bitmap using a new device context
+        let modified_dc: HDC = CreateCompatibleDC(None);
+        let modified_bitmap = CreateDIBSection(
+            modified_dc,
+            &mut modified_info as *mut _ as *const BITMAPINFO,
+            DIB_RGB_COLORS,
+            &mut modified_pixels as *mut _ as *mut *mut c_void,
+            None,
+            0,
+        )?;
+        let modified_dc_prevobj = Gdi::SelectObject(modified_dc, modified_bitmap);
+
+        // Create a copy of the icon by blitting from the DC with the original to the new one
+        BitBlt(
+            modified_dc,
+            0,
+            0,
+            icon_bitmap.bmWidth,
+            icon_bitmap.bmHeight,
+            icon_dc,
+            0,
+            0,
+            SRCCOPY,
+        )?;
+
+        // Remove DC for original icon since we don't need it after blitting is done
+        Gdi::SelectObject(icon_dc, icon_dc_prevobj);
+        DeleteDC(icon_dc).ok()?;
+
+        // Modify the pixels which we can now access via the pointer
+        modify(
+            std::slice::from_raw_parts_mut(
+                modified_pixels,
+                (icon_bitmap.bmWidth * icon_bitmap.bmHeight) as usize,
+            ),
+            icon_bitmap.bmWidth,
+            icon_bitmap.bmHeight,
+        );
+
+        // Create a new icon with our modified color data
+        icon_info.hbmColor = modified_bitmap;
+        let modified = CreateIconIndirect(&icon_info)?;
+
+        // Remove DC for modified icon since we have now created it
+        Gdi::SelectObject(modified_dc, modified_dc_prevobj);
+        DeleteDC(modified_dc).ok()?;
+
+        Ok(modified)
+    }
+}
+
+// Create a copy of the icon with its colors inverted
+fn invert_icon(icon: HICON) -> Result<HICON> {
+    modify_icon(icon, |pixels, _, _| {
+        pixels.iter_mut().for_each(|pixel| *pixel ^= 0x00ffffff)
+    })
+}
+
+// Create a copy of the icon in a single color, keeping its transparency
+fn recolor_icon(icon: HICON, color: u32) -> Result<HICON> {
+    modify_icon(icon, |pixels, _, _| {
+        pixels
+            .iter_mut()
+            .for_each(|pixel| *pixel = *pixel & 0xff000000 | color)
+    })
+}
+
+// Create a copy of the icon with a bar along the left edge rising with the level
+fn draw_level_meter(icon: HICON, level: u8) -> Result<HICON> {
+    modify_icon(icon, |pixels, width, height| {
+        let top = 0.9 - 0.8 * level as f32 / LEVEL_METER_STEPS as f32;
+        for y in 0..height {
+            for x in 0..width {
+                let (fx, fy) = (
+                    (x as f32 + 0.5) / width as f32,
+                    (y as f32 + 0.5) / height as f32,
+                );
+                if (0.04..0.14).contains(&fx) && (top..0.9).contains(&fy) {
+                    pixels[((height - 1 - y) * width + x) as usize] = ICON_COLOR_LEVEL;
+                }
+            }
+        }
+    })
+}
+
+// Create a copy of the icon with a dot in the top right corner, shown while an
+// application is listening
+fn mark_icon_in_use(icon: HICON) -> Result<HICON> {
+    modify_icon(icon, |pixels, width, height| {
+        for y in 0..height {
+            for x in 0..width {
+                let (dx, dy) = (
+                    (x as f32 + 0.5) / width as f32 - 0.84,
+                    (y as f32 + 0.5) / height as f32 - 0.16,
+                );
+                if dx.hypot(dy) < 0.12 {
+                    pixels[((height - 1 - y) * width + x) as usize] = ICON_COLOR_IN_USE;
+                }
+            }
+        }
+    })
+}
+
+// Create a copy of the icon with a yellow slash through it, shown while only
+// some devices are muted
+fn mark_icon_partially_muted(icon: HICON) -> Result<HICON> {
+    modify_icon(icon, |pixels, width, height| {
+        for y in 0..height {
+            for x in 0..width {
+                let (px, py) = (
+                    (x as f32 + 0.5) / width as f32,
+                    (y as f32 + 0.5) / height as f32,
+                );
+                // Distance to the line from (0.2, 0.14) to (0.8, 0.86)
+                let t = ((px - 0.2) * 0.6 + (py - 0.14) * 0.72) / (0.6 * 0.6 + 0.72 * 0.72);
+                let t = t.clamp(0.0, 1.0);
+                let distance = (px - 0.2 - t * 0.6).hypot(py - 0.14 - t * 0.72);
+                let pixel = &mut pixels[((height - 1 - y) * width + x) as usize];
+                if distance < 0.055 {
+                    *pixel = ICON_COLOR_PARTIALLY_MUTED;
+                } else if distance < 0.11 {
+                    *pixel = 0;
+                }
+            }
+        }
+    })
+}
+
+// Load an icon and invert it if required
+fn load_icon(
+    instance: HINSTANCE,
+    path: PCWSTR,
+    index: u32,
+    theme: Option<IconTheme>,
+) -> Result<(HICON, HICON)> {
+    let icon = unsafe { ExtractIconW(instance, path, index) };
+    assert!(!icon.is_invalid(), "Icon is not valid.");
+    let icon_inverted = invert_icon(icon)?;
+    assert!(!icon_inverted.is_invalid(), "Inverted icon is not valid.");
+    // Both icons are always separate handles, as each of them is destroyed
+    let (kept, unused) = match theme.unwrap_or(CONFIG_ICON_THEME.with(|global| *global.borrow())) {
+        IconTheme::Auto => return Ok((icon, icon_inverted)),
+        IconTheme::Normal => (icon, icon_inverted),
+        IconTheme::Inverted => (icon_inverted, icon),
+    };
+    unsafe { DestroyIcon(unused) }?;
+    Ok((kept, unsafe { CopyIcon(kept) }?))
+}
+
+// Load the icons to use from the icon file
+fn load_icons(instance: HINSTANCE) -> Result<()> {
+    // Load the icons
+    let (icon_active, icon_active_inverted) = CONFIG_ICON_ACTIVE.with(|global| {
+        let (_, path, index, theme) = &*global.borrow();
+        load_icon(instance, *path, *index, *theme)
+    })?;
+    let (icon_muted, icon_muted_inverted) = CONFIG_ICON_MUTED.with(|global| {
+        let (_, path, index, theme) = &*global.borrow();
+        load_icon(instance, *path, *index, *theme)
+    })?;
+    let icon_in_use = mark_icon_in_use(icon_active)?;
+    let icon_in_use_inverted = mark_icon_in_use(icon_active_inverted)?;
+    let icon_talking = recolor_icon(icon_muted, ICON_COLOR_TALKING)?;
+    let icon_partially_muted = mark_icon_partially_muted(icon_active)?;
+    let icon_partially_muted_inverted = mark_icon_partially_muted(icon_active_inverted)?;
+
+    // Replace currently loaded icons
+    ICON_ACTIVE.with(|global| match global.replace(Some(icon_active)) {
+        Some(old_icon) => unsafe { DestroyIcon(old_icon) },
+        _ => Ok(()),
+    })?;
+    ICON_ACTIVE_INVERTED.with(|global| match global.replace(Some(icon_active_inverted)) {
+        Some(old_icon) => unsafe { DestroyIcon(old_icon) },
+        _ => Ok(()),
+    })?;
+    ICON_IN_USE.with(|global| match global.replace(Some(icon_in_use)) {
+        Some(old_icon) => unsafe { DestroyIcon(old_icon) },
+        _ => Ok(()),
+    })?;
+    ICON_IN_USE_INVERTED.with(|global| match global.replace(Some(icon_in_use_inverted)) {
+        Some(old_icon) => unsafe { DestroyIcon(old_icon) },
+        _ => Ok(()),
+    })?;
+    ICON_MUTED.with(|global| match global.replace(Some(icon_muted)) {
+        Some(old_icon) => unsafe { DestroyIcon(old_icon) },
+        _ => Ok(()),
+    })?;
+    ICON_MUTED_INVERTED.with(|global| match global.replace(Some(icon_muted_inverted)) {
+        Some(old_icon) => unsafe { DestroyIcon(old_icon) },
+        _ => Ok(()),
+    })?;
+    ICON_TALKING.with(|global| match global.replace(Some(icon_talking)) {
+        Some(old_icon) => unsafe { DestroyIcon(old_icon) },
+        _ => Ok(()),
+    })?;
+    ICON_PARTIALLY_MUTED.with(|global| match global.replace(Some(icon_partially_muted)) {
+        Some(old_icon) => unsafe { DestroyIcon(old_icon) },
+        _ => Ok(()),
+    })?;
+    ICON_PARTIALLY_MUTED_INVERTED.with(|global| {
+        match global.replace(Some(icon_partially_muted_inverted)) {
+            Some(old_icon) => unsafe { DestroyIcon(old_icon) },
+            _ => Ok(()),
+        }
+    })?;
+
+    Ok(())
+}
+
+// Function determining whether we should use the inverted icon by checking if Windows is in dark or light mode
+fn should_use_inverted_icon() -> bool {
+    let mut buffer = [0u8; 4];
+    let mut size: u32 = 4;
+    if unsafe {
+        RegGetValueW(
+            HKEY_CURRENT_USER,
+            w!("Software\\Microsoft\\Windows\\CurrentVersion\\Themes\\Personalize"),
+            w!("AppsUseLightTheme"),
+            RRF_RT_REG_DWORD,
+            None,
+            Some(buffer.as_mut_ptr() as *mut c_void),
+            Some(&mut size as *mut u32),
+        )
+        .ok()
+        .is_err()
+    } {
+        return false;
+    }
+    assert!(size == 4, "Invalid size for DWORD.");
+    i32::from_le_bytes(buffer) != 0
+}
+
+// Retrieves the microphone status and updates the icon and tooltip
+fn update_icon_data() -> Result<()> {
+    let (icon_active, icon_in_use, icon_muted, icon_partially_muted) = if should_use_inverted_icon()
+    {
+        (
+            &ICON_ACTIVE_INVERTED,
+            &ICON_IN_USE_INVERTED,
+            &ICON_MUTED_INVERTED,
+            &ICON_PARTIALLY_MUTED_INVERTED,
+        )
+    } else {
+        (
+            &ICON_ACTIVE,
+            &ICON_IN_USE,
+            &ICON_MUTED,
+            &ICON_PARTIALLY_MUTED,
+        )
+    };
+    let (indicator, level) = CONTROLLER.with(|global| match &*global.borrow() {
+        Some(controller) => Ok((controller.state()?, controller.level())),
+        _ => Ok::<_, Error>((
+            IndicatorState {
+                active: false,
+                in_use: false,
+                partially_muted: false,
+                tooltip: LABEL_NO_DEFAULT_DEVICE.to_owned(),
+            },
+            None,
+        )),
+    })?;
+    let icon = if indicator.in_use {
+        icon_in_use
+    } else if indicator.active {
+        icon_active
+    } else if indicator.partially_muted {
+        icon_partially_muted
+    } else if TALKING_FLASH.with(|global| *global.borrow() == Some(true)) {
+        &ICON_TALKING
+    } else {
+        icon_muted
+    };
+
+    // Draw the level meter into a copy of the icon while active
+    let level_icon = match level.filter(|level| indicator.active && *level > 0) {
+        Some(level) => icon
+            .with(|icon| *icon.borrow())
+            .map(|icon| draw_level_meter(icon, level))
+            .transpose()?,
+        _ => None,
+    };
+    if let Some(old_icon) = ICON_LEVEL.with(|global| global.replace(level_icon)) {
+        unsafe { DestroyIcon(old_icon) }?;
+    }
+    let icon = if level_icon.is_some() {
+        &ICON_LEVEL
+    } else {
+        icon
+    };
+    let text = indicator.tooltip.encode_utf16().collect::<Vec<u16>>();
+    NOTIFY_ICON_DATA.with(|global_notify_icon_data| {
+        global_notify_icon_data.replace_with(|previous_notify_icon_data| {
+            let mut notify_icon_data = previous_notify_icon_data.unwrap_or_default();
+            let max_text_len = notify_icon_data.szTip.len() - 1;
+            icon.with(|icon_ref| match *icon_ref.borrow() {
+                Some(icon_id) if !icon_id.is_invalid() => {
+                    notify_icon_data.hIcon = icon_id;
+                    notify_icon_data.uFlags |= NIF_ICON;
+                }
+                _ => {
+                    notify_icon_data.hIcon = Default::default();
+                    notify_icon_data.uFlags &= !NIF_ICON;
+                }
+            });
+            notify_icon_data
+                .szTip
+                .iter_mut()
+                .zip(
+                    text.clone()
+                        .into_iter()
+                        .take(max_text_len)
+                        .chain(std::iter::repeat(0)),
+                )
+                .for_each(|(ptr, chr)| *ptr = chr);
+            if notify_icon_data.szTip[0] == 0 {
+                notify_icon_data.uFlags &= !(NIF_TIP | NIF_SHOWTIP);
+            } else {
+                notify_icon_data.uFlags |= NIF_TIP | NIF_SHOWTIP;
+            }
+            Some(notify_icon_data)
+        })
+    });
+
+    Ok(())
+}
+
+// Show a notification as a balloon of the notify icon, which Windows 10 and
+// later show as a toast
+fn show_notification(notification: &Notification) -> Result<()> {
+    NOTIFY_ICON_DATA.with(|global| match global.borrow().as_ref() {
+        Some(notify_icon_data) => {
+            let mut notify_icon_data = NOTIFYICONDATAW {
+                uFlags: NIF_INFO,
+                dwInfoFlags: NIIF_NONE | NIIF_NOSOUND,
+                ..*notify_icon_data
+            };
+            for (buffer, text) in [
+                (&mut notify_icon_data.szInfoTitle[..], &notification.title),
+                (&mut notify_icon_data.szInfo[..], &notification.body),
+            ] {
+                let max_text_len = buffer.len() - 1;
+                buffer
+                    .iter_mut()
+                    .zip(
+                        text.encode_utf16()
+                            .take(max_text_len)
+                            .chain(std::iter::repeat(0)),
+                    )
+                    .for_each(|(ptr, chr)| *ptr = chr);
+            }
+            unsafe { Shell_NotifyIconW(NIM_MODIFY, &notify_icon_data).ok() }
+        }
+        _ => Ok(()),
+    })
+}
+
+// Show a notification for what changed, or later when it is throttled
+fn notify_change(window: HWND) -> Result<()> {
+    let status = CONTROLLER.with(|global| match &*global.borrow() {
+        Some(controller) => controller.status().map(Some),
+        _ => Ok(None),
+    })?;
+    let (notification, pending_due) = NOTIFIER.with(|global| match global.borrow_mut().as_mut() {
+        Some(notifier) => (
+            status.and_then(|status| notifier.update(status)),
+            notifier.pending_due(),
+        ),
+        _ => (None, None),
+    });
+    show_or_schedule_notification(window, notification, pending_due)
+}
+
+// Show a warning about talking while muted, or later when it is throttled
+fn notify_talking_while_muted(window: HWND) -> Result<()> {
+    let status = CONTROLLER.with(|global| match &*global.borrow() {
+        Some(controller) => controller.status().map(Some),
+        _ => Ok(None),
+    })?;
+    let (notification, pending_due) = NOTIFIER.with(|global| match global.borrow_mut().as_mut() {
+        Some(notifier) => (
+            status.and_then(|status| notifier.talking_while_muted(&status)),
+            notifier.pending_due(),
+        ),
+        _ => (None, None),
+    });
+    show_or_schedule_notification(window, notification, pending_due)
+}
+
+fn show_or_schedule_notification(
+    window: HWND,
+    notification: Option<Notification>,
+    pending_due: Option<std::time::Instant>,
+) -> Result<()> {
+    if let Some(due) = pending_due {
+        let delay = due.saturating_duration_since(std::time::Instant::now());
+        unsafe {
+            SetTimer(
+                window,
+                IDT_NOTIFICATION,
+                delay.as_millis().max(1) as u32,
+                None,
+            )
+        };
+    }
+    match notification {
+        Some(notification) => show_notification(&notification),
+        _ => Ok(()),
+    }
+}
+
+// Function for showing the notification held back by throttling
+fn flush_notification(window: HWND) -> Result<()> {
+    unsafe { KillTimer(window, IDT_NOTIFICATION) }.ok();
+    let (notification, pending_due) = NOTIFIER.with(|global| match global.borrow_mut().as_mut() {
+        Some(notifier) => (notifier.flush(), notifier.pending_due()),
+        _ => (None, None),
+    });
+    show_or_schedule_notification(window, notification, pending_due)
+}
+
+// Start playing a sound on the default render device
+fn play_sound(sound: &Sound) -> Result<()> {
+    let played = match sound {
+        Sound::File(path) => unsafe {
+            PlaySoundW(
+                &HSTRING::from(path.as_os_str()),
+                None,
+                SND_FILENAME | SND_ASYNC | SND_NODEFAULT,
+            )
+        },
+        Sound::BuiltIn(cue) => BUILT_IN_SOUNDS.with(|global| {
+            let mut built_in_sounds = global.borrow_mut();
+            let i = match built_in_sounds
+                .iter()
+                .position(|(built_in, _)| built_in == cue)
+            {
+                Some(i) => i,
+                _ => {
+                    built_in_sounds.push((*cue, cue_wav(*cue)));
+                    built_in_sounds.len() - 1
+                }
+            };
+            unsafe {
+                PlaySoundW(
+                    PCWSTR(built_in_sounds[i].1.as_ptr() as *const u16),
+                    None,
+                    SND_MEMORY | SND_ASYNC | SND_NODEFAULT,
+                )
+            }
+        }),
+    };
+    played.ok()
+}
+
+// Play the sound for muting or unmuting when the mute state flipped
+fn play_cue() -> Result<()> {
+    let muted = CONTROLLER.with(|global| match &*global.borrow() {
+        Some(controller) => controller.muted(),
+        _ => Ok(None),
+    })?;
+    let sound = CUE_TRACKER
+        .with(|global| global.borrow_mut().update(muted))
+        .and_then(|cue| {
+            SOUNDS.with(|global| match cue {
+                Cue::Muted => global.borrow().0.clone(),
+                Cue::Unmuted => global.borrow().1.clone(),
+            })
+        });
+    match sound {
+        Some(sound) => play_sound(&sound),
+        _ => Ok(()),
+    }
+}
+
+// Add the notify icon for when it does not already exists
+fn add_notify_icon() -> Result<()> {
+    update_icon_data()?;
+    NOTIFY_ICON_DATA.with(|global| {
+        if let Some(notify_icon_data) = global.borrow().as_ref() {
+            unsafe {
+                Shell_NotifyIconW(NIM_ADD, notify_icon_data).ok()?;
+                Shell_NotifyIconW(NIM_SETVERSION, notify_icon_data).ok()?;
+            }
+            Ok(())
+        } else {
+            Ok(())
+        }
+    })
+}
+
+// Update the existing notify icon
+fn update_notify_icon() -> Result<()> {
+    update_icon_data()?;
+    NOTIFY_ICON_DATA.with(|global| {
+        if let Some(notify_icon_data) = global.borrow().as_ref() {
+            unsafe { Shell_NotifyIconW(NIM_MODIFY, notify_icon_data).ok() }
+        } else {
+            Ok(())
+        }
+    })
+}
+
+// Update the icon and the menu when the applications capturing audio changed
+fn check_capture_streams(window: HWND) -> Result<()> {
+    let streams = CONTROLLER.with(|global| match &*global.borrow() {
+        Some(controller) => controller.capture_streams(),
+        _ => Ok(Vec::new()),
+    })?;
+    if CAPTURE_STREAMS.with(|global| global.replace(streams.clone())) != streams {
+        update_notify_icon()?;
+        update_menu()?;
+        unsafe { DrawMenuBar(window) }?;
+    }
+    Ok(())
+}
+
+// Replace the items of the submenu listing the applications capturing audio
+fn update_capture_streams_menu(menu: HMENU) -> Result<()> {
+    let streams = CAPTURE_STREAMS.with(|global| global.borrow().clone());
+    MENU_CAPTURE_STREAMS.with(|global| -> Result<()> {
+        if let Some(streams_menu) = *global.borrow() {
+            unsafe {
+                while GetMenuItemCount(streams_menu) > 0 {
+                    DeleteMenu(streams_menu, 0, MF_BYPOSITION)?;
+                }
+            }
+            for stream in &streams {
+                let label = capture_stream_label(stream)
+                    .encode_utf16()
+                    .chain(std::iter::once(0))
+                    .collect::<Vec<u16>>();
+                unsafe {
+                    AppendMenuW(
+                        streams_menu,
+                        MF_DISABLED | MF_GRAYED | MF_STRING,
+                        IDM_CAPTURE_STREAM as usize,
+                        PCWSTR(label.as_ptr()),
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    })?;
+    let mut label_buffer = if streams.is_empty() {
+        LABEL_NO_CAPTURE_STREAMS
+    } else {
+        LABEL_CAPTURE_STREAMS
+    }
+    .encode_utf16()
+    .chain(std::iter::once(0))
+    .collect::<Vec<u16>>();
+    unsafe {
+        SetMenuItemInfoW(
+            menu,
+            IDM_CAPTURE_STREAMS as u32,
+            false,
+            &MENUITEMINFOW {
+                cbSize: std::mem::size_of::<MENUITEMINFOW>() as u32,
+                fMask: MIIM_STATE | MIIM_STRING,
+                fState: if streams.is_empty() {
+                    MFS_DISABLED
+                } else {
+                    MFS_ENABLED
+                },
+                dwTypeData: PWSTR(label_buffer.as_mut_ptr()),
+                ..Default::default()
+            },
+        )?;
+    }
+    Ok(())
+}
+
+// Replace the items of the submenus setting the default device per role,
+// checking the device which is the default for that role
+fn update_default_role_menus(controller: &Controller, devices: &[AudioDevice]) -> Result<()> {
+    MENU_DEFAULT_ROLES.with(|global| -> Result<()> {
+        for (role, role_menu) in &*global.borrow() {
+            let default_id = controller.role_default_device(*role).ok().flatten();
+            let first_id = match role {
+                DeviceRole::Communications => IDM_COMMUNICATIONS_DEVICES,
+                _ => IDM_CONSOLE_DEVICES,
+            };
+            unsafe {
+                while GetMenuItemCount(*role_menu) > 0 {
+                    DeleteMenu(*role_menu, 0, MF_BYPOSITION)?;
+                }
+            }
+            for (i, device) in devices.iter().enumerate() {
+                let label = device
+                    .name
+                    .encode_utf16()
+                    .chain(std::iter::once(0))
+                    .collect::<Vec<u16>>();
+                let check = if default_id.as_ref() == Some(&device.id) {
+                    MF_CHECKED
+                } else {
+                    MF_UNCHECKED
+                };
+                unsafe {
+                    AppendMenuW(
+                        *role_menu,
+                        MF_ENABLED | MF_STRING | check,
+                        (first_id + i as u16) as usize,
+                        PCWSTR(label.as_ptr()),
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    })?;
+    MENU_DEFAULT_ROLE_DEVICES
+        .with(|global| global.replace(devices.iter().map(|device| device.id.clone()).collect()));
+    Ok(())
+}
+
+// Update the menu
+fn update_menu() -> Result<()> {
+    MENU.with(|global_menu| {
+        CONTROLLER.with(|global_controller| match &*global_controller.borrow() {
+            Some(controller) => {
+                let devices = controller.capture_devices()?;
+                let devices_count = devices.len();
+                if global_menu.borrow().is_none() {
+                    global_menu.replace(Some({
+                        let menu = unsafe { CreatePopupMenu() }?;
+                        unsafe {
+                            AppendMenuW(
+                                menu,
+                                MF_DISABLED | MF_GRAYED,
+                                IDM_NO_ENDPOINTS as usize,
+                                LABEL_NO_CAPTURE_DEVICES,
+                            )?;
+                            AppendMenuW(
+                                menu,
+                                MF_SEPARATOR,
+                                IDM_SEPARATOR as usize,
+                                PCWSTR::null(),
+                            )?;
+                            let mut role_menus = Vec::new();
+                            for (role, label) in [
+                                (DeviceRole::Communications, LABEL_COMMUNICATIONS_DEVICE),
+                                (DeviceRole::Console, LABEL_CONSOLE_DEVICE),
+                            ] {
+                                let role_menu = CreatePopupMenu()?;
+                                AppendMenuW(
+                                    menu,
+                                    MF_POPUP | MF_ENABLED | MF_STRING,
+                                    role_menu.0 as usize,
+                                    label,
+                                )?;
+                                role_menus.push((role, role_menu));
+                            }
+                            MENU_DEFAULT_ROLES.with(|global| global.replace(role_menus));
+                            let streams_menu = CreatePopupMenu()?;
+                            InsertMenuItemW(
+                                menu,
+                                GetMenuItemCount(menu) as u32,
+                                true,
+                                &MENUITEMINFOW {
+                                    cbSize: std::mem::size_of::<MENUITEMINFOW>() as u32,
+                                    fMask: MIIM_ID | MIIM_STRING | MIIM_SUBMENU,
+                                    wID: IDM_CAPTURE_STREAMS as u32,
+                                    hSubMenu: streams_menu,
+                                    dwTypeData: PWSTR(
+                                        LABEL_CAPTURE_STREAMS
+                                            .encode_utf16()
+                                            .chain(std::iter::once(0))
+                                            .collect::<Vec<u16>>()
+                                            .as_mut_ptr(),
+                                    ),
+                                    ..Default::default()
+                                },
+                            )?;
+                            MENU_CAPTURE_STREAMS.with(|global| global.replace(Some(streams_menu)));
+                            let volume_menu = CreatePopupMenu()?;
+                            for (i, preset) in VOLUME_PRESETS.iter().enumerate() {
+                                let label = format!("{}%", preset)
+                                    .encode_utf16()
+                                    .chain(std::iter::once(0))
+                                    .collect::<Vec<u16>>();
+                                AppendMenuW(
+                                    volume_menu,
+                                    MF_ENABLED | MF_STRING,
+                                    (IDM_VOLUME_PRESETS + i as u16) as usize,
+                                    PCWSTR(label.as_ptr()),
+                                )?;
+                            }
+                            AppendMenuW(
+                                menu,
+                                if controller.forced_volume().is_some() {
+                                    MF_POPUP | MF_GRAYED | MF_STRING
+                                } else {
+                                    MF_POPUP | MF_ENABLED | MF_STRING
+                                },
+                                volume_menu.0 as usize,
+                                LABEL_VOLUME,
+                            )?;
+                            MENU_VOLUME.with(|global| global.replace(Some(volume_menu)));
+                            if let Some(percent) = controller.forced_volume() {
+                                let mut label_buffer = forced_volume_label(percent, false)
+                                    .encode_utf16()
+                                    .chain(std::iter::once(0))
+                                    .collect::<Vec<u16>>();
+                                let label = PWSTR(label_buffer.as_mut_ptr());
+                                InsertMenuItemW(
+                                    menu,
+                                    IDM_SET_MAX_VOLUME as u32,
+                                    false,
+                                    &MENUITEMINFOW {
+                                        cbSize: std::mem::size_of::<MENUITEMINFOW>() as u32,
+                                        fMask: MIIM_FTYPE | MIIM_ID | MIIM_STATE | MIIM_STRING,
+                                        fType: MFT_STRING,
+                                        fState: MFS_CHECKED | MFS_DISABLED,
+                                        wID: IDM_SET_MAX_VOLUME as u32,
+                                        dwTypeData: label,
+                                        ..Default::default()
+                                    },
+                                )?;
+                            } else {
+                                AppendMenuW(
+                                    menu,
+                                    MF_ENABLED | MF_STRING,
+                                    IDM_SET_MAX_VOLUME as usize,
+                                    LABEL_SET_MAX_VOLUME,
+                                )?;
+                            }
+                            AppendMenuW(
+                                menu,
+                                MF_ENABLED | MF_STRING,
+                                IDM_OPEN_SOUNDCONTROLPANEL as usize,
+                                LABEL_OPEN_SOUNDCONTROLPANEL,
+                            )?;
+                            AppendMenuW(
+                                menu,
+                                MF_ENABLED | MF_STRING,
+                                IDM_EXIT as usize,
+                                LABEL_EXIT,
+                            )?;
+                        }
+                        menu
+                    }));
+                }
+
+                // Check the preset matching the current volume
+                let volume = controller.volume_percent().ok().flatten();
+                MENU_VOLUME.with(|global| {
+                    if let Some(volume_menu) = *global.borrow() {
+                        for (i, preset) in VOLUME_PRESETS.iter().enumerate() {
+                            let check = if volume == Some(*preset) {
+                                MF_CHECKED
+                            } else {
+                                MF_UNCHECKED
+                            };
+                            unsafe {
+                                CheckMenuItem(
+                                    volume_menu,
+                                    (IDM_VOLUME_PRESETS + i as u16) as u32,
+                                    (MF_BYCOMMAND | check).0,
+                                )
+                            };
+                        }
+                    }
+                });
+
+                let menuref = &*global_menu.borrow();
+                let menu = menuref.as_ref();
+                if let Some(menu) = menuref {
+                    update_capture_streams_menu(*menu)?;
+                }
+                update_default_role_menus(controller, &devices)?;
+
+                // Show whether the forced volume is currently being restored
+                if let Some(percent) = controller.forced_volume() {
+                    let mut label_buffer =
+                        forced_volume_label(percent, controller.forced_volume_paused())
+                            .encode_utf16()
+                            .chain(std::iter::once(0))
+                            .collect::<Vec<u16>>();
+                    unsafe {
+                        SetMenuItemInfoW(
+                            menu,
+                            IDM_SET_MAX_VOLUME as u32,
+                            false,
+                            &MENUITEMINFOW {
+                                cbSize: std::mem::size_of::<MENUITEMINFOW>() as u32,
+                                fMask: MIIM_STRING,
+                                dwTypeData: PWSTR(label_buffer.as_mut_ptr()),
+                                ..Default::default()
+                            },
+                        )?;
+                    }
+                }
+                MENU_AUDIO_ENDPOINTS.with(|global_menu_audio_endpoints| -> Result<()> {
+                    let mut menu_audio_endpoints = global_menu_audio_endpoints.borrow_mut();
+                    let add_no_devices_label =
+                        devices_count == 0 && !menu_audio_endpoints.is_empty();
+                    if menu_audio_endpoints.is_empty() && devices_count > 0 {
+                        unsafe {
+                            RemoveMenu(menu, IDM_NO_ENDPOINTS as u32, MF_BYCOMMAND)?;
+                        }
+                    }
+                    for edit in diff_menu(&mut menu_audio_endpoints, &devices) {
+                        let (i, insert) = match edit {
+                            MenuEdit::Remove(i) => {
+                                unsafe { RemoveMenu(menu, i as u32, MF_BYPOSITION)? };
+                                continue;
+                            }
+                            MenuEdit::Update(i) => (i, false),
+                            MenuEdit::Insert(i) => (i, true),
+                        };
+                        let device = &devices[i];
+                        let mut device_name_buffer = device
+                            .name
+                            .encode_utf16()
+                            .chain(std::iter::once(0))
+                            .collect::<Vec<u16>>();
+                        let device_name = PWSTR(device_name_buffer.as_mut_ptr());
+                        let device_is_default =
+                            controller.is_default_device(&device.id).unwrap_or(false);
+                        let item_info = MENUITEMINFOW {
+                            cbSize: std::mem::size_of::<MENUITEMINFOW>() as u32,
+                            fMask: MIIM_FTYPE | MIIM_ID | MIIM_STATE | MIIM_STRING,
+                            fType: MFT_STRING,
+                            fState: if device_is_default {
+                                MFS_CHECKED
+                            } else {
+                                MFS_UNCHECKED
+                            },
+                            wID: i as u32,
+                            dwTypeData: device_name,
+                            ..Default::default()
+                        };
+                        unsafe {
+                            if insert {
+                                InsertMenuItemW(menu, i as u32, true, &item_info)?;
+                            } else {
+                                SetMenuItemInfoW(menu, i as u32, true, &item_info)?;
+                            }
+                        }
+                    }
+                    if add_no_devices_label {
+                        unsafe {
+                            InsertMenuItemW(
+                                menu,
+                                0,
+                                true,
+                                &MENUITEMINFOW {
+                                    cbSize: std::mem::size_of::<MENUITEMINFOW>() as u32,
+                                    fMask: MIIM_FTYPE | MIIM_ID | MIIM_STATE | MIIM_STRING,
+                                    fType: MFT_STRING,
+                                    fState: MFS_DISABLED | MFS_GRAYED,
+                                    wID: IDM_NO_ENDPOINTS as u32,
+                                    dwTypeData: PWSTR(
+                                        LABEL_NO_CAPTURE_DEVICES
+                                            .to_string()?
+                                            .encode_utf16()
+                                            .chain(std::iter::once(0))
+                                            .collect::<Vec<u16>>()
+                                            .as_mut_ptr(),
+                                    ),
+                                    ..Default::default()
+                                },
+                            )?;
+                        }
+                    }
+                    Ok(())
+                })?;
+                Ok(())
+            }
+            _ => Ok(()),
+        })
+    })
+}
+
+// Destroy the menu, it is created again when it is next shown
+fn destroy_menu() -> Result<()> {
+    MENU_AUDIO_ENDPOINTS.with(|global| global.borrow_mut().clear());
+    MENU_VOLUME.with(|global| global.replace(None));
+    MENU_CAPTURE_STREAMS.with(|global| global.replace(None));
+    MENU_DEFAULT_ROLES.with(|global| global.borrow_mut().clear());
+    MENU_DEFAULT_ROLE_DEVICES.with(|global| global.borrow_mut().clear());
+    MENU.with(|global| match global.replace(None) {
+        Some(menu) => unsafe { DestroyMenu(menu) },
+        _ => Ok(()),
+    })
+}
+
+// Show the menu at the given coordinates
+fn show_menu(window: HWND, x: i32, y: i32) -> Result<()> {
+    update_menu()?;
+    MENU.with(|menu| {
+        unsafe {
+            // Set our window as foreground so the menu disappears when focus is lost
+            SetForegroundWindow(window).ok().ok();
+            TrackPopupMenuEx(
+                (*menu.borrow()).as_ref(),
+                (TPM_RIGHTBUTTON
+                    | if GetSystemMetrics(SM_MENUDROPALIGNMENT) != 0 {
+                        TPM_RIGHTALIGN
+                    } else {
+                        TPM_LEFTALIGN
+                    })
+                .0,
+                x,
+                y,
+                window,
+                None,
+            )
+            .ok()
+        }
+    })
+}
+
+// Callback for message pump
+extern "system" fn window_callback(
+    window: HWND,
+    message: u32,
+    wparam: WPARAM,
+    lparam: LPARAM,
+) -> LRESULT {
+    match message {
+        WM_CREATE => {
+            // Listen for taskbar created messages since we should re-add our icon
+            let wm_taskbar_created = unsafe { RegisterWindowMessageW(w!("TaskbarCreated")) };
+            assert!(wm_taskbar_created != 0);
+            WM_TASKBAR_CREATED.with(|global| {
+                global.replace(Some(wm_taskbar_created));
+            });
+            LRESULT(0)
+        }
+        WM_DPICHANGED => {
+            let instance: HINSTANCE = unsafe { GetModuleHandleW(None).unwrap().into() };
+            assert!(!instance.is_invalid());
+            load_icons(instance).unwrap();
+            update_notify_icon().ok();
+            LRESULT(0)
+        }
+        WM_SETTINGCHANGE => {
+            update_notify_icon().ok();
+            LRESULT(0)
+        }
+        WM_TIMER if wparam.0 == IDT_CONFIG_POLL => {
+            reload_config(window).ok();
+            LRESULT(0)
+        }
+        WM_TIMER if wparam.0 == IDT_HELD_HOTKEY => {
+            check_held_hotkey(window).ok();
+            LRESULT(0)
+        }
+        WM_TIMER if wparam.0 == IDT_HOLD_RELEASE => {
+            finish_hold(window).ok();
+            LRESULT(0)
+        }
+        WM_HOTKEY => {
+            handle_hotkey(window, wparam.0, true).ok();
+            LRESULT(0)
+        }
+        WM_APP_MOUSE_HOTKEY => {
+            handle_hotkey(window, wparam.0, lparam.0 != 0).ok();
+            LRESULT(0)
+        }
+        WM_APP_NOTIFYICON => match lparam.0 as u32 & 0xffff {
+            NIN_SELECT => {
+                toggle_mute().ok();
+                LRESULT(0)
+            }
+            WM_CONTEXTMENU => {
+                show_menu(
+                    window,
+                    (wparam.0 as u32 & 0xffff) as i16 as i32,
+                    ((wparam.0 as u32 >> 16) & 0xffff) as i16 as i32,
+                )
+                .unwrap();
+                LRESULT(0)
+            }
+            _ => LRESULT(0),
+        },
+        WM_APP_CALLBACK_ENDPOINT => {
+            // Audio endpoint has changed
+            handle_audio_event(AudioEvent::Devices).ok();
+            set_metering_timer(window);
+            update_notify_icon().ok();
+            update_menu()
+                .and_then(|()| unsafe { DrawMenuBar(window) })
+                .ok();
+            notify_change(window).ok();
+            LRESULT(0)
+        }
+        WM_APP_CALLBACK_VOLUME => {
+            // Audio endpoint volume/mute has changed
+            update_notify_icon().ok();
+            play_cue().ok();
+            handle_audio_event(AudioEvent::Volume).ok();
+            set_metering_timer(window);
+            notify_change(window).ok();
+            LRESULT(0)
+        }
+        WM_APP_CALLBACK_STREAMS => {
+            check_capture_streams(window).ok();
+            LRESULT(0)
+        }
+        WM_TIMER if wparam.0 == IDT_CAPTURE_STREAMS => {
+            check_capture_streams(window).ok();
+            LRESULT(0)
+        }
+        WM_TIMER if wparam.0 == IDT_METERING => {
+            update_metering(window).ok();
+            LRESULT(0)
+        }
+        WM_TIMER if wparam.0 == IDT_TALKING_FLASH => {
+            TALKING_FLASH.with(|global| {
+                if let Some(shown) = global.borrow_mut().as_mut() {
+                    *shown = !*shown;
+                }
+            });
+            update_notify_icon().ok();
+            LRESULT(0)
+        }
+        WM_TIMER if wparam.0 == IDT_NOTIFICATION => {
+            flush_notification(window).ok();
+            LRESULT(0)
+        }
+        WM_APP_ACTION => {
+            // Another invocation forwarded actions, the message itself carries
+            // nothing as any process can post it
+            let actions: Vec<Action> = ACTIONS.with(|global| {
+                global
+                    .borrow()
+                    .as_ref()
+                    .map(|receiver| receiver.try_iter().collect())
+                    .unwrap_or_default()
+            });
+            for action in actions {
+                perform_action(action).ok();
+            }
+            LRESULT(0)
+        }
+        WM_COMMAND => {
+            match (wparam.0 as u32 & 0xffff) as u16 {
+                IDM_EXIT => unsafe {
+                    DestroyWindow(window).ok();
+                },
+                IDM_OPEN_SOUNDCONTROLPANEL => {
+                    open_sound_control_panel_recording_tab().ok();
+                }
+                IDM_SET_MAX_VOLUME => {
+                    set_volume_to_max().ok();
+                }
+                i if (IDM_VOLUME_PRESETS..IDM_VOLUME_PRESETS + VOLUME_PRESETS.len() as u16)
+                    .contains(&i) =>
+                {
+                    set_volume_percent(VOLUME_PRESETS[(i - IDM_VOLUME_PRESETS) as usize]).ok();
+                }
+                i if (IDM_COMMUNICATIONS_DEVICES..IDM_CONSOLE_DEVICES).contains(&i) => {
+                    set_role_default_audio_capture_device(
+                        DeviceRole::Communications,
+                        (i - IDM_COMMUNICATIONS_DEVICES) as usize,
+                    )
+                    .ok();
+                }
+                i if (IDM_CONSOLE_DEVICES..IDM_ROLE_DEVICES_END).contains(&i) => {
+                    set_role_default_audio_capture_device(
+                        DeviceRole::Console,
+                        (i - IDM_CONSOLE_DEVICES) as usize,
+                    )
+                    .ok();
+                }
+                i => {
+                    let i = i as usize;
+                    MENU_AUDIO_ENDPOINTS.with(|global_menu_audio_endpoints| {
+                        let menu_audio_endpoints = global_menu_audio_endpoints.borrow();
+                        if i < menu_audio_endpoints.len() {
+                            set_default_audio_capture_device(&menu_audio_endpoints[i]).ok();
+                        }
+                    });
+                }
+            }
+            LRESULT(0)
+        }
+        WM_CLOSE => {
+            unsafe {
+                DestroyWindow(window).ok();
+            }
+            LRESULT(0)
+        }
+        WM_DESTROY => {
+            // Do not leave the microphone in the state of a key still being held
+            CONTROLLER.with(|global| {
+                if let Some(controller) = global.borrow_mut().as_mut() {
+                    controller.end_hold().ok();
+                }
+            });
+            MOUSE_HOOK.with(|global| {
+                if let Some((hook, _)) = global.replace(None) {
+                    unsafe { UnhookWindowsHookEx(hook) }.ok();
+                }
+            });
+            unsafe {
+                PostQuitMessage(0);
+            }
+            LRESULT(0)
+        }
+        _ => WM_TASKBAR_CREATED.with(|wm_taskbar_created| {
+            if wm_taskbar_created.borrow().is_some_and(|m| m == message) {
+                // We also get TaskbarCreated messages when the DPI is changed,
+                // but in that case the icon still exists, so if adding it fails
+                // we try updating it instead
+                add_notify_icon().or_else(|_| update_notify_icon()).unwrap();
+                LRESULT(0)
+            } else {
+                unsafe { DefWindowProcW(window, message, wparam, lparam) }
+            }
+        }),
+    }
+}
+
+use std::os::windows::ffi::OsStrExt;
+
+pub fn run() -> Result<()> {
+    let instance: HINSTANCE = unsafe { GetModuleHandleW(None)?.into() };
+    assert!(!instance.is_invalid());
+
+    // Attach to parent console so we can output help messages etc
+    unsafe { AttachConsole(ATTACH_PARENT_PROCESS) }.ok();
+
+    // Parse CLI arguments
+    let args: CliArgs = argh::from_env();
+
+    // Leave the actions to the running instance if there is one, unless we
+    // need to report the state after them
+    let actions = Action::from_args(&args);
+    if !args.query && instance::forward(&actions)? {
+        return Ok(());
+    }
+
+    let config = Config::load(&args)?;
+
+    // Main window class definition
+    let window_class_name_buffer = "MicrophoneMuteIndicator\0"
+        .encode_utf16()
+        .collect::<Vec<_>>();
+    let window_class_name = PCWSTR(window_class_name_buffer.as_ptr());
+    let window_class = WNDCLASSW {
+        hInstance: instance,
+        lpszClassName: window_class_name,
+        lpfnWndProc: Some(window_callback),
+        ..Default::default()
+    };
+
+    // Register the main window class
+    let window_class_atom = unsafe { RegisterClassW(&window_class) };
+    assert!(window_class_atom != 0);
+
+    // Create a window using the class we just defined
+    let window = unsafe {
+        CreateWindowExW(
+            WS_EX_NOACTIVATE,
+            window_class_name,
+            window_class_name,
+            WS_DISABLED,
+            CW_USEDEFAULT,
+            CW_USEDEFAULT,
+            CW_USEDEFAULT,
+            CW_USEDEFAULT,
+            None,
+            None,
+            instance,
+            None,
+        )
+    }?;
+    assert!(!window.is_invalid());
+
+    // Initialize COM runtime
+    unsafe {
+        CoInitializeEx(None, COINIT_MULTITHREADED).ok()?;
+    }
+
+    // Set up the audio backend and forward its notifications to our window
+    let mut controller = Controller::new(audio::default_backend()?);
+    let window_handle = window.0 as isize;
+    controller.subscribe(Box::new(move |event| {
+        let message = match event {
+            AudioEvent::Devices => WM_APP_CALLBACK_ENDPOINT,
+            AudioEvent::Volume => WM_APP_CALLBACK_VOLUME,
+            AudioEvent::Streams => WM_APP_CALLBACK_STREAMS,
+        };
+        unsafe {
+            PostMessageW(
+                HWND(window_handle as *mut c_void),
+                message,
+                WPARAM(0),
+                LPARAM(0),
+            )
+        }
+        .ok();
+    }))?;
+    CONTROLLER.with(|global| {
+        global.replace(Some(controller));
+    });
+
+    // Set configuration options
+    apply_config(&config);
+
+    // Execute actions
+    for action in actions {
+        perform_action(action)?;
+    }
+    if args.query {
+        let status = CONTROLLER.with(|global| match global.replace(None) {
+            Some(controller) => controller.status(),
+            _ => unreachable!("Controller is set up before performing actions"),
+        })?;
+        unsafe { CoUninitialize() };
+        print_status(&status, args.format);
+        std::process::exit(status.exit_code());
+    }
+
+    // Only add icon if we're not exiting immediately
+    let mut exit_result = Ok(());
+    if !args.action_exit {
+        // Add the notify icon
+        let notify_icon_data = NOTIFYICONDATAW {
+            cbSize: std::mem::size_of::<NOTIFYICONDATAW>() as u32,
+            hWnd: window,
+            uID: 0,
+            uFlags: NIF_MESSAGE,
+            uCallbackMessage: WM_APP_NOTIFYICON,
+            Anonymous: NOTIFYICONDATAW_0 {
+                uVersion: NOTIFYICON_VERSION_4,
+            },
+            ..Default::default()
+        };
+        NOTIFY_ICON_DATA.with(|global| {
+            global.replace(Some(notify_icon_data));
+        });
+
+        load_icons(instance)?;
+        add_notify_icon()?;
+
+        // Start notifying about changes from the current state
+        NOTIFIER.with(|global| {
+            global.replace(Some(Notifier::new(config.notifications())));
+        });
+        notify_change(window)?;
+        play_cue()?;
+
+        // Check the config file for changes every now and then
+        CONFIG_WATCHER.with(|global| {
+            global.replace(Some(ConfigWatcher::new(args)));
+        });
+        unsafe { SetTimer(window, IDT_CONFIG_POLL, CONFIG_POLL_INTERVAL_MS, None) };
+        set_metering_timer(window);
+
+        // Keep track of the applications capturing audio
+        check_capture_streams(window)?;
+        unsafe {
+            SetTimer(
+                window,
+                IDT_CAPTURE_STREAMS,
+                CAPTURE_STREAMS_POLL_INTERVAL_MS,
+                None,
+            )
+        };
+
+        register_hotkeys(window, 0);
+
+        // Accept actions from later invocations
+        let (sender, receiver) = mpsc::channel();
+        ACTIONS.with(|global| global.replace(Some(receiver)));
+        if let Err(error) = Endpoint::listen(Box::new(move |action| {
+            if sender.send(action).is_ok() {
+                unsafe {
+                    PostMessageW(
+                        HWND(window_handle as *mut c_void),
+                        WM_APP_ACTION,
+                        WPARAM(0),
+                        LPARAM(0),
+                    )
+                }
+                .ok();
+            }
+        })) {
+            eprintln!("Cannot accept actions from other invocations: {}", error);
+        }
+    } else {
+        unsafe { DestroyWindow(window) }?;
+    }
+
+    // Message pump
+    let mut message = MSG::default();
+    while unsafe { GetMessageW(&mut message, HWND(std::ptr::null_mut()), 0, 0) }.into() {
+        unsafe {
+            DispatchMessageW(&message);
+        }
+    }
+
+    if message.wParam.0 != 0 {
+        exit_result = Err(Error::from_win32())
+    }
+
+    // Remove the notification icon
+    NOTIFY_ICON_DATA.with(|global| match global.replace(None) {
+        Some(notify_icon_data) => unsafe { Shell_NotifyIconW(NIM_DELETE, &notify_icon_data).ok() },
+        _ => Ok(()),
+    })?;
+
+    // Release the COM objects
+    CONTROLLER.with(|global| {
+        global.replace(None);
+    });
+
+    // Deinitialize COM runtime
+    unsafe {
+        CoUninitialize();
+    }
+
+    // Deallocate icons
+    ICON_ACTIVE.with(|global| match global.replace(None) {
+        Some(old_icon) => unsafe { DestroyIcon(old_icon) },
+        _ => Ok(()),
+    })?;
+    ICON_ACTIVE_INVERTED.with(|global| match global.replace(None) {
+        Some(old_icon) => unsafe { DestroyIcon(old_icon) },
+        _ => Ok(()),
+    })?;
+    ICON_IN_USE.with(|global| match global.replace(None) {
+        Some(old_icon) => unsafe { DestroyIcon(old_icon) },
+        _ => Ok(()),
+    })?;
+    ICON_IN_USE_INVERTED.with(|global| match global.replace(None) {
+        Some(old_icon) => unsafe { DestroyIcon(old_icon) },
+        _ => Ok(()),
+    })?;
+    ICON_LEVEL.with(|global| match global.replace(None) {
+        Some(old_icon) => unsafe { DestroyIcon(old_icon) },
+        _ => Ok(()),
+    })?;
+    ICON_TALKING.with(|global| match global.replace(None) {
+        Some(old_icon) => unsafe { DestroyIcon(old_icon) },
+        _ => Ok(()),
+    })?;
+    ICON_PARTIALLY_MUTED.with(|global| match global.replace(None) {
+        Some(old_icon) => unsafe { DestroyIcon(old_icon) },
+        _ => Ok(()),
+    })?;
+    ICON_PARTIALLY_MUTED_INVERTED.with(|global| match global.replace(None) {
+        Some(old_icon) => unsafe { DestroyIcon(old_icon) },
+        _ => Ok(()),
+    })?;
+    ICON_MUTED.with(|global| match global.replace(None) {
+        Some(old_icon) => unsafe { DestroyIcon(old_icon) },
+        _ => Ok(()),
+    })?;
+    ICON_MUTED_INVERTED.with(|global| match global.replace(None) {
+        Some(old_icon) => unsafe { DestroyIcon(old_icon) },
+        _ => Ok(()),
+    })?;
+
+    // Destroy the menu
+    destroy_menu()?;
+
+    // Unregister the window class
+    unsafe {
+        UnregisterClassW(window_class_name, instance)?;
+    }
+
+    exit_result
+}